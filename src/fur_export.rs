@@ -0,0 +1,490 @@
+use crate::sound_generator::*;
+use crate::sound_manager::*;
+
+// Furnace module (.fur) の書き出し
+// 無圧縮の .fur もそのまま読み込めるので zlib 圧縮はしない
+const FUR_MAGIC: &[u8; 16] = b"-Furnace module-";
+const FUR_VERSION: u16 = 127; // INS2 形式の楽器が使える最初の版
+const CHIP_NAMCO_C15: u8 = 0x9a; // ナムコ C15 WSG (8 チャンネル)
+const INS_TYPE_NAMCO: u16 = 31;
+const NUM_CHANNELS: usize = 8;
+const PATTERN_LENGTH: usize = 64;
+const MAX_ORDERS: usize = 256;
+const EFFECT_COLUMNS: usize = 3;
+const FRAMES_PER_SECOND: f32 = 60.0;
+const MAX_MACRO_LENGTH: usize = 255;
+const MIDI_NOTE_OF_KEY0_OCT0: i32 = 105;
+const REFERENCE_A7: f64 = 3520.0 * 65536.0 / 1500.0; // A7 (3520Hz) のレジスタ値
+
+const NOTE_OFF: i16 = 100;
+const EFFECT_SET_WAVE: i16 = 0x10;
+const EFFECT_PANNING: i16 = 0x08; // 上位 4 ビットが左、下位 4 ビットが右の音量
+const EFFECT_FINE_PITCH: i16 = 0xe5;
+const EFFECT_STOP_SONG: i16 = 0xff;
+
+#[derive(Clone, Copy)]
+struct Row {
+    note: i16,
+    octave: i16,
+    instrument: i16,
+    effects: [(i16, i16); EFFECT_COLUMNS],
+}
+
+impl Default for Row {
+    fn default() -> Self {
+        Self {
+            note: 0,
+            octave: 0,
+            instrument: -1,
+            effects: [(-1, -1); EFFECT_COLUMNS],
+        }
+    }
+}
+
+impl Row {
+    fn add_effect(&mut self, effect: i16, value: i16) {
+        if let Some(slot) = self.effects.iter_mut().find(|e| e.0 < 0) {
+            *slot = (effect, value);
+        }
+    }
+}
+
+struct FurWriter {
+    buf: Vec<u8>,
+}
+
+impl FurWriter {
+    fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn i16(&mut self, v: i16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn f32(&mut self, v: f32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn zeros(&mut self, n: usize) {
+        self.buf.resize(self.buf.len() + n, 0);
+    }
+
+    fn block(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut block = Vec::with_capacity(body.len() + 8);
+        block.extend_from_slice(id);
+        block.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        block.extend_from_slice(body);
+        block
+    }
+}
+
+// ENVELOPE_TBL の１行を SoundManager と同じ手順で展開し、音量マクロにする
+// 0x12 (発音の残りフレームに追従) は表現できないので、そこで保持とする
+fn envelope_macro(envelope: &[i32]) -> (Vec<u8>, Option<u8>) {
+    let mut values = Vec::new();
+    let mut pos = 0;
+    let mut gain = 0;
+    while values.len() < MAX_MACRO_LENGTH {
+        match envelope[pos] {
+            0x10 | 0x12 => break,
+            0x11 => {
+                if gain > 0 {
+                    if gain - 1 <= envelope[pos + 1] {
+                        pos += 1;
+                    }
+                    gain -= 1;
+                } else {
+                    pos += 1;
+                }
+            },
+            0x13 => return (values, Some(0)),
+            0x14 => {
+                pos += 1;
+                continue;
+            },
+            g => {
+                pos += 1;
+                gain = g;
+            },
+        }
+        values.push(gain as u8);
+    }
+    (values, None)
+}
+
+fn instrument_block(no: usize, envelope: &[i32]) -> Vec<u8> {
+    let mut w = FurWriter::new();
+    w.u16(FUR_VERSION);
+    w.u16(INS_TYPE_NAMCO);
+    let name = format!("envelope {:02x}", no);
+    w.buf.extend_from_slice(b"NA");
+    w.u16(name.len() as u16 + 1);
+    w.str(&name);
+
+    let (values, loop_pos) = envelope_macro(envelope);
+    let mut macros = FurWriter::new();
+    macros.u16(8); // マクロのヘッダの長さ
+    macros.u8(0); // 音量のマクロ
+    macros.u8(values.len() as u8);
+    macros.u8(loop_pos.unwrap_or(0xff));
+    macros.u8(0xff); // リリース位置
+    macros.u8(0); // モード
+    macros.u8(0x01); // 開いた状態、シーケンス、符号なし８ビット
+    macros.u8(0); // 遅延
+    macros.u8(1); // 速さ
+    macros.buf.extend_from_slice(&values);
+    macros.u8(0xff); // マクロの終わり
+    w.buf.extend_from_slice(b"MA");
+    w.u16(macros.buf.len() as u16);
+    w.buf.extend_from_slice(&macros.buf);
+    w.buf.extend_from_slice(b"EN");
+    FurWriter::block(b"INS2", &w.buf)
+}
+
+fn wavetable_block(no: usize) -> Vec<u8> {
+    let mut w = FurWriter::new();
+    w.str(&format!("wave {}", no));
+    w.u32(WAVE_DATA_LENGTH as u32);
+    w.u32(0);
+    w.u32(15);
+    for s in wave_samples(no).iter() {
+        w.u32(*s as u32);
+    }
+    FurWriter::block(b"WAVE", &w.buf)
+}
+
+fn pattern_block(ch: usize, index: usize, rows: &[Row]) -> Vec<u8> {
+    let mut w = FurWriter::new();
+    w.u16(ch as u16);
+    w.u16(index as u16);
+    w.u16(0); // サブソング
+    w.u16(0);
+    for row in rows.iter() {
+        w.i16(row.note);
+        w.i16(row.octave);
+        w.i16(row.instrument);
+        w.i16(-1); // 音量
+        for effect in row.effects.iter() {
+            w.i16(effect.0);
+            w.i16(effect.1);
+        }
+    }
+    w.str("");
+    FurWriter::block(b"PATR", &w.buf)
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// 0xf2 の単位をすべて割り切るフレーム数を１行とする
+fn frames_per_row(parts: &[(&[u8], &ScaleSet)]) -> usize {
+    let mut speed = 0;
+    for part in parts.iter() {
+        let mut adr = part.0;
+        while let Some(&r0) = adr.first() {
+            match r0 {
+                0xf2 => {
+                    speed = gcd(speed, adr[1] as usize);
+                    adr = &adr[2..];
+                },
+                0xf3 => break,
+                _ => adr = &adr[instruction_len(r0).unwrap_or(2).min(adr.len())..],
+            }
+        }
+    }
+    speed.max(1)
+}
+
+fn scale_pitch_offset(scale: &ScaleSet) -> i16 {
+    let cents = 1200.0 * (scale[0] as f64 / REFERENCE_A7).log2();
+    (0x80 + (cents * 128.0 / 100.0).round() as i32).clamp(0, 0xff) as i16
+}
+
+// パートのバイトコードを行単位のノート列に変換する
+fn part_rows(part: &(&[u8], &ScaleSet), speed: usize) -> Result<Vec<Row>, String> {
+    let mut rows = vec![Row::default()];
+    let mut frame = 0;
+    let mut unit_frames = 0;
+    let mut envelope = 0;
+    let mut wave = None;
+    let mut pan = None;
+    let mut adr = part.0;
+    let mut flow = FlowState::new(part.0);
+    rows[0].add_effect(EFFECT_FINE_PITCH, scale_pitch_offset(part.1));
+    loop {
+        let r0 = *adr.first().ok_or("part ends without 0xf3")?;
+        match r0 {
+            0xf0 => {
+                wave = Some((adr[1] >> 4) as i16);
+                adr = &adr[2..];
+            },
+            0xf1 => {
+                envelope = adr[1] as i16;
+                adr = &adr[2..];
+            },
+            0xf2 => {
+                unit_frames = adr[1] as usize;
+                adr = &adr[2..];
+            },
+            // 音程の効果はフレーム単位の周波数で Furnace の効果と一致しないので書き出さない
+            0xf9..=0xfb => adr = &adr[2..],
+            0xfc => {
+                pan = Some(match adr[1] as i8 {
+                    p if p < 0 => 0xf0,
+                    0 => 0xff,
+                    _ => 0x0f,
+                });
+                adr = &adr[2..];
+            },
+            0xf3 => break,
+            // 後ろへのジャンプは無限に繰り返すので、そこまでを書き出す
+            0xf8 if ((adr[1] as usize) << 8 | adr[2] as usize) <= part.0.len() - adr.len() => break,
+            0xf4..=0xff => if !flow.execute(&mut adr) {
+                return Err(format!("unknown command {:02x}", r0));
+            },
+            _ => {
+                let key = (r0 >> 4) as i32;
+                let oct = (r0 & 0x0f) as i32;
+                let len = adr[1] as usize * unit_frames;
+                let row_no = frame / speed;
+                rows.resize(row_no + 1, Row::default());
+                let row = &mut rows[row_no];
+                if key >= 12 {
+                    row.note = NOTE_OFF;
+                } else {
+                    let midi_note = MIDI_NOTE_OF_KEY0_OCT0 + key - 12 * oct;
+                    let (note, octave) = match midi_note % 12 {
+                        0 => (12, midi_note / 12 - 2),
+                        n => (n, midi_note / 12 - 1),
+                    };
+                    row.note = note as i16;
+                    row.octave = (octave as i16) & 0xff;
+                    row.instrument = envelope;
+                    if let Some(w) = wave.take() {
+                        row.add_effect(EFFECT_SET_WAVE, w);
+                    }
+                    if let Some(p) = pan.take() {
+                        row.add_effect(EFFECT_PANNING, p);
+                    }
+                }
+                frame += len;
+                adr = &adr[2..];
+            },
+        }
+    }
+    let end_row = frame / speed;
+    rows.resize(end_row + 1, Row::default());
+    rows[end_row].note = NOTE_OFF;
+    Ok(rows)
+}
+
+pub fn export_fur(sound_no: usize) -> Result<Vec<u8>, String> {
+    let parts = *MUSIC_SCORES.get(sound_no).ok_or(format!("sound {:02x} does not exist", sound_no))?;
+    let speed = frames_per_row(parts);
+    if speed > 0xff {
+        return Err(format!("unit of {} frames is too long for a row", speed));
+    }
+    let mut channel_rows = Vec::new();
+    for (part_no, part) in parts.iter().enumerate().take(NUM_CHANNELS) {
+        channel_rows.push(part_rows(part, speed).map_err(|e| format!("part {}: {}", part_no, e))?);
+    }
+    let total_rows = channel_rows.iter().map(|r| r.len()).max().unwrap_or(1);
+    let num_orders = total_rows.div_ceil(PATTERN_LENGTH);
+    if num_orders > MAX_ORDERS {
+        return Err(format!("sound is too long ({} rows)", total_rows));
+    }
+    if let Some(rows) = channel_rows.first_mut() {
+        let end_row = total_rows - 1;
+        rows.resize(total_rows, Row::default());
+        rows[end_row].add_effect(EFFECT_STOP_SONG, 0);
+    }
+
+    let instruments: Vec<Vec<u8>> = ENVELOPE_TBL.iter().enumerate().map(|(no, env)| instrument_block(no, env)).collect();
+    let wavetables: Vec<Vec<u8>> = (0..NUM_OF_WAVE_FORMS).map(wavetable_block).collect();
+    let mut patterns = Vec::new();
+    for (ch, rows) in channel_rows.iter_mut().enumerate() {
+        rows.resize(num_orders * PATTERN_LENGTH, Row::default());
+        for (index, chunk) in rows.chunks(PATTERN_LENGTH).enumerate() {
+            patterns.push(pattern_block(ch, index, chunk));
+        }
+    }
+
+    let song_name = format!("{:?}", SoundIdx::from(sound_no as i32));
+    let info = |pointers: &[u32]| {
+        let mut w = FurWriter::new();
+        w.u8(0); // タイムベース
+        w.u8(speed as u8);
+        w.u8(speed as u8);
+        w.u8(1); // アルペジオの間隔
+        w.f32(FRAMES_PER_SECOND);
+        w.u16(PATTERN_LENGTH as u16);
+        w.u16(num_orders as u16);
+        w.u8(4);
+        w.u8(16);
+        w.u16(instruments.len() as u16);
+        w.u16(wavetables.len() as u16);
+        w.u16(0); // サンプル数
+        w.u32(patterns.len() as u32);
+        w.u8(CHIP_NAMCO_C15);
+        w.zeros(31);
+        w.u8(64); // チップの音量
+        w.zeros(31);
+        w.zeros(32); // チップの定位
+        w.zeros(128); // チップのフラグへのポインタ
+        w.str(&song_name);
+        w.str("");
+        w.f32(440.0);
+        // limit slides から reset note base on arpeggio stop まで
+        for flag in [0, 2, 0, 1, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1] {
+            w.u8(flag);
+        }
+        for p in pointers.iter() {
+            w.u32(*p);
+        }
+        for _ in 0..NUM_CHANNELS {
+            for order in 0..num_orders {
+                w.u8(order as u8);
+            }
+        }
+        for _ in 0..NUM_CHANNELS {
+            w.u8(EFFECT_COLUMNS as u8);
+        }
+        w.zeros(NUM_CHANNELS); // 隠す
+        w.zeros(NUM_CHANNELS); // 折りたたむ
+        for _ in 0..NUM_CHANNELS * 2 {
+            w.str(""); // 名前、短い名前
+        }
+        w.str("exported by wsg_test2");
+        w.f32(1.0); // 全体の音量
+        // 追加の互換フラグ (バージョン 70 から 121)
+        for flag in [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4, 0, 0, 1, 1, 0, 0, 0, 0, 2, 0, 1, 0, 0] {
+            w.u8(flag);
+        }
+        w.u16(150); // 仮想テンポ
+        w.u16(150);
+        w.str(""); // 最初のサブソングの名前
+        w.str("");
+        w.u8(0); // 追加のサブソング数
+        w.zeros(3);
+        w.str("Namco C15"); // システム名
+        w.str("The Tower of Druaga");
+        for _ in 0..4 {
+            w.str("");
+        }
+        FurWriter::block(b"INFO", &w.buf)
+    };
+    let num_pointers = instruments.len() + wavetables.len() + patterns.len();
+    let info_len = info(&vec![0; num_pointers]).len();
+    let mut pointers = Vec::with_capacity(num_pointers);
+    let mut offset = 32 + info_len;
+    for block in instruments.iter().chain(wavetables.iter()).chain(patterns.iter()) {
+        pointers.push(offset as u32);
+        offset += block.len();
+    }
+
+    let mut w = FurWriter::new();
+    w.buf.extend_from_slice(FUR_MAGIC);
+    w.u16(FUR_VERSION);
+    w.u16(0);
+    w.u32(32); // 曲情報へのポインタ
+    w.zeros(8);
+    w.buf.extend_from_slice(&info(&pointers));
+    for block in instruments.iter().chain(wavetables.iter()).chain(patterns.iter()) {
+        w.buf.extend_from_slice(block);
+    }
+    Ok(w.buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], pos: usize) -> usize {
+        u16::from_le_bytes([data[pos], data[pos + 1]]) as usize
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> usize {
+        u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize
+    }
+
+    #[test]
+    fn block_layout() {
+        let sound_no = SoundIdx::Chime as usize;
+        let fur = export_fur(sound_no).unwrap();
+        assert_eq!(&fur[..16], FUR_MAGIC);
+        assert_eq!(u16_at(&fur, 16), FUR_VERSION as usize);
+        assert_eq!(u32_at(&fur, 20), 32);
+
+        // INFO の後に INS2, WAVE, PATR が隙間なく続く
+        let mut blocks = Vec::new();
+        let mut pos = 32;
+        while pos < fur.len() {
+            blocks.push((&fur[pos..pos + 4], pos));
+            pos += 8 + u32_at(&fur, pos + 4);
+        }
+        assert_eq!(pos, fur.len());
+        let info = &fur[32 + 8..blocks[1].1];
+        let (num_instruments, num_waves, num_patterns) = (u16_at(info, 14), u16_at(info, 16), u32_at(info, 20));
+        assert_eq!(num_instruments, ENVELOPE_TBL.len());
+        assert_eq!(num_waves, NUM_OF_WAVE_FORMS);
+        assert_eq!(info[24], CHIP_NAMCO_C15);
+        let num_orders = u16_at(info, 10);
+        assert_eq!(num_patterns, MUSIC_SCORES[sound_no].len().min(NUM_CHANNELS) * num_orders);
+        let ids: Vec<&[u8]> = blocks.iter().map(|block| block.0).collect();
+        let expected: Vec<&[u8]> = [&b"INFO"[..]].into_iter()
+            .chain([&b"INS2"[..]].repeat(num_instruments))
+            .chain([&b"WAVE"[..]].repeat(num_waves))
+            .chain([&b"PATR"[..]].repeat(num_patterns))
+            .collect();
+        assert_eq!(ids, expected);
+
+        // INFO のポインタ表は曲名、作者名、A4 の周波数、互換フラグ 20 バイトの後
+        let song_name = format!("{:?}", SoundIdx::from(sound_no as i32));
+        let table = 248 + song_name.len() + 1 + 1 + 4 + 20;
+        let pointers: Vec<usize> = (0..blocks.len() - 1).map(|no| u32_at(info, table + no * 4)).collect();
+        let offsets: Vec<usize> = blocks[1..].iter().map(|block| block.1).collect();
+        assert_eq!(pointers, offsets);
+    }
+
+    #[test]
+    fn part_rows_of_note_rest_and_end() {
+        // unit 2 フレーム、エンベロープ 3、波形 5: A (key 0, oct 3) 4 単位、休符 2 単位、A# (key 1, oct 3) 2 単位
+        let score: &[u8] = &[0xf2, 0x02, 0xf1, 0x03, 0xf0, 0x50, 0x03, 0x04, 0xc0, 0x02, 0x13, 0x02, 0xf3];
+        let part = (score, &SCALE_0);
+        let speed = frames_per_row(&[part]);
+        assert_eq!(speed, 2);
+        let rows = part_rows(&part, speed).unwrap();
+        assert_eq!(rows.len(), 9);
+        assert_eq!((rows[0].note, rows[0].octave, rows[0].instrument), (9, 4, 3));
+        assert_eq!(rows[0].effects[0].0, EFFECT_FINE_PITCH);
+        assert_eq!(rows[0].effects[1], (EFFECT_SET_WAVE, 5));
+        assert_eq!(rows[4].note, NOTE_OFF);
+        assert_eq!((rows[6].note, rows[6].octave, rows[6].instrument), (10, 4, 3));
+        // 波形は変わったときだけ設定する
+        assert_eq!(rows[6].effects[0], (-1, -1));
+        // 0xf3 の行で音を止める
+        assert_eq!(rows[8].note, NOTE_OFF);
+        for row in [1, 2, 3, 5, 7] {
+            assert_eq!(rows[row].note, 0, "row {}", row);
+        }
+    }
+}
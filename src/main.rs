@@ -23,6 +23,12 @@ use sound_generator::*;
 mod sound_manager;
 use sound_manager::*;
 
+mod fur_export;
+
+mod reference_driver;
+
+mod tool_command;
+
 use piston_window::{ControllerButton, ControllerHat, HatState, Key, MouseButton};
 
 const FULL_SCREEN: bool = false;
@@ -43,8 +49,36 @@ const SOUND_BUF_SIZE: usize = 8192;
 const NUM_BUFFERING_FRAME: usize = 1;
 const NUM_OF_AUDIO_CHANNELS: usize = 8;
 const FREQ_ADJ_RATIO: f64 = 65536.0 / 1500.0; // 65536(=0x10000) -> 1500Hz
+// 選択中のサウンドの演奏位置のスライダー (クリックでその位置から演奏)
+const SEEK_ROW: i32 = 26;
+const SEEK_X: i32 = 4;
+const SEEK_WIDTH: usize = 32;
+const TIME_ROW: i32 = 27;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(result) = tool_command::run(&args) {
+        if let Err(message) = result {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let rom_scores = match tool_command::rom_scores(&args) {
+        Ok(rom_scores) => rom_scores,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    let mut reference_driver = match tool_command::reference_driver(&args) {
+        Ok(reference_driver) => reference_driver,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let audio_subsystem = sdl_context.audio().unwrap();
@@ -266,6 +300,10 @@ fn main() {
         bg.1.set_cur_pos(29, 4).put_string(&"Volume:", None);
         bg.1.set_achar_at(38, 4, &AChar::new('-', 7, BgSymmetry::Normal));
         bg.1.set_achar_at(39, 4, &AChar::new('+', 7, BgSymmetry::Normal));
+        if reference_driver.is_some() {
+            bg.1.set_cur_pos(31, 1).put_string(&"Reference", None);
+        }
+        bg.1.set_cur_pos(0, SEEK_ROW).put_string(&"Pos", None);
     }
     spr.sp[0].code(0).palette(1).symmetry(SpSymmetry::Normal);
 
@@ -276,9 +314,20 @@ fn main() {
     let mut music_playing = None;
     let mut play_step = 1;
     let mut suppress_last = false;
+    // A-B ループの範囲 (フレーム)
+    let mut ab_loop: [Option<usize>; 2] = [None; 2];
 
-    let mut sound_manager = SoundManager::default();
+    let mut sound_manager = match tool_command::sound_manager(&args, rom_scores.as_ref()) {
+        Ok(sound_manager) => sound_manager,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    let num_sounds = sound_manager.num_sounds().min(0x20);
     sound_manager.suppress_last_silence = suppress_last;
+    // 位置を指定したサウンドは画面 (キャラクタ単位) の中央で聞く
+    sound_manager.set_listener(Some(Listener::centered(VM_RECT_SIZE.0, VM_RECT_SIZE.1))).unwrap();
     let mut sound_generator = SoundGenerator::new(SAMPLING_FREQ);
     let samples_per_frame_2ch = sound_generator.samples_per_frame() * 2;
 
@@ -287,10 +336,29 @@ fn main() {
     audio_device.resume();
     'main_loop: loop {
         sound_generator.master_gain = master_gain;
-        if t_count % play_step == 0 {
+        // 全体の一時停止中は演奏も進めない
+        if t_count % play_step == 0 && !sound_generator.paused {
+            if let Some(reference_driver) = &mut reference_driver {
+                reference_driver.set_requests(&sound_manager.play_request);
+                reference_driver.run();
+            }
             sound_manager.run();
+            // 通知は毎フレーム取り出す (develop では内容を表示する)
+            let _events = sound_manager.drain_events();
+            #[cfg(feature = "develop")]
+            for event in _events.iter() {
+                println!("{}", event);
+            }
         }
-        let sound_data = sound_manager.get_ch_registers();
+        let sound_data = match &reference_driver {
+            Some(reference_driver) => reference_driver.get_ch_registers(),
+            None => sound_manager.get_ch_registers(),
+        };
+        let sound_pans = match &reference_driver {
+            Some(_) => [None; NUM_OF_AUDIO_CHANNELS],
+            None => sound_manager.get_ch_pans(),
+        };
+        sound_generator.set_sound_pans(&sound_pans);
         if t_count % play_step == play_step - 1 {
             sound_manager.clear_ch_registers();
         }
@@ -327,6 +395,9 @@ fn main() {
         let mut speed_ctl:Option<Direction> = None;
         let mut music_select_ctl:Option<Direction> = None;
         let mut gain_ctl:Option<Direction> = None;
+        let mut seek_to:Option<usize> = None;
+        let seek_frames = sound_manager.duration(music_select).frames.unwrap_or(0).max(1);
+        let position = sound_manager.position(music_select) % seek_frames;
         if input_role_state.get(InputRole::LeftButton).1 & 0b1111 == 0b0011
             || input_role_state.get(InputRole::LeftButton).1 & 0xfff_ffff == 0xfff_ffff
                 && t_count % 8 == 0
@@ -338,6 +409,17 @@ fn main() {
                     let ch = achar.code as usize - '0' as usize;
                     sound_generator.mute[ch] = !sound_generator.mute[ch];
                 }
+                if m_pos_bgy == SEEK_ROW {
+                    let cell = (m_pos_bgx - SEEK_X) as usize;
+                    if (0..SEEK_WIDTH).contains(&cell) {
+                        seek_to = Some(cell * seek_frames / SEEK_WIDTH);
+                    }
+                    // A, B は今の位置を設定し、設定済みなら解除する
+                    if achar.code == 'A' as u32 || achar.code == 'B' as u32 {
+                        let mark = &mut ab_loop[(achar.code - 'A' as u32) as usize];
+                        *mark = if mark.is_some() { None } else { Some(position) };
+                    }
+                }
                 if achar.code == 0x80 as u32 {
                     for ch in 0..8 {
                         sound_generator.mute[ch] = !sound_generator.mute[ch];
@@ -345,7 +427,7 @@ fn main() {
                 }
                 if achar.code == '_' as u32 || achar.code == '|' as u32 {
                     let music_no = m_pos_bgx as usize - 4;
-                    if (0..0x20).contains(&music_no) {
+                    if (0..num_sounds).contains(&music_no) {
                         selected = Some(music_no);
                     }
                 }
@@ -369,20 +451,27 @@ fn main() {
                     suppress_last = !suppress_last;
                     sound_manager.suppress_last_silence = suppress_last;
                 }
+                // チャンネルごとの指定はサウンドの定位より優先し、全体の C で譜面の定位に戻す
                 if achar.code == 'L' as u32 {
                     sound_generator.panpot[(m_pos_bgy - 6) as usize / 2] = PanPot::Left;
+                    sound_generator.pan_override[(m_pos_bgy - 6) as usize / 2] = true;
                 }
                 if achar.code == 'C' as u32 {
                     if m_pos_bgy < 6 {
                         for panpot in sound_generator.panpot.iter_mut() {
                             *panpot = PanPot::Center;
                         }
+                        for pan_override in sound_generator.pan_override.iter_mut() {
+                            *pan_override = false;
+                        }
                     } else {
                         sound_generator.panpot[(m_pos_bgy - 6) as usize / 2] = PanPot::Center;
+                        sound_generator.pan_override[(m_pos_bgy - 6) as usize / 2] = true;
                     }
                 }
                 if achar.code == 'R' as u32 {
                     sound_generator.panpot[(m_pos_bgy - 6) as usize / 2] = PanPot::Right;
+                    sound_generator.pan_override[(m_pos_bgy - 6) as usize / 2] = true;
                 }
             }
         }
@@ -414,7 +503,7 @@ fn main() {
         if input_role_state.get(InputRole::Start).1 & 0b1111 == 0b0011 {
             if let Some(music_no) = music_playing {
                 if music_no != music_select {
-                    sound_manager.play_request[music_no] = 0;
+                    sound_manager.stop(music_no);
                 }
             }
             selected = Some(music_select);
@@ -424,9 +513,10 @@ fn main() {
             selected = Some(music_select);
         }
         if input_role_state.get(InputRole::Cancel).1 & 0b1111 == 0b0011 {
-            for i in 0..0x20 {
-                sound_manager.play_request[i] = 0;
-            }
+            sound_manager.stop_all();
+        }
+        if input_role_state.get(InputRole::Pause).1 & 0b1111 == 0b0011 {
+            sound_generator.paused = !sound_generator.paused;
         }
 
 
@@ -437,10 +527,21 @@ fn main() {
         };
         music_select += match music_select_ctl {
             Some(Direction::Up) => 1,
-            Some(Direction::Down) => 31,
+            Some(Direction::Down) => num_sounds - 1,
             _ => 0,
         };
-        music_select %= 32;
+        music_select %= num_sounds;
+        if music_select_ctl.is_some() {
+            ab_loop = [None; 2];
+        }
+        if let Some(frame) = seek_to {
+            sound_manager.seek(music_select, frame);
+        }
+        if let [Some(a), Some(b)] = ab_loop {
+            if a < b && sound_manager.is_playing(music_select) && position >= b {
+                sound_manager.seek(music_select, a);
+            }
+        }
         master_gain += match gain_ctl {
             Some(Direction::Up) if master_gain < 7 => 1,
             Some(Direction::Down) if master_gain > 0 => -1,
@@ -457,14 +558,6 @@ fn main() {
                 };
             }
         }
-        for music_no in 0..0x20 {
-            if bg.1.get_code_at(music_no as i32 + 4, 22) == 'R' as u32
-                && !sound_manager.play_progress(music_no)
-                && sound_manager.play_request[music_no] == 0
-            {
-                sound_manager.play_request[music_no] = -1;
-            }
-        }
         if let Some(music_no) = music_playing {
             if !sound_manager.play_progress(music_no) && sound_manager.play_request[music_no] == 0 {
                 music_playing = None;
@@ -481,16 +574,25 @@ fn main() {
             };
             let y = (6 + ch * 2) as i32;
             bg.1.set_palette_n_at(0, y, 5, 3);
-            bg.1.set_palette_at(1 + sound_generator.panpot[ch] as i32, y, 3);
+            bg.1.set_palette_at(1 + sound_generator.effective_panpot(ch) as i32, y, 3);
             bg.1.set_palette_at(4, y, if sound_generator.mute[ch] { 5 } else { 4 });
             bg.1.set_cur_pos(6, y)
                 .put_string(&format!("{:7.2}Hz {:1} {:2} ", freq, w, gain), None);
             bg.1.set_cur_pos(21, y)
                 .put_code_n(0x7f as u32, gain)
                 .put_code_n(' ', 15 - gain);
+            let mut owner = match sound_manager.channel_owner(ch) {
+                Some(part) => format!("{}.{}", sound_manager.sound_name(part.sound_no), part.part_no),
+                None => String::new(),
+            };
+            for part in sound_manager.masked_parts(ch) {
+                owner += &format!(" -{}.{}", sound_manager.sound_name(part.sound_no), part.part_no);
+            }
+            bg.1.set_cur_pos(6, y + 1)
+                .put_string(&format!("{:<34.34}", owner), None);
         }
         bg.1.set_cur_pos(4, 22);
-        for music_no in 0..0x20 {
+        for music_no in 0..num_sounds {
             let c = match sound_manager.play_request[music_no] {
                 0 => ' ',
                 n => {
@@ -504,7 +606,7 @@ fn main() {
             bg.1.put_achar(&AChar::new(c, 1, BgSymmetry::Normal));
         }
         bg.1.set_cur_pos(4, 23);
-        for music_no in 0..0x20 {
+        for music_no in 0..num_sounds {
             let c = if sound_manager.play_progress(music_no) {
                 '|'
             } else {
@@ -515,6 +617,38 @@ fn main() {
         bg.1.set_code_n_at(4, 24, ' ', 0x20);
         bg.1.set_cur_pos(music_select as i32 + 4, 24);
         bg.1.put_achar(&AChar::new('^', 3, BgSymmetry::Normal));
+        bg.1.set_cur_pos(SEEK_X, SEEK_ROW);
+        let playing = sound_manager.is_playing(music_select);
+        let ab_range = match ab_loop {
+            [Some(a), Some(b)] => a..b,
+            _ => 0..0,
+        };
+        for cell in 0..SEEK_WIDTH {
+            let (start, end) = (cell * seek_frames / SEEK_WIDTH, (cell + 1) * seek_frames / SEEK_WIDTH);
+            let c = if playing && (start..end.max(start + 1)).contains(&position) {
+                '#'
+            } else if ab_range.contains(&start) {
+                '='
+            } else {
+                '.'
+            };
+            bg.1.put_achar(&AChar::new(c, 5, BgSymmetry::Normal));
+        }
+        bg.1.set_cur_pos(SEEK_X + SEEK_WIDTH as i32 + 1, SEEK_ROW)
+            .put_achar(&AChar::new('A', if ab_loop[0].is_some() { 5 } else { 4 }, BgSymmetry::Normal))
+            .put_achar(&AChar::new('B', if ab_loop[1].is_some() { 5 } else { 4 }, BgSymmetry::Normal));
+        // 経過と残り (mm:ss.ff) と進み具合
+        let time = match (sound_manager.duration(music_select).frames, sound_manager.elapsed(music_select)) {
+            (_, Some((elapsed, total))) => format!("{} -{}", tool_command::format_time(elapsed), tool_command::format_time(total - elapsed)),
+            (Some(total), None) => format!("{} -{}", tool_command::format_time(0), tool_command::format_time(total)),
+            (None, None) => "--:--.-- ---:--.--".to_string(),
+        };
+        let bar = sound_manager.elapsed(music_select).map_or(0, |(elapsed, total)| elapsed * 16 / total.max(1));
+        bg.1.set_cur_pos(4, TIME_ROW)
+            .put_string(&format!("{:<19}", time), None);
+        bg.1.set_cur_pos(23, TIME_ROW)
+            .put_code_n(0x7f as u32, bar as i32)
+            .put_code_n('.', 16 - bar as i32);
         #[cfg(feature = "develop")]
         {
             if input_role_state.get(InputRole::Pause).1 & 0b1111 == 0b0011 {
@@ -541,6 +675,8 @@ fn main() {
             .put_string(&format!("{:3}%", speed), None);
         let p = if suppress_last { 5 } else { 4 };
         bg.1.set_palette_at(12, 4, p);
+        bg.1.set_cur_pos(24, 1)
+            .put_string(if sound_generator.paused { "Paused" } else { "      " }, None);
 
         if wait_and_update::doing(
             &mut game_window,
@@ -0,0 +1,344 @@
+// 元のサウンド CPU (6809) プログラムを ROM イメージから動かす参照用ドライバ
+// SoundManager の再実装と同じ (波形番号, 周波数, 音量) の形でチャンネルレジスタを返す
+
+mod cpu6809;
+use cpu6809::*;
+
+use crate::sound_manager::*;
+
+// サウンド CPU のメモリマップ
+// 0000-003f: WSG (15XX) のレジスタ、0040-03ff: メイン CPU との共有 RAM
+// 2000-200f: ラッチ (アドレスの bit3-1 でビット番号、bit0 で値。bit0 = IRQ 許可、bit3 = 発音許可)
+const RAM_SIZE: usize = 0x0400;
+const WSG_REGISTER_SIZE: usize = 8;
+const LATCH_START: u16 = 0x2000;
+const LATCH_END: u16 = 0x200f;
+const LATCH_IRQ_ENABLE: u8 = 0x01;
+const LATCH_SOUND_ENABLE: u8 = 0x08;
+
+// 6809 のクロック 1.536MHz を 60Hz で割ったもの
+const CYCLES_PER_FRAME: u64 = 25600;
+// リセット直後の初期化処理を終わらせるために空回しするフレーム数
+const WARM_UP_FRAMES: usize = 8;
+
+// 共有 RAM 上でメイン CPU がサウンド番号ごとの要求を書き込む位置
+pub const DEFAULT_REQUEST_BASE: usize = 0x0040;
+
+struct SoundBoard {
+    ram: [u8; RAM_SIZE],
+    rom: Vec<u8>,
+    rom_base: usize,
+    latch: u8,
+}
+
+impl Bus for SoundBoard {
+    fn read(&mut self, adr: u16) -> u8 {
+        let adr = adr as usize;
+        if adr < RAM_SIZE {
+            self.ram[adr]
+        } else if adr >= self.rom_base && adr - self.rom_base < self.rom.len() {
+            self.rom[adr - self.rom_base]
+        } else {
+            0xff
+        }
+    }
+
+    fn write(&mut self, adr: u16, data: u8) {
+        if (adr as usize) < RAM_SIZE {
+            self.ram[adr as usize] = data;
+        } else if (LATCH_START..=LATCH_END).contains(&adr) {
+            let bit = 1 << ((adr - LATCH_START) >> 1);
+            if adr & 1 != 0 {
+                self.latch |= bit;
+            } else {
+                self.latch &= !bit;
+            }
+        }
+    }
+}
+
+pub struct ReferenceDriver {
+    cpu: Cpu6809,
+    board: SoundBoard,
+    request_base: usize,
+    last_request: Vec<i32>,
+}
+
+#[allow(dead_code)]
+impl ReferenceDriver {
+    // rom_base は ROM イメージを置く先頭アドレス (RomMap の base)
+    pub fn new(image: &[u8], rom_base: usize, request_base: usize) -> Result<Self, String> {
+        if rom_base < RAM_SIZE || rom_base + image.len() != 0x10000 {
+            return Err(format!("ROM image of {} bytes at {:04x} does not end at the 6809 vectors (ffff)", image.len(), rom_base));
+        }
+        if request_base + NUM_SOUND_IDX > RAM_SIZE {
+            return Err(format!("request area {:04x} is outside the shared RAM", request_base));
+        }
+        let mut driver = Self {
+            cpu: Cpu6809::default(),
+            board: SoundBoard {
+                ram: [0; RAM_SIZE],
+                rom: image.to_vec(),
+                rom_base,
+                latch: 0,
+            },
+            request_base,
+            last_request: vec![0; NUM_SOUND_IDX],
+        };
+        driver.cpu.reset(&mut driver.board);
+        for _ in 0..WARM_UP_FRAMES {
+            driver.run();
+        }
+        Ok(driver)
+    }
+
+    // メイン CPU と同様に、要求が変わったときだけ共有 RAM に書き込む
+    // (要求の消去はサウンド CPU 側が行うので、変化がなければ触らない)
+    // 負の要求 (ループ) は元のドライバには無いので、メイン CPU と同じく１回の要求を書き、
+    // 演奏を終えたサウンド CPU が要求を消したらまた書く
+    pub fn set_requests(&mut self, requests: &[i32]) {
+        for (idx, &request) in requests.iter().enumerate().take(NUM_SOUND_IDX) {
+            let adr = self.request_base + idx;
+            if request < 0 {
+                if request != self.last_request[idx] || self.board.ram[adr] == 0 {
+                    self.board.ram[adr] = 1;
+                }
+            } else if request != self.last_request[idx] {
+                self.board.ram[adr] = request.min(0xff) as u8;
+            }
+            self.last_request[idx] = request;
+        }
+    }
+
+    // １フレーム分: IRQ を上げてから、１フレーム分のサイクルだけ実行する
+    pub fn run(&mut self) {
+        if self.board.latch & LATCH_IRQ_ENABLE != 0 {
+            self.cpu.irq = true;
+        }
+        let mut cycles = 0;
+        while cycles < CYCLES_PER_FRAME {
+            cycles += self.cpu.step(&mut self.board);
+        }
+        self.cpu.irq = false;
+    }
+
+    pub fn get_ch_registers(&self) -> [(usize, i32, i32); 8] {
+        let mut registers = [(0, 0, 0); 8];
+        if self.board.latch & LATCH_SOUND_ENABLE == 0 {
+            return registers;
+        }
+        for (ch, register) in registers.iter_mut().enumerate() {
+            let r = &self.board.ram[ch * WSG_REGISTER_SIZE..(ch + 1) * WSG_REGISTER_SIZE];
+            let wave = ((r[6] >> 4) & 0x07) as usize;
+            let freq = r[4] as i32 | (r[5] as i32) << 8 | ((r[6] & 0x0f) as i32) << 16;
+            let gain = (r[3] & 0x0f) as i32;
+            *register = (wave, freq, gain);
+        }
+        registers
+    }
+
+    pub fn read_ram(&self, adr: usize) -> u8 {
+        self.board.ram[adr]
+    }
+}
+
+// 両ドライバの出力を比べる際に、参照ドライバ側の遅れとして試すフレーム数の上限
+const MAX_LAG: usize = 4;
+// 再実装側の演奏終了後も比較を続けるフレーム数
+const TAIL_FRAMES: usize = 8;
+
+pub type ChRegisterSet = [(usize, i32, i32); 8];
+
+// 音量 0 同士は波形・周波数が違っても同じ出力とみなす
+fn same_output(a: &(usize, i32, i32), b: &(usize, i32, i32)) -> bool {
+    (a.2 == 0 && b.2 == 0) || a == b
+}
+
+#[derive(Debug)]
+pub struct DriverMismatch {
+    pub frame: usize,
+    pub ch: usize,
+    pub manager: (usize, i32, i32),
+    pub reference: (usize, i32, i32),
+}
+
+#[derive(Debug)]
+pub struct DriverDiff {
+    pub frames: usize,
+    pub lag: usize,
+    pub mismatched_frames: usize,
+    pub first_mismatch: Option<DriverMismatch>,
+}
+
+// サウンドを１つだけ要求し、SoundManager と参照ドライバのフレームごとの出力を比べる
+// 参照ドライバは要求を拾うまでに数フレーム遅れることがあるので、一致が最も多くなる遅れを採る
+pub fn diff_sound(sound_manager: &mut SoundManager, reference: &mut ReferenceDriver, sound_no: usize, max_frames: usize) -> DriverDiff {
+    let mut manager_frames: Vec<ChRegisterSet> = Vec::new();
+    let mut reference_frames: Vec<ChRegisterSet> = Vec::new();
+    sound_manager.play_request[sound_no] = 1;
+    let mut tail = 0;
+    while manager_frames.len() < max_frames && tail < TAIL_FRAMES {
+        reference.set_requests(&sound_manager.play_request);
+        sound_manager.run();
+        reference.run();
+        manager_frames.push(sound_manager.get_ch_registers());
+        sound_manager.clear_ch_registers();
+        reference_frames.push(reference.get_ch_registers());
+        if !sound_manager.play_progress(sound_no) && sound_manager.play_request[sound_no] == 0 {
+            tail += 1;
+        }
+    }
+    for _ in 0..MAX_LAG {
+        reference.set_requests(&sound_manager.play_request);
+        reference.run();
+        reference_frames.push(reference.get_ch_registers());
+    }
+
+    let mut best: Option<DriverDiff> = None;
+    for lag in 0..=MAX_LAG {
+        let mut mismatched_frames = 0;
+        let mut first_mismatch = None;
+        for (frame, manager) in manager_frames.iter().enumerate() {
+            let reference = &reference_frames[frame + lag];
+            let ch = (0..8).find(|&ch| !same_output(&manager[ch], &reference[ch]));
+            if let Some(ch) = ch {
+                mismatched_frames += 1;
+                if first_mismatch.is_none() {
+                    first_mismatch = Some(DriverMismatch { frame, ch, manager: manager[ch], reference: reference[ch] });
+                }
+            }
+        }
+        if best.as_ref().is_none_or(|best| mismatched_frames < best.mismatched_frames) {
+            best = Some(DriverDiff { frames: manager_frames.len(), lag, mismatched_frames, first_mismatch });
+        }
+    }
+    best.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM_BASE: usize = 0x8000;
+    const FRAME_DATA: usize = 0x8100;
+    // IRQ ごとにメモリに書き込む WSG レジスタ８チャンネル分
+    const FRAME_BYTES: usize = 8 * WSG_REGISTER_SIZE;
+
+    // 要求を拾うと、ROM に並べたフレームごとのレジスタ値を IRQ ごとに WSG に書き写すプログラム
+    fn player_rom(sound_no: usize, frames: &[ChRegisterSet]) -> Vec<u8> {
+        let request = (DEFAULT_REQUEST_BASE + sound_no) as u16;
+        let end = (FRAME_DATA + frames.len() * FRAME_BYTES) as u16;
+        let [rh, rl] = request.to_be_bytes();
+        let [eh, el] = end.to_be_bytes();
+        let [dh, dl] = (FRAME_DATA as u16).to_be_bytes();
+        let mut image = vec![0x12; 0x10000 - ROM_BASE];
+        let reset = [
+            0x10, 0xce, 0x03, 0xf0, // 8000: LDS #$03f0
+            0xb7, 0x20, 0x01,       // 8004: STA $2001 (IRQ 許可)
+            0xb7, 0x20, 0x07,       // 8007: STA $2007 (発音許可)
+            0x1c, 0xef,             // 800a: ANDCC #$ef
+            0x20, 0xfe,             // 800c: BRA *
+        ];
+        let irq = [
+            0xb6, rh, rl,           // 8010: LDA request
+            0x27, 0x08,             // 8013: BEQ 801d
+            0x7f, rh, rl,           // 8015: CLR request
+            0x8e, dh, dl,           // 8018: LDX #frame data
+            0x20, 0x05,             // 801b: BRA 8022
+            0xbe, 0x03, 0xf8,       // 801d: LDX $03f8 (書き写す位置、0 なら停止中)
+            0x27, 0x18,             // 8020: BEQ 803a
+            0xce, 0x00, 0x00,       // 8022: LDU #$0000
+            0xa6, 0x80,             // 8025: LDA ,X+
+            0xa7, 0xc0,             // 8027: STA ,U+
+            0x11, 0x83, 0x00, FRAME_BYTES as u8, // 8029: CMPU #FRAME_BYTES
+            0x26, 0xf6,             // 802d: BNE 8025
+            0x8c, eh, el,           // 802f: CMPX #end
+            0x26, 0x03,             // 8032: BNE 8037
+            0x8e, 0x00, 0x00,       // 8034: LDX #$0000
+            0xbf, 0x03, 0xf8,       // 8037: STX $03f8
+            0x3b,                   // 803a: RTI
+        ];
+        image[..reset.len()].copy_from_slice(&reset);
+        image[0x10..0x10 + irq.len()].copy_from_slice(&irq);
+        for (frame, registers) in frames.iter().enumerate() {
+            let adr = FRAME_DATA - ROM_BASE + frame * FRAME_BYTES;
+            for (ch, &(wave, freq, gain)) in registers.iter().enumerate() {
+                let r = &mut image[adr + ch * WSG_REGISTER_SIZE..adr + (ch + 1) * WSG_REGISTER_SIZE];
+                r[3] = gain as u8 & 0x0f;
+                r[4] = freq as u8;
+                r[5] = (freq >> 8) as u8;
+                r[6] = (wave as u8) << 4 | (freq >> 16) as u8 & 0x0f;
+            }
+        }
+        let vectors = image.len() - 8;
+        image[vectors..].copy_from_slice(&[0x80, 0x10, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00]);
+        image
+    }
+
+    // 末尾の無音のフレームを出さない設定で比べる
+    fn new_manager() -> SoundManager<'static> {
+        let mut sound_manager = SoundManager::default();
+        sound_manager.suppress_last_silence = true;
+        sound_manager
+    }
+
+    // 再実装の出力を演奏の終わりまで記録し、無音のフレームを１つ足す
+    fn manager_frames(sound_no: usize) -> Vec<ChRegisterSet> {
+        let mut sound_manager = new_manager();
+        sound_manager.play_request[sound_no] = 1;
+        let mut frames = Vec::new();
+        while sound_manager.play_progress(sound_no) || sound_manager.play_request[sound_no] != 0 {
+            sound_manager.run();
+            frames.push(sound_manager.get_ch_registers());
+            sound_manager.clear_ch_registers();
+        }
+        frames.push([(0, 0, 0); 8]);
+        frames
+    }
+
+    #[test]
+    fn diff_sound_matches_a_player_of_the_same_output() {
+        let chime = SoundIdx::Chime as usize;
+        let mut frames = manager_frames(chime);
+        assert!(FRAME_DATA + frames.len() * FRAME_BYTES < 0xfff0, "{} frames do not fit in the ROM", frames.len());
+        let mut reference = ReferenceDriver::new(&player_rom(chime, &frames), ROM_BASE, DEFAULT_REQUEST_BASE).unwrap();
+        let mut sound_manager = new_manager();
+        let diff = diff_sound(&mut sound_manager, &mut reference, chime, 1000);
+        assert_eq!(diff.mismatched_frames, 0);
+        assert!(diff.first_mismatch.is_none());
+
+        // １フレームだけ音量を変えると、そのフレームを最初の不一致として返す
+        let frame = frames.iter().position(|registers| registers[0].2 > 1).unwrap() + 2;
+        frames[frame][0].2 = (frames[frame][0].2 + 1) & 0x0f;
+        let mut reference = ReferenceDriver::new(&player_rom(chime, &frames), ROM_BASE, DEFAULT_REQUEST_BASE).unwrap();
+        let mut sound_manager = new_manager();
+        let diff = diff_sound(&mut sound_manager, &mut reference, chime, 1000);
+        assert_eq!(diff.lag, 0);
+        assert_eq!(diff.mismatched_frames, 1);
+        let mismatch = diff.first_mismatch.unwrap();
+        assert_eq!((mismatch.frame, mismatch.ch), (frame, 0));
+        assert_eq!(mismatch.reference.2, frames[frame][0].2);
+    }
+
+    #[test]
+    fn loop_requests_are_written_again_after_the_driver_clears_them() {
+        let chime = SoundIdx::Chime as usize;
+        let adr = DEFAULT_REQUEST_BASE + chime;
+        let mut reference = ReferenceDriver::new(&player_rom(chime, &[]), ROM_BASE, DEFAULT_REQUEST_BASE).unwrap();
+        let mut requests = vec![0; NUM_SOUND_IDX];
+        requests[chime] = -1;
+        reference.set_requests(&requests);
+        assert_eq!(reference.board.ram[adr], 1);
+        // サウンド CPU が消したら書き直す
+        reference.board.ram[adr] = 0;
+        reference.set_requests(&requests);
+        assert_eq!(reference.board.ram[adr], 1);
+        // 回数の要求は変わったときだけ書く
+        requests[chime] = 0x123;
+        reference.set_requests(&requests);
+        assert_eq!(reference.board.ram[adr], 0xff);
+        reference.board.ram[adr] = 0;
+        reference.set_requests(&requests);
+        assert_eq!(reference.board.ram[adr], 0);
+    }
+}
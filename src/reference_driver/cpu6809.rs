@@ -0,0 +1,1075 @@
+// MC6809 の命令セットインタプリタ
+// サイクル数は厳密ではなく、バスアクセス数 + 命令あたり１で近似する
+
+pub trait Bus {
+    fn read(&mut self, adr: u16) -> u8;
+    fn write(&mut self, adr: u16, data: u8);
+}
+
+const CC_E: u8 = 0x80;
+const CC_F: u8 = 0x40;
+const CC_H: u8 = 0x20;
+const CC_I: u8 = 0x10;
+const CC_N: u8 = 0x08;
+const CC_Z: u8 = 0x04;
+const CC_V: u8 = 0x02;
+const CC_C: u8 = 0x01;
+
+const VECTOR_SWI3: u16 = 0xfff2;
+const VECTOR_SWI2: u16 = 0xfff4;
+const VECTOR_FIRQ: u16 = 0xfff6;
+const VECTOR_IRQ: u16 = 0xfff8;
+const VECTOR_SWI: u16 = 0xfffa;
+const VECTOR_NMI: u16 = 0xfffc;
+const VECTOR_RESET: u16 = 0xfffe;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+enum Wait {
+    #[default]
+    Running,
+    Sync,
+    Cwai,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Immediate,
+    Direct,
+    Indexed,
+    Extended,
+}
+
+#[derive(Default, Debug)]
+pub struct Cpu6809 {
+    pub a: u8,
+    pub b: u8,
+    pub dp: u8,
+    pub cc: u8,
+    pub x: u16,
+    pub y: u16,
+    pub u: u16,
+    pub s: u16,
+    pub pc: u16,
+    // 割り込み要求線 (IRQ, FIRQ は受け付けた時点で下ろす: HOLD_LINE 相当、NMI はエッジ)
+    pub irq: bool,
+    pub firq: bool,
+    pub nmi: bool,
+    wait: Wait,
+    cycles: u64,
+}
+
+#[allow(dead_code)]
+impl Cpu6809 {
+    pub fn reset(&mut self, bus: &mut impl Bus) {
+        self.dp = 0;
+        self.cc = CC_I | CC_F;
+        self.irq = false;
+        self.firq = false;
+        self.nmi = false;
+        self.wait = Wait::Running;
+        self.pc = self.read16(bus, VECTOR_RESET);
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.wait != Wait::Running
+    }
+
+    fn d(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.b])
+    }
+
+    fn set_d(&mut self, v: u16) {
+        [self.a, self.b] = v.to_be_bytes();
+    }
+
+    fn flag(&self, mask: u8) -> bool {
+        self.cc & mask != 0
+    }
+
+    fn set_flag(&mut self, mask: u8, on: bool) {
+        if on {
+            self.cc |= mask;
+        } else {
+            self.cc &= !mask;
+        }
+    }
+
+    fn set_nz8(&mut self, v: u8) {
+        self.set_flag(CC_N, v & 0x80 != 0);
+        self.set_flag(CC_Z, v == 0);
+    }
+
+    fn set_nz16(&mut self, v: u16) {
+        self.set_flag(CC_N, v & 0x8000 != 0);
+        self.set_flag(CC_Z, v == 0);
+    }
+
+    fn set_nzv8(&mut self, v: u8) {
+        self.set_nz8(v);
+        self.set_flag(CC_V, false);
+    }
+
+    fn set_nzv16(&mut self, v: u16) {
+        self.set_nz16(v);
+        self.set_flag(CC_V, false);
+    }
+
+    fn read8(&mut self, bus: &mut impl Bus, adr: u16) -> u8 {
+        self.cycles += 1;
+        bus.read(adr)
+    }
+
+    fn write8(&mut self, bus: &mut impl Bus, adr: u16, v: u8) {
+        self.cycles += 1;
+        bus.write(adr, v);
+    }
+
+    fn read16(&mut self, bus: &mut impl Bus, adr: u16) -> u16 {
+        let hi = self.read8(bus, adr);
+        let lo = self.read8(bus, adr.wrapping_add(1));
+        u16::from_be_bytes([hi, lo])
+    }
+
+    fn write16(&mut self, bus: &mut impl Bus, adr: u16, v: u16) {
+        let [hi, lo] = v.to_be_bytes();
+        self.write8(bus, adr, hi);
+        self.write8(bus, adr.wrapping_add(1), lo);
+    }
+
+    fn fetch8(&mut self, bus: &mut impl Bus) -> u8 {
+        let v = self.read8(bus, self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        v
+    }
+
+    fn fetch16(&mut self, bus: &mut impl Bus) -> u16 {
+        let v = self.read16(bus, self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        v
+    }
+
+    fn push8(&mut self, bus: &mut impl Bus, user: bool, v: u8) {
+        let sp = if user { &mut self.u } else { &mut self.s };
+        *sp = sp.wrapping_sub(1);
+        let adr = *sp;
+        self.write8(bus, adr, v);
+    }
+
+    fn push16(&mut self, bus: &mut impl Bus, user: bool, v: u16) {
+        let [hi, lo] = v.to_be_bytes();
+        self.push8(bus, user, lo);
+        self.push8(bus, user, hi);
+    }
+
+    fn pull8(&mut self, bus: &mut impl Bus, user: bool) -> u8 {
+        let adr = if user { self.u } else { self.s };
+        let v = self.read8(bus, adr);
+        if user {
+            self.u = adr.wrapping_add(1);
+        } else {
+            self.s = adr.wrapping_add(1);
+        }
+        v
+    }
+
+    fn pull16(&mut self, bus: &mut impl Bus, user: bool) -> u16 {
+        let hi = self.pull8(bus, user);
+        let lo = self.pull8(bus, user);
+        u16::from_be_bytes([hi, lo])
+    }
+
+    // PSHS/PSHU と同じ順で積む (PC が最初、CC が最後)
+    fn push_registers(&mut self, bus: &mut impl Bus, user: bool, mask: u8) {
+        if mask & 0x80 != 0 {
+            self.push16(bus, user, self.pc);
+        }
+        if mask & 0x40 != 0 {
+            let other = if user { self.s } else { self.u };
+            self.push16(bus, user, other);
+        }
+        if mask & 0x20 != 0 {
+            self.push16(bus, user, self.y);
+        }
+        if mask & 0x10 != 0 {
+            self.push16(bus, user, self.x);
+        }
+        if mask & 0x08 != 0 {
+            self.push8(bus, user, self.dp);
+        }
+        if mask & 0x04 != 0 {
+            self.push8(bus, user, self.b);
+        }
+        if mask & 0x02 != 0 {
+            self.push8(bus, user, self.a);
+        }
+        if mask & 0x01 != 0 {
+            self.push8(bus, user, self.cc);
+        }
+    }
+
+    fn pull_registers(&mut self, bus: &mut impl Bus, user: bool, mask: u8) {
+        if mask & 0x01 != 0 {
+            self.cc = self.pull8(bus, user);
+        }
+        if mask & 0x02 != 0 {
+            self.a = self.pull8(bus, user);
+        }
+        if mask & 0x04 != 0 {
+            self.b = self.pull8(bus, user);
+        }
+        if mask & 0x08 != 0 {
+            self.dp = self.pull8(bus, user);
+        }
+        if mask & 0x10 != 0 {
+            self.x = self.pull16(bus, user);
+        }
+        if mask & 0x20 != 0 {
+            self.y = self.pull16(bus, user);
+        }
+        if mask & 0x40 != 0 {
+            let v = self.pull16(bus, user);
+            if user {
+                self.s = v;
+            } else {
+                self.u = v;
+            }
+        }
+        if mask & 0x80 != 0 {
+            self.pc = self.pull16(bus, user);
+        }
+    }
+
+    fn interrupt(&mut self, bus: &mut impl Bus, entire: bool, mask: u8, vector: u16) {
+        if self.wait != Wait::Cwai {
+            self.set_flag(CC_E, entire);
+            let registers = if entire { 0xff } else { 0x81 };
+            self.push_registers(bus, false, registers);
+        }
+        self.wait = Wait::Running;
+        self.cc |= mask;
+        self.pc = self.read16(bus, vector);
+    }
+
+    // 割り込みの受け付け、または１命令の実行を行い、使ったサイクル数を返す
+    pub fn step(&mut self, bus: &mut impl Bus) -> u64 {
+        let start = self.cycles;
+        if self.wait == Wait::Sync && (self.nmi || self.firq || self.irq) {
+            self.wait = Wait::Running;
+        }
+        if self.nmi {
+            self.nmi = false;
+            self.interrupt(bus, true, CC_I | CC_F, VECTOR_NMI);
+        } else if self.firq && !self.flag(CC_F) {
+            self.firq = false;
+            self.interrupt(bus, false, CC_I | CC_F, VECTOR_FIRQ);
+        } else if self.irq && !self.flag(CC_I) {
+            self.irq = false;
+            self.interrupt(bus, true, CC_I, VECTOR_IRQ);
+        } else if self.wait != Wait::Running {
+            self.cycles += 1;
+        } else {
+            self.cycles += 1;
+            let op = self.fetch8(bus);
+            self.execute(bus, op);
+        }
+        self.cycles - start
+    }
+
+    fn index_register(&self, code: u8) -> u16 {
+        match code & 3 {
+            0 => self.x,
+            1 => self.y,
+            2 => self.u,
+            _ => self.s,
+        }
+    }
+
+    fn set_index_register(&mut self, code: u8, v: u16) {
+        match code & 3 {
+            0 => self.x = v,
+            1 => self.y = v,
+            2 => self.u = v,
+            _ => self.s = v,
+        }
+    }
+
+    fn ea_indexed(&mut self, bus: &mut impl Bus) -> u16 {
+        let post = self.fetch8(bus);
+        let code = post >> 5;
+        let r = self.index_register(code);
+        if post & 0x80 == 0 {
+            let offset = (((post & 0x1f) << 3) as i8 >> 3) as i16;
+            self.cycles += 1;
+            return r.wrapping_add(offset as u16);
+        }
+        let ea = match post & 0x0f {
+            0x0 => {
+                self.set_index_register(code, r.wrapping_add(1));
+                r
+            },
+            0x1 => {
+                self.set_index_register(code, r.wrapping_add(2));
+                r
+            },
+            0x2 => {
+                let r = r.wrapping_sub(1);
+                self.set_index_register(code, r);
+                r
+            },
+            0x3 => {
+                let r = r.wrapping_sub(2);
+                self.set_index_register(code, r);
+                r
+            },
+            0x5 => r.wrapping_add(self.b as i8 as u16),
+            0x6 => r.wrapping_add(self.a as i8 as u16),
+            0x8 => {
+                let offset = self.fetch8(bus) as i8 as u16;
+                r.wrapping_add(offset)
+            },
+            0x9 => {
+                let offset = self.fetch16(bus);
+                r.wrapping_add(offset)
+            },
+            0xb => r.wrapping_add(self.d()),
+            0xc => {
+                let offset = self.fetch8(bus) as i8 as u16;
+                self.pc.wrapping_add(offset)
+            },
+            0xd => {
+                let offset = self.fetch16(bus);
+                self.pc.wrapping_add(offset)
+            },
+            0xf => self.fetch16(bus),
+            _ => r,
+        };
+        self.cycles += 1;
+        if post & 0x10 != 0 {
+            self.read16(bus, ea)
+        } else {
+            ea
+        }
+    }
+
+    fn ea(&mut self, bus: &mut impl Bus, mode: Mode) -> u16 {
+        match mode {
+            Mode::Direct => {
+                let lo = self.fetch8(bus);
+                u16::from_be_bytes([self.dp, lo])
+            },
+            Mode::Indexed => self.ea_indexed(bus),
+            Mode::Extended => self.fetch16(bus),
+            Mode::Immediate => {
+                let adr = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                adr
+            },
+        }
+    }
+
+    fn operand8(&mut self, bus: &mut impl Bus, mode: Mode) -> u8 {
+        match mode {
+            Mode::Immediate => self.fetch8(bus),
+            _ => {
+                let adr = self.ea(bus, mode);
+                self.read8(bus, adr)
+            },
+        }
+    }
+
+    fn operand16(&mut self, bus: &mut impl Bus, mode: Mode) -> u16 {
+        match mode {
+            Mode::Immediate => self.fetch16(bus),
+            _ => {
+                let adr = self.ea(bus, mode);
+                self.read16(bus, adr)
+            },
+        }
+    }
+
+    fn add8(&mut self, a: u8, b: u8, carry: bool) -> u8 {
+        let r = a as u16 + b as u16 + carry as u16;
+        let r8 = r as u8;
+        self.set_flag(CC_H, (a ^ b ^ r8) & 0x10 != 0);
+        self.set_nz8(r8);
+        self.set_flag(CC_V, (a ^ r8) & (b ^ r8) & 0x80 != 0);
+        self.set_flag(CC_C, r > 0xff);
+        r8
+    }
+
+    fn sub8(&mut self, a: u8, b: u8, borrow: bool) -> u8 {
+        let r = (a as u16).wrapping_sub(b as u16).wrapping_sub(borrow as u16);
+        let r8 = r as u8;
+        self.set_nz8(r8);
+        self.set_flag(CC_V, (a ^ b) & (a ^ r8) & 0x80 != 0);
+        self.set_flag(CC_C, r & 0x100 != 0);
+        r8
+    }
+
+    fn add16(&mut self, a: u16, b: u16) -> u16 {
+        let r = a as u32 + b as u32;
+        let r16 = r as u16;
+        self.set_nz16(r16);
+        self.set_flag(CC_V, (a ^ r16) & (b ^ r16) & 0x8000 != 0);
+        self.set_flag(CC_C, r > 0xffff);
+        r16
+    }
+
+    fn sub16(&mut self, a: u16, b: u16) -> u16 {
+        let r = (a as u32).wrapping_sub(b as u32);
+        let r16 = r as u16;
+        self.set_nz16(r16);
+        self.set_flag(CC_V, (a ^ b) & (a ^ r16) & 0x8000 != 0);
+        self.set_flag(CC_C, r & 0x1_0000 != 0);
+        r16
+    }
+
+    fn condition(&self, code: u8) -> bool {
+        let n = self.flag(CC_N);
+        let z = self.flag(CC_Z);
+        let v = self.flag(CC_V);
+        let c = self.flag(CC_C);
+        match code & 0x0f {
+            0x0 => true,
+            0x1 => false,
+            0x2 => !(c || z),
+            0x3 => c || z,
+            0x4 => !c,
+            0x5 => c,
+            0x6 => !z,
+            0x7 => z,
+            0x8 => !v,
+            0x9 => v,
+            0xa => !n,
+            0xb => n,
+            0xc => n == v,
+            0xd => n != v,
+            0xe => !z && n == v,
+            _ => z || n != v,
+        }
+    }
+
+    fn register_value(&self, code: u8) -> u16 {
+        match code {
+            0x0 => self.d(),
+            0x1 => self.x,
+            0x2 => self.y,
+            0x3 => self.u,
+            0x4 => self.s,
+            0x5 => self.pc,
+            0x8 => 0xff00 | self.a as u16,
+            0x9 => 0xff00 | self.b as u16,
+            0xa => 0xff00 | self.cc as u16,
+            0xb => 0xff00 | self.dp as u16,
+            _ => 0xffff,
+        }
+    }
+
+    fn set_register(&mut self, code: u8, v: u16) {
+        match code {
+            0x0 => self.set_d(v),
+            0x1 => self.x = v,
+            0x2 => self.y = v,
+            0x3 => self.u = v,
+            0x4 => self.s = v,
+            0x5 => self.pc = v,
+            0x8 => self.a = v as u8,
+            0x9 => self.b = v as u8,
+            0xa => self.cc = v as u8,
+            0xb => self.dp = v as u8,
+            _ => (),
+        }
+    }
+
+    // 0x00-0x0f, 0x40-0x7f: 読み出し・変更・書き込み型の命令
+    fn execute_rmw(&mut self, bus: &mut impl Bus, op: u8) {
+        let adr = match op >> 4 {
+            0x0 => Some(self.ea(bus, Mode::Direct)),
+            0x6 => Some(self.ea(bus, Mode::Indexed)),
+            0x7 => Some(self.ea(bus, Mode::Extended)),
+            _ => None,
+        };
+        if op & 0x0f == 0x0e {
+            if let Some(adr) = adr {
+                self.pc = adr;
+            }
+            return;
+        }
+        let m = match (op >> 4, adr) {
+            (0x4, _) => self.a,
+            (0x5, _) => self.b,
+            (_, Some(adr)) => self.read8(bus, adr),
+            _ => 0,
+        };
+        let c = self.flag(CC_C);
+        let r = match op & 0x0f {
+            0x0 => {
+                let r = self.sub8(0, m, false);
+                Some(r)
+            },
+            0x3 => {
+                let r = !m;
+                self.set_nzv8(r);
+                self.set_flag(CC_C, true);
+                Some(r)
+            },
+            0x4 => {
+                let r = m >> 1;
+                self.set_nz8(r);
+                self.set_flag(CC_C, m & 0x01 != 0);
+                Some(r)
+            },
+            0x6 => {
+                let r = ((c as u8) << 7) | (m >> 1);
+                self.set_nz8(r);
+                self.set_flag(CC_C, m & 0x01 != 0);
+                Some(r)
+            },
+            0x7 => {
+                let r = (m & 0x80) | (m >> 1);
+                self.set_nz8(r);
+                self.set_flag(CC_C, m & 0x01 != 0);
+                Some(r)
+            },
+            0x8 => {
+                let r = m << 1;
+                self.set_nz8(r);
+                self.set_flag(CC_V, (m ^ r) & 0x80 != 0);
+                self.set_flag(CC_C, m & 0x80 != 0);
+                Some(r)
+            },
+            0x9 => {
+                let r = (m << 1) | c as u8;
+                self.set_nz8(r);
+                self.set_flag(CC_V, (m ^ (m << 1)) & 0x80 != 0);
+                self.set_flag(CC_C, m & 0x80 != 0);
+                Some(r)
+            },
+            0xa => {
+                let r = m.wrapping_sub(1);
+                self.set_nz8(r);
+                self.set_flag(CC_V, m == 0x80);
+                Some(r)
+            },
+            0xc => {
+                let r = m.wrapping_add(1);
+                self.set_nz8(r);
+                self.set_flag(CC_V, m == 0x7f);
+                Some(r)
+            },
+            0xd => {
+                self.set_nzv8(m);
+                None
+            },
+            0xf => {
+                self.cc = (self.cc & !(CC_N | CC_V | CC_C)) | CC_Z;
+                Some(0)
+            },
+            _ => None, // 未定義命令
+        };
+        if let Some(r) = r {
+            match (op >> 4, adr) {
+                (0x4, _) => self.a = r,
+                (0x5, _) => self.b = r,
+                (_, Some(adr)) => self.write8(bus, adr, r),
+                _ => (),
+            }
+        }
+    }
+
+    // 0x80-0xff: アキュムレータ A/B と 16bit レジスタの命令
+    fn execute_accumulator(&mut self, bus: &mut impl Bus, op: u8) {
+        let use_b = op >= 0xc0;
+        let mode = match (op >> 4) & 3 {
+            0 => Mode::Immediate,
+            1 => Mode::Direct,
+            2 => Mode::Indexed,
+            _ => Mode::Extended,
+        };
+        let acc = if use_b { self.b } else { self.a };
+        let result = match op & 0x0f {
+            0x0 => {
+                let m = self.operand8(bus, mode);
+                Some(self.sub8(acc, m, false))
+            },
+            0x1 => {
+                let m = self.operand8(bus, mode);
+                self.sub8(acc, m, false);
+                None
+            },
+            0x2 => {
+                let m = self.operand8(bus, mode);
+                let c = self.flag(CC_C);
+                Some(self.sub8(acc, m, c))
+            },
+            0x3 => {
+                let m = self.operand16(bus, mode);
+                let d = self.d();
+                let r = if use_b { self.add16(d, m) } else { self.sub16(d, m) };
+                self.set_d(r);
+                None
+            },
+            0x4 => {
+                let r = acc & self.operand8(bus, mode);
+                self.set_nzv8(r);
+                Some(r)
+            },
+            0x5 => {
+                let r = acc & self.operand8(bus, mode);
+                self.set_nzv8(r);
+                None
+            },
+            0x6 => {
+                let r = self.operand8(bus, mode);
+                self.set_nzv8(r);
+                Some(r)
+            },
+            0x7 => {
+                let adr = self.ea(bus, mode);
+                self.write8(bus, adr, acc);
+                self.set_nzv8(acc);
+                None
+            },
+            0x8 => {
+                let r = acc ^ self.operand8(bus, mode);
+                self.set_nzv8(r);
+                Some(r)
+            },
+            0x9 => {
+                let m = self.operand8(bus, mode);
+                let c = self.flag(CC_C);
+                Some(self.add8(acc, m, c))
+            },
+            0xa => {
+                let r = acc | self.operand8(bus, mode);
+                self.set_nzv8(r);
+                Some(r)
+            },
+            0xb => {
+                let m = self.operand8(bus, mode);
+                Some(self.add8(acc, m, false))
+            },
+            0xc => {
+                let m = self.operand16(bus, mode);
+                if use_b {
+                    self.set_d(m);
+                    self.set_nzv16(m);
+                } else {
+                    self.sub16(self.x, m);
+                }
+                None
+            },
+            0xd => {
+                if use_b {
+                    let adr = self.ea(bus, mode);
+                    let d = self.d();
+                    self.write16(bus, adr, d);
+                    self.set_nzv16(d);
+                } else if mode == Mode::Immediate {
+                    let offset = self.fetch8(bus) as i8 as u16;
+                    self.push16(bus, false, self.pc);
+                    self.pc = self.pc.wrapping_add(offset);
+                } else {
+                    let adr = self.ea(bus, mode);
+                    self.push16(bus, false, self.pc);
+                    self.pc = adr;
+                }
+                None
+            },
+            0xe => {
+                let m = self.operand16(bus, mode);
+                self.set_nzv16(m);
+                if use_b {
+                    self.u = m;
+                } else {
+                    self.x = m;
+                }
+                None
+            },
+            _ => {
+                let adr = self.ea(bus, mode);
+                let v = if use_b { self.u } else { self.x };
+                self.write16(bus, adr, v);
+                self.set_nzv16(v);
+                None
+            },
+        };
+        if let Some(r) = result {
+            if use_b {
+                self.b = r;
+            } else {
+                self.a = r;
+            }
+        }
+    }
+
+    fn execute_page2(&mut self, bus: &mut impl Bus, op: u8) {
+        let mode = match (op >> 4) & 3 {
+            0 => Mode::Immediate,
+            1 => Mode::Direct,
+            2 => Mode::Indexed,
+            _ => Mode::Extended,
+        };
+        match op {
+            0x21..=0x2f => {
+                let offset = self.fetch16(bus);
+                if self.condition(op) {
+                    self.pc = self.pc.wrapping_add(offset);
+                }
+            },
+            0x3f => {
+                self.set_flag(CC_E, true);
+                self.push_registers(bus, false, 0xff);
+                self.pc = self.read16(bus, VECTOR_SWI2);
+            },
+            0x83 | 0x93 | 0xa3 | 0xb3 => {
+                let m = self.operand16(bus, mode);
+                self.sub16(self.d(), m);
+            },
+            0x8c | 0x9c | 0xac | 0xbc => {
+                let m = self.operand16(bus, mode);
+                self.sub16(self.y, m);
+            },
+            0x8e | 0x9e | 0xae | 0xbe => {
+                self.y = self.operand16(bus, mode);
+                self.set_nzv16(self.y);
+            },
+            0x9f | 0xaf | 0xbf => {
+                let adr = self.ea(bus, mode);
+                self.write16(bus, adr, self.y);
+                self.set_nzv16(self.y);
+            },
+            0xce | 0xde | 0xee | 0xfe => {
+                self.s = self.operand16(bus, mode);
+                self.set_nzv16(self.s);
+            },
+            0xdf | 0xef | 0xff => {
+                let adr = self.ea(bus, mode);
+                self.write16(bus, adr, self.s);
+                self.set_nzv16(self.s);
+            },
+            _ => (),
+        }
+    }
+
+    fn execute_page3(&mut self, bus: &mut impl Bus, op: u8) {
+        let mode = match (op >> 4) & 3 {
+            0 => Mode::Immediate,
+            1 => Mode::Direct,
+            2 => Mode::Indexed,
+            _ => Mode::Extended,
+        };
+        match op {
+            0x3f => {
+                self.set_flag(CC_E, true);
+                self.push_registers(bus, false, 0xff);
+                self.pc = self.read16(bus, VECTOR_SWI3);
+            },
+            0x83 | 0x93 | 0xa3 | 0xb3 => {
+                let m = self.operand16(bus, mode);
+                self.sub16(self.u, m);
+            },
+            0x8c | 0x9c | 0xac | 0xbc => {
+                let m = self.operand16(bus, mode);
+                self.sub16(self.s, m);
+            },
+            _ => (),
+        }
+    }
+
+    fn execute(&mut self, bus: &mut impl Bus, op: u8) {
+        match op {
+            0x10 => {
+                let op2 = self.fetch8(bus);
+                self.execute_page2(bus, op2);
+            },
+            0x11 => {
+                let op2 = self.fetch8(bus);
+                self.execute_page3(bus, op2);
+            },
+            0x00..=0x0f | 0x40..=0x7f => self.execute_rmw(bus, op),
+            0x80..=0xff => self.execute_accumulator(bus, op),
+            0x13 => self.wait = Wait::Sync,
+            0x16 => {
+                let offset = self.fetch16(bus);
+                self.pc = self.pc.wrapping_add(offset);
+            },
+            0x17 => {
+                let offset = self.fetch16(bus);
+                self.push16(bus, false, self.pc);
+                self.pc = self.pc.wrapping_add(offset);
+            },
+            0x19 => {
+                let a = self.a;
+                let mut correction = 0u16;
+                if a & 0x0f > 0x09 || self.flag(CC_H) {
+                    correction |= 0x06;
+                }
+                if a > 0x99 || self.flag(CC_C) || (a > 0x89 && a & 0x0f > 0x09) {
+                    correction |= 0x60;
+                }
+                let r = a as u16 + correction;
+                self.a = r as u8;
+                self.set_nz8(self.a);
+                self.set_flag(CC_V, false);
+                if r > 0xff {
+                    self.set_flag(CC_C, true);
+                }
+            },
+            0x1a => self.cc |= self.fetch8(bus),
+            0x1c => self.cc &= self.fetch8(bus),
+            0x1d => {
+                self.a = if self.b & 0x80 != 0 { 0xff } else { 0x00 };
+                self.set_nz16(self.d());
+            },
+            0x1e => {
+                let post = self.fetch8(bus);
+                let v0 = self.register_value(post >> 4);
+                let v1 = self.register_value(post & 0x0f);
+                self.set_register(post >> 4, v1);
+                self.set_register(post & 0x0f, v0);
+            },
+            0x1f => {
+                let post = self.fetch8(bus);
+                let v = self.register_value(post >> 4);
+                self.set_register(post & 0x0f, v);
+            },
+            0x20..=0x2f => {
+                let offset = self.fetch8(bus) as i8 as u16;
+                if self.condition(op) {
+                    self.pc = self.pc.wrapping_add(offset);
+                }
+            },
+            0x30..=0x33 => {
+                let adr = self.ea(bus, Mode::Indexed);
+                match op {
+                    0x30 => {
+                        self.x = adr;
+                        self.set_flag(CC_Z, adr == 0);
+                    },
+                    0x31 => {
+                        self.y = adr;
+                        self.set_flag(CC_Z, adr == 0);
+                    },
+                    0x32 => self.s = adr,
+                    _ => self.u = adr,
+                }
+            },
+            0x34 => {
+                let mask = self.fetch8(bus);
+                self.push_registers(bus, false, mask);
+            },
+            0x35 => {
+                let mask = self.fetch8(bus);
+                self.pull_registers(bus, false, mask);
+            },
+            0x36 => {
+                let mask = self.fetch8(bus);
+                self.push_registers(bus, true, mask);
+            },
+            0x37 => {
+                let mask = self.fetch8(bus);
+                self.pull_registers(bus, true, mask);
+            },
+            0x39 => self.pc = self.pull16(bus, false),
+            0x3a => self.x = self.x.wrapping_add(self.b as u16),
+            0x3b => {
+                self.pull_registers(bus, false, 0x01);
+                let mask = if self.flag(CC_E) { 0xfe } else { 0x80 };
+                self.pull_registers(bus, false, mask);
+            },
+            0x3c => {
+                self.cc &= self.fetch8(bus);
+                self.set_flag(CC_E, true);
+                self.push_registers(bus, false, 0xff);
+                self.wait = Wait::Cwai;
+            },
+            0x3d => {
+                let r = self.a as u16 * self.b as u16;
+                self.set_d(r);
+                self.set_flag(CC_Z, r == 0);
+                self.set_flag(CC_C, r & 0x80 != 0);
+            },
+            0x3f => {
+                self.set_flag(CC_E, true);
+                self.push_registers(bus, false, 0xff);
+                self.cc |= CC_I | CC_F;
+                self.pc = self.read16(bus, VECTOR_SWI);
+            },
+            _ => (), // NOP (0x12) と未定義命令
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM_START: u16 = 0x1000;
+
+    struct Memory([u8; 0x10000]);
+
+    impl Bus for Memory {
+        fn read(&mut self, adr: u16) -> u8 {
+            self.0[adr as usize]
+        }
+
+        fn write(&mut self, adr: u16, data: u8) {
+            self.0[adr as usize] = data;
+        }
+    }
+
+    fn memory(program: &[u8]) -> Memory {
+        let mut memory = Memory([0; 0x10000]);
+        let start = PROGRAM_START as usize;
+        memory.0[start..start + program.len()].copy_from_slice(program);
+        memory.0[VECTOR_RESET as usize..].copy_from_slice(&PROGRAM_START.to_be_bytes());
+        memory
+    }
+
+    // プログラムの終わりまで実行する
+    fn run(cpu: &mut Cpu6809, memory: &mut Memory, program_len: usize) {
+        cpu.reset(memory);
+        let end = PROGRAM_START + program_len as u16;
+        for _ in 0..1000 {
+            if cpu.pc == end {
+                return;
+            }
+            cpu.step(memory);
+        }
+        panic!("program did not reach {:04x} (pc {:04x})", end, cpu.pc);
+    }
+
+    fn execute(program: &[u8]) -> Cpu6809 {
+        let mut cpu = Cpu6809::default();
+        run(&mut cpu, &mut memory(program), program.len());
+        cpu
+    }
+
+    #[test]
+    fn add_sets_carry_and_overflow() {
+        // LDA #$7f; ADDA #$01
+        let cpu = execute(&[0x86, 0x7f, 0x8b, 0x01]);
+        assert_eq!(cpu.a, 0x80);
+        assert_eq!(cpu.cc & (CC_N | CC_Z | CC_V | CC_C | CC_H), CC_N | CC_V | CC_H);
+        // LDA #$ff; ADDA #$01
+        let cpu = execute(&[0x86, 0xff, 0x8b, 0x01]);
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.cc & (CC_N | CC_Z | CC_V | CC_C), CC_Z | CC_C);
+        // ORCC #$01; LDB #$10; ADCB #$0f
+        let cpu = execute(&[0x1a, 0x01, 0xc6, 0x10, 0xc9, 0x0f]);
+        assert_eq!(cpu.b, 0x20);
+        assert_eq!(cpu.cc & (CC_V | CC_C), 0);
+        // LDD #$7fff; ADDD #$0001
+        let cpu = execute(&[0xcc, 0x7f, 0xff, 0xc3, 0x00, 0x01]);
+        assert_eq!(cpu.d(), 0x8000);
+        assert_eq!(cpu.cc & (CC_N | CC_Z | CC_V | CC_C), CC_N | CC_V);
+    }
+
+    #[test]
+    fn sub_sets_borrow_and_overflow() {
+        // LDA #$80; SUBA #$01
+        let cpu = execute(&[0x86, 0x80, 0x80, 0x01]);
+        assert_eq!(cpu.a, 0x7f);
+        assert_eq!(cpu.cc & (CC_N | CC_Z | CC_V | CC_C), CC_V);
+        // LDA #$00; SUBA #$01
+        let cpu = execute(&[0x86, 0x00, 0x80, 0x01]);
+        assert_eq!(cpu.a, 0xff);
+        assert_eq!(cpu.cc & (CC_N | CC_Z | CC_V | CC_C), CC_N | CC_C);
+        // ORCC #$01; LDB #$10; SBCB #$0f
+        let cpu = execute(&[0x1a, 0x01, 0xc6, 0x10, 0xc2, 0x0f]);
+        assert_eq!(cpu.b, 0x00);
+        assert_eq!(cpu.cc & (CC_Z | CC_C), CC_Z);
+        // LDA #$05; CMPA #$05 (A は変わらない)
+        let cpu = execute(&[0x86, 0x05, 0x81, 0x05]);
+        assert_eq!(cpu.a, 0x05);
+        assert_eq!(cpu.cc & (CC_Z | CC_C), CC_Z);
+        // LDD #$8000; SUBD #$0001
+        let cpu = execute(&[0xcc, 0x80, 0x00, 0x83, 0x00, 0x01]);
+        assert_eq!(cpu.d(), 0x7fff);
+        assert_eq!(cpu.cc & (CC_N | CC_Z | CC_V | CC_C), CC_V);
+    }
+
+    #[test]
+    fn indexed_addressing_modes() {
+        // LDX #$2010; LDY #$2020; LDU #$2030; LDD #$ff05 (A = -1, B = 5)
+        let setup = [0x8e, 0x20, 0x10, 0x10, 0x8e, 0x20, 0x20, 0xce, 0x20, 0x30, 0xcc, 0xff, 0x05];
+        // LEAX の次の命令の先頭 (PC 相対の基準)
+        let pcr = PROGRAM_START + setup.len() as u16 + 3;
+        let cases: [(&[u8], u16); 13] = [
+            (&[0x1f], 0x200f),             // -1,X (5 ビットのオフセット)
+            (&[0x88, 0x02], 0x2012),       // 2,X (8 ビットのオフセット)
+            (&[0x88, 0xfe], 0x200e),       // -2,X
+            (&[0x89, 0x01, 0x23], 0x2133), // $123,X (16 ビットのオフセット)
+            (&[0x85], 0x2015),             // B,X
+            (&[0x86], 0x200f),             // A,X
+            (&[0x8b], 0x1f15),             // D,X
+            (&[0x23], 0x2023),             // 3,Y
+            (&[0xc4], 0x2030),             // ,U
+            (&[0x8c, 0x10], pcr + 0x10),   // $10,PCR
+            (&[0x94], 0x2340),             // [,X]
+            (&[0xd8, 0x02], 0x2350),       // [2,U]
+            (&[0x9f, 0x21, 0x00], 0x2360), // [$2100]
+        ];
+        for (post, expected) in cases {
+            // LEAX <post> (短い命令は NOP で埋めて長さを揃える)
+            let mut program = setup.to_vec();
+            program.push(0x30);
+            program.extend_from_slice(post);
+            program.resize(setup.len() + 4, 0x12);
+            let mut memory = memory(&program);
+            memory.0[0x2010..0x2012].copy_from_slice(&[0x23, 0x40]);
+            memory.0[0x2032..0x2034].copy_from_slice(&[0x23, 0x50]);
+            memory.0[0x2100..0x2102].copy_from_slice(&[0x23, 0x60]);
+            let mut cpu = Cpu6809::default();
+            run(&mut cpu, &mut memory, program.len());
+            assert_eq!(cpu.x, expected, "post byte {:02x?}", post);
+        }
+    }
+
+    #[test]
+    fn indexed_auto_increment_and_decrement() {
+        // LDX #$2010; LDA ,X+; LDB ,X++; LDA ,-X; LDB ,--X
+        let mut memory = memory(&[0x8e, 0x20, 0x10, 0xa6, 0x80, 0xe6, 0x81, 0xa6, 0x82, 0xe6, 0x83]);
+        memory.0[0x200e..0x2014].copy_from_slice(&[0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13]);
+        let mut cpu = Cpu6809::default();
+        cpu.reset(&mut memory);
+        let mut steps = Vec::new();
+        for _ in 0..5 {
+            cpu.step(&mut memory);
+            steps.push((cpu.a, cpu.b, cpu.x));
+        }
+        assert_eq!(&steps[1..], &[(0x10, 0x00, 0x2011), (0x10, 0x11, 0x2013), (0x12, 0x11, 0x2012), (0x12, 0x10, 0x2010)]);
+    }
+
+    #[test]
+    fn branch_conditions() {
+        // (条件コード, 分岐する命令)
+        let cases = [
+            (0, &[0x20, 0x22, 0x24, 0x26, 0x28, 0x2a, 0x2c, 0x2e][..]),
+            (CC_Z, &[0x20, 0x23, 0x24, 0x27, 0x28, 0x2a, 0x2c, 0x2f]),
+            (CC_C, &[0x20, 0x23, 0x25, 0x26, 0x28, 0x2a, 0x2c, 0x2e]),
+            (CC_N, &[0x20, 0x22, 0x24, 0x26, 0x28, 0x2b, 0x2d, 0x2f]),
+            (CC_V, &[0x20, 0x22, 0x24, 0x26, 0x29, 0x2a, 0x2d, 0x2f]),
+            (CC_N | CC_V, &[0x20, 0x22, 0x24, 0x26, 0x29, 0x2b, 0x2c, 0x2e]),
+        ];
+        for (cc, taken) in cases {
+            for op in 0x20..=0x2f {
+                // ANDCC #0; ORCC #cc; Bcc +2; LDA #1 (分岐しなければ A = 1)
+                let program = [0x1c, 0x00, 0x1a, cc, op, 0x02, 0x86, 0x01];
+                let short = execute(&program);
+                // 長い分岐 (LBcc +2、LBRA は 0x16)
+                let long = if op == 0x20 { vec![0x16, 0x00, 0x02] } else { vec![0x10, op, 0x00, 0x02] };
+                let program_long = [&[0x1c, 0x00, 0x1a, cc][..], &long, &[0x86, 0x01]].concat();
+                let long = execute(&program_long);
+                let expected = if taken.contains(&op) { 0 } else { 1 };
+                assert_eq!(short.a, expected, "cc {:02x} op {:02x}", cc, op);
+                assert_eq!(long.a, expected, "cc {:02x} long op {:02x}", cc, op);
+            }
+        }
+    }
+}
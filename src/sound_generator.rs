@@ -1,5 +1,5 @@
 mod wave_data;
-use wave_data::*;
+pub use wave_data::*;
 
 const INTERNAL_RATE: i32 = 192_000;
 const REFERENCE_TONE: i32 = 1500; // 1500Hz
@@ -44,13 +44,29 @@ pub enum PanPot {
     Right = 1,
 }
 
+impl PanPot {
+    // SoundManager の定位の値 (負: 左、0: 中央、正: 右)
+    pub fn from_value(value: i32) -> Self {
+        match value {
+            v if v < 0 => PanPot::Left,
+            0 => PanPot::Center,
+            _ => PanPot::Right,
+        }
+    }
+}
+
 pub struct SoundGenerator {
     sampling_freq: i32,
     samples_per_frame: usize,
     generators: [GeneratorUnit; NUM_OF_GENARTORS],
     pub mute: [bool; NUM_OF_GENARTORS],
     pub panpot: [PanPot; NUM_OF_GENARTORS],
+    // true のチャンネルはサウンドの定位より panpot (ミキサーでの指定) を優先する
+    pub pan_override: [bool; NUM_OF_GENARTORS],
+    sound_panpot: [Option<PanPot>; NUM_OF_GENARTORS],
     pub master_gain: i32,
+    // 全体の一時停止 (無音を出し、各チャンネルの位相などはそのまま残す)
+    pub paused: bool,
     mixed_buffer: Vec<i16>,
     work: Vec<(i32, i32)>,
 }
@@ -74,7 +90,10 @@ impl SoundGenerator {
             ],
             mute: [false; NUM_OF_GENARTORS],
             panpot: [PanPot::Center; NUM_OF_GENARTORS],
+            pan_override: [false; NUM_OF_GENARTORS],
+            sound_panpot: [None; NUM_OF_GENARTORS],
             master_gain: 7,
+            paused: false,
             mixed_buffer: vec![0; samples_per_frame * 2], // Stereo
             work: vec![(0, 0); INTERNAL_RATE as usize / 60],
         }
@@ -90,9 +109,16 @@ impl SoundGenerator {
         for p in self.panpot.iter_mut() {
             *p = PanPot::Center;
         }
+        for o in self.pan_override.iter_mut() {
+            *o = false;
+        }
+        for p in self.sound_panpot.iter_mut() {
+            *p = None;
+        }
         for d in self.mixed_buffer.iter_mut() {
             *d = 0;
         }
+        self.paused = false;
     }
 
     pub fn sampling_freq(&self) -> i32 {
@@ -107,13 +133,35 @@ impl SoundGenerator {
         &self.mixed_buffer
     }
 
+    // SoundManager::get_ch_pans の定位 (None のチャンネルは panpot のまま)
+    pub fn set_sound_pans(&mut self, pans: &[Option<i32>; NUM_OF_GENARTORS]) {
+        for (panpot, pan) in self.sound_panpot.iter_mut().zip(pans.iter()) {
+            *panpot = pan.map(PanPot::from_value);
+        }
+    }
+
+    // 実際に使う定位
+    pub fn effective_panpot(&self, ch: usize) -> PanPot {
+        match self.sound_panpot[ch] {
+            Some(panpot) if !self.pan_override[ch] => panpot,
+            _ => self.panpot[ch],
+        }
+    }
+
     pub fn generate(&mut self, sound_data: &[(usize, i32, i32); NUM_OF_GENARTORS]) {
+        if self.paused {
+            for d in self.mixed_buffer.iter_mut() {
+                *d = 0;
+            }
+            return;
+        }
         for work in self.work.iter_mut() {
             *work = (0, 0);
         }
+        let panpots: [PanPot; NUM_OF_GENARTORS] = std::array::from_fn(|ch| self.effective_panpot(ch));
         for (ch, unit) in self.generators.iter_mut().enumerate() {
             let (w, f, g) = sound_data[ch];
-            let panpot = self.panpot[ch];
+            let panpot = panpots[ch];
             if g == 0 && unit.current_gain == 0x0_00 {
                 unit.phase_pos = 0;
                 unit.current_wave_form = None;
@@ -196,3 +244,35 @@ impl SoundGenerator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sound_pans_apply_unless_overridden() {
+        let mut generator = SoundGenerator::new(48000);
+        generator.panpot[1] = PanPot::Left;
+        generator.panpot[2] = PanPot::Left;
+        generator.pan_override[2] = true;
+        generator.set_sound_pans(&[Some(-3), Some(1), Some(1), None, Some(0), None, None, None]);
+        assert_eq!(generator.effective_panpot(0), PanPot::Left);
+        assert_eq!(generator.effective_panpot(1), PanPot::Right);
+        assert_eq!(generator.effective_panpot(2), PanPot::Left);
+        assert_eq!(generator.effective_panpot(3), PanPot::Center);
+        assert_eq!(generator.effective_panpot(4), PanPot::Center);
+    }
+
+    #[test]
+    fn paused_generator_outputs_silence() {
+        let mut generator = SoundGenerator::new(48000);
+        let sound_data = [(2, 0x4000, 15); NUM_OF_GENARTORS];
+        generator.generate(&sound_data);
+        assert!(generator.mixed_buffer().iter().any(|&d| d != 0));
+        generator.paused = true;
+        generator.generate(&sound_data);
+        assert!(generator.mixed_buffer().iter().all(|&d| d == 0));
+        generator.clear();
+        assert!(!generator.paused);
+    }
+}
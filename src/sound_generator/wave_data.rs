@@ -181,3 +181,16 @@ pub const WAVE_FORMS: [&[i16]; NUM_OF_WAVE_FORMS] = [
     &WAVE_6,
     &WAVE_7,
 ];
+
+// WAVE_FORMS から元の 4bit サンプル値を復元する
+pub fn wave_samples(wave_form_no: usize) -> [u8; WAVE_DATA_LENGTH] {
+    let mut samples = [0; WAVE_DATA_LENGTH];
+    for (s, w) in samples.iter_mut().zip(WAVE_FORMS[wave_form_no].iter()) {
+        *s = if *w == i16::MAX {
+            0x0f
+        } else {
+            (((*w as i32 + SETUP_U16) >> 12) - 1) as u8
+        };
+    }
+    samples
+}
@@ -1,17 +1,41 @@
 mod scale_set;
-use scale_set::*;
+pub use scale_set::*;
 mod envelope_tbl;
-use envelope_tbl::*;
+pub use envelope_tbl::*;
+mod vibrato_tbl;
+pub use vibrato_tbl::*;
 mod sound_score;
-use sound_score::*;
+pub use sound_score::*;
 mod sound_index;
-use sound_index::*;
+pub use sound_index::*;
+mod smf_import;
+pub use smf_import::*;
+mod bytecode;
+pub use bytecode::*;
+mod rom_image;
+pub use rom_image::*;
+mod rom_patch;
+pub use rom_patch::*;
+mod sound_table;
+pub use sound_table::*;
+mod voice_allocator;
+pub use voice_allocator::*;
+mod sound_event;
+pub use sound_event::*;
+mod scheduler;
+pub use scheduler::*;
+mod positional;
+pub use positional::*;
 
-#[derive(Default, Debug)]
+pub type PartScore<'a> = (&'a [u8], &'a ScaleSet);
+
+#[derive(Default, Debug, Clone)]
 struct ChRegisters {
     wave_form: usize,
     freq: i32,
     gain: i32,
+    // 定位 (-1: 左、0: 中央、1: 右、None は指定なし)
+    pan: Option<i32>,
 }
 
 impl ChRegisters {
@@ -19,6 +43,7 @@ impl ChRegisters {
         self.wave_form = 0;
         self.freq = 0;
         self.gain = 0;
+        self.pan = None;
     }
 
     fn get_registers(&self) -> (usize, i32, i32) {
@@ -28,7 +53,7 @@ impl ChRegisters {
 
 type SoundRegisters = [ChRegisters; 8];
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 struct ChPrepare<'a> {
     pre_data: ChRegisters,
     read_adr: &'a[u8],
@@ -40,6 +65,15 @@ struct ChPrepare<'a> {
     // work_d: usize,
     // work_e: usize,
     // work_f: usize,
+    flow: FlowState<'a>,
+    // 音程の効果 (f9..fb)
+    vibrato: usize,
+    portamento: usize,
+    sweep: i32,
+    note_frames: usize,
+    slide_from: i32,
+    // 演奏を始めてから読み進めたバイト数 (ループやジャンプで戻っても減らない)
+    played_bytes: usize,
 }
 
 impl<'a> ChPrepare<'a> {
@@ -53,32 +87,640 @@ impl<'a> ChPrepare<'a> {
         // self.work_e = 0;
         // self.work_f = 0;
     }
+
+    // 最初から演奏する (定位と音程の効果も戻す)
+    fn start(&mut self, part: &'a [u8]) {
+        self.restart(part, 0);
+        self.pre_data.pan = None;
+        self.vibrato = 0;
+        self.portamento = 0;
+        self.sweep = 0;
+        self.note_frames = 0;
+        self.slide_from = 0;
+        self.played_bytes = 0;
+    }
+
+    // ループ位置から演奏し直す (定位と音程の効果はループの前のまま続ける)
+    fn restart(&mut self, part: &'a [u8], offset: usize) {
+        self.read_adr = &part[offset..];
+        self.remain_frames = 0;
+        self.flow = FlowState::new(part);
+    }
+
+    // ポルタメントは直前に出力していた周波数から移る
+    fn start_note(&mut self) {
+        self.note_frames = 0;
+        self.slide_from = if self.portamento > 0 { self.pre_data.freq } else { 0 };
+    }
+
+    // 音符の周波数にポルタメント、スイープ、ビブラートを掛けた今回のフレームの周波数
+    fn pitch(&mut self, base_freq: i32) -> i32 {
+        let frame = self.note_frames as i64;
+        self.note_frames += 1;
+        let base = base_freq as i64;
+        if base == 0 {
+            return 0;
+        }
+        let mut freq = base;
+        let slide_from = self.slide_from as i64;
+        if slide_from > 0 && frame < self.portamento as i64 {
+            freq = slide_from + (base - slide_from) * frame / self.portamento as i64;
+        }
+        freq += base * self.sweep as i64 * frame / 256;
+        let (delay, speed, depth) = VIBRATO_TBL.get(self.vibrato).copied().unwrap_or_default();
+        if depth != 0 && frame >= delay as i64 {
+            let phase = (frame as usize - delay) * speed;
+            freq += base * depth as i64 * vibrato_offset(phase) as i64 / (16 * 1024);
+        }
+        freq.clamp(0, 0xf_ffff) as i32
+    }
+
+    fn execute_flow(&mut self) -> bool {
+        let len = instruction_len(self.read_adr[0]).unwrap_or(1);
+        let executed = self.flow.execute(&mut self.read_adr);
+        if executed {
+            self.played_bytes += len;
+        }
+        executed
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.read_adr = &self.read_adr[len..];
+        self.played_bytes += len;
+    }
+}
+
+// サウンドごとの演奏のしかた
+//   tempo: 演奏の速さ (1.0 で元の速さ、MIN_TEMPO..=MAX_TEMPO)
+//   transpose: 半音単位の移調、detune: セント単位のずれ
+//   volume: 出力する音量に掛ける割合 (0.0..=1.0)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayParams {
+    pub tempo: f32,
+    pub transpose: i32,
+    pub detune: i32,
+    pub volume: f32,
+}
+
+impl Default for PlayParams {
+    fn default() -> Self {
+        Self { tempo: 1.0, transpose: 0, detune: 0, volume: 1.0 }
+    }
+}
+
+pub const MIN_TEMPO: f32 = 1.0 / 16.0;
+pub const MAX_TEMPO: f32 = 4.0;
+// テンポの刻みの累算の単位 (1.0 に当たる値)
+const TEMPO_ONE: usize = 0x100;
+
+impl PlayParams {
+    // キーとオクターブから ScaleSet を引いた周波数に、移調とずれを掛ける
+    fn note_freq(&self, scale: &ScaleSet, key: u8, oct: u8) -> i32 {
+        if key >= 12 || (self.transpose == 0 && self.detune == 0) {
+            return scale[key as usize] >> oct;
+        }
+        // 大きいキーほど高く、大きいオクターブほど低い
+        let note = key as i32 - 12 * oct as i32 + self.transpose;
+        let (key, oct) = (note.rem_euclid(12), -note.div_euclid(12));
+        let freq = scale[key as usize] as i64;
+        let freq = if oct >= 0 { freq >> oct.min(31) } else { freq << (-oct).min(8) };
+        let freq = if self.detune == 0 {
+            freq
+        } else {
+            (freq as f64 * 2f64.powf(self.detune as f64 / 1200.0)).round() as i64
+        };
+        freq.clamp(0, 0xf_ffff) as i32
+    }
+}
+
+// 音量の割合の単位 (1.0 に当たる値)
+const VOLUME_ONE: i32 = 0x100;
+
+// frames フレームかけて音量の割合を from から to へ変える
+#[derive(Debug, Clone, Copy)]
+struct Fade {
+    from: i32,
+    to: i32,
+    frames: usize,
+    elapsed: usize,
+}
+
+impl Fade {
+    fn level(&self) -> i32 {
+        if self.elapsed >= self.frames {
+            self.to
+        } else {
+            self.from + (self.to - self.from) * self.elapsed as i32 / self.frames as i32
+        }
+    }
+}
+
+// サウンドごとの再生状態 (play_request に載らないもの)
+#[derive(Debug, Clone, Copy, Default)]
+struct SoundState {
+    // Retriggerable の残り繰り返し回数 (負の値はループ)
+    repeats: i32,
+    params: PlayParams,
+    // テンポの刻みの累算 (TEMPO_ONE ごとに１フレーム分進める)
+    tick: usize,
+    fade: Option<Fade>,
+    // 演奏を始めてから進めたフレーム数
+    elapsed: usize,
+    // 演奏を終えるまで待たせている Retriggerable の要求 (RetriggerMode::Queue)
+    queued: bool,
+    // ループ位置に戻った (次に進めるフレームで Started を出す)
+    looped: bool,
+    // 音源の位置 (止めるか演奏を終えたら消える)
+    position: Option<(i32, i32)>,
+}
+
+impl SoundState {
+    // 音量とフェードを合わせた割合 (VOLUME_ONE が元の音量)
+    fn level(&self) -> i32 {
+        let volume = (self.params.volume.clamp(0.0, 1.0) * VOLUME_ONE as f32).round() as i32;
+        volume * self.fade_level() / VOLUME_ONE
+    }
+
+    fn fade_level(&self) -> i32 {
+        self.fade.map_or(VOLUME_ONE, |fade| fade.level())
+    }
+
+    fn start_fade(&mut self, from: i32, to: i32, frames: usize) {
+        self.fade = Some(Fade { from, to, frames, elapsed: 0 });
+    }
+
+    // フェードを１フレーム進め、フェードアウトを終えたら true を返す
+    fn advance_fade(&mut self) -> bool {
+        let fade = match self.fade.as_mut() {
+            Some(fade) => fade,
+            None => return false,
+        };
+        fade.elapsed += 1;
+        if fade.elapsed < fade.frames {
+            return false;
+        }
+        let faded_out = fade.to == 0;
+        if fade.to == VOLUME_ONE {
+            self.fade = None;
+        }
+        faded_out
+    }
+
+    // このフレームで進めるフレーム数
+    // 演奏を始めるフレームはテンポによらず必ず進める
+    fn ticks(&mut self, started: bool) -> usize {
+        let tempo = ((self.params.tempo.clamp(MIN_TEMPO, MAX_TEMPO) * TEMPO_ONE as f32).round() as usize).max(1);
+        if !started {
+            self.tick = TEMPO_ONE.saturating_sub(tempo);
+        }
+        self.tick += tempo;
+        let ticks = self.tick / TEMPO_ONE;
+        self.tick %= TEMPO_ONE;
+        ticks
+    }
 }
 
-type PlayRequest = [i32; NUM_SOUND_IDX];
-type PlayProgress = [bool; NUM_SOUND_IDX];
-#[derive(Default, Debug)]
+// OneShot は要求に回数を書き、Retriggerable は先頭から演奏し直して残りの回数を覚えておく
+// Looping は回数によらずループの要求にする
+fn request_times(sound_type: SoundType, request: &mut i32, state: &mut SoundState, times: usize) {
+    if times == 0 {
+        return;
+    }
+    match sound_type {
+        SoundType::OneShot | SoundType::Exclusive | SoundType::Queued => *request = times.min(i32::MAX as usize) as i32,
+        SoundType::Looping => *request = -1,
+        SoundType::Retriggerable => {
+            *request = 1;
+            state.repeats = (times - 1).min(i32::MAX as usize) as i32;
+        },
+    }
+}
+
+// 遅延付きで待っている続きのサウンド
+#[derive(Debug, Clone, Copy)]
+struct PendingFollowUp {
+    from: usize,
+    next: usize,
+    times: usize,
+    delay: usize,
+}
+
+// 演奏中の Retriggerable への要求を、制限に合わなければ捨てる (Queue は演奏を終えるまで待たせる)
+fn gate_retrigger(info: &SoundInfo, request: &mut i32, state: &mut SoundState, group: &[ChPrepare]) {
+    let policy = match info.retrigger {
+        Some(policy) => policy,
+        None => return,
+    };
+    let allowed = state.elapsed >= policy.min_interval && match policy.mode {
+        RetriggerMode::Restart => true,
+        RetriggerMode::Ignore | RetriggerMode::Queue => false,
+        RetriggerMode::After { part_no, offset } => group.get(part_no).is_some_and(|part| part.played_bytes >= offset),
+    };
+    if !allowed {
+        if policy.mode == RetriggerMode::Queue {
+            state.queued = true;
+        }
+        *request = 0;
+    }
+}
+
+// ループ演奏中 (OneShot などは負の要求、Retriggerable は負の繰り返し回数、Looping は常に) ならループ位置を返す
+fn loop_points<'b>(info: &'b SoundInfo, request: i32, state: &SoundState) -> Option<&'b [usize]> {
+    let looping = match info.sound_type {
+        SoundType::OneShot | SoundType::Exclusive | SoundType::Queued => request < 0,
+        SoundType::Looping => request != 0,
+        SoundType::Retriggerable => state.repeats < 0,
+    };
+    if looping { Some(&info.loop_points[..]) } else { None }
+}
+
+// 音を出さずに演奏を進める上限 (ジャンプで終わらない譜面のため)
+pub const MAX_DRY_RUN_FRAMES: usize = 60 * 60 * 10;
+
+// サウンドを１回演奏したときの長さ
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoundDuration {
+    // 演奏を終えるまでのフレーム数 (MAX_DRY_RUN_FRAMES までに終わらなければ None)
+    pub frames: Option<usize>,
+    // パートごとに単独で演奏したときのフレーム数 (最初に終わったパートでサウンド全体が終わる)
+    pub part_frames: Vec<Option<usize>>,
+    // ループ演奏での (ループ位置までのフレーム数, ループ１回のフレーム数)
+    pub loop_frames: Option<(usize, usize)>,
+}
+
+impl SoundDuration {
+    fn new(score: &[PartScore], loop_points: &[usize]) -> Self {
+        Self {
+            frames: dry_run(score),
+            part_frames: (0..score.len()).map(|part_no| dry_run(&score[part_no..part_no + 1])).collect(),
+            loop_frames: dry_run_loop(score, loop_points),
+        }
+    }
+
+    // パートの長さが揃っているか (揃っていなければ長いパートは途中で切れる)
+    pub fn parts_match(&self) -> bool {
+        self.part_frames.windows(2).all(|pair| pair[0] == pair[1])
+    }
+}
+
+// 譜面を音を出さずに１回演奏し、終わるまでのフレーム数を数える (末尾の無音は省かない)
+fn dry_run(score: &[PartScore]) -> Option<usize> {
+    let mut request = [1];
+    let mut progress = [false];
+    let mut group = vec![ChPrepare::default(); score.len()];
+    let mut registers: SoundRegisters = Default::default();
+    let mut frames = 0;
+    while request[0] != 0 {
+        if frames >= MAX_DRY_RUN_FRAMES {
+            return None;
+        }
+        let mut events = Vec::new();
+        let mut ctx = PrepareContext { start_ch: 0, suppress_last_silence: false, loop_points: None, params: PlayParams::default(), events: &mut events, looped: false };
+        prepare(0, &mut request, &mut progress, score, &mut group, &mut registers, &mut ctx);
+        frames += 1;
+    }
+    Some(frames)
+}
+
+// ループ演奏を音を出さずに進め、１回目と２回目にループ位置へ戻ったフレームからループの長さを求める
+// (ループ位置に戻らずに終わるか、MAX_DRY_RUN_FRAMES までに２回戻らなければ None)
+fn dry_run_loop(score: &[PartScore], loop_points: &[usize]) -> Option<(usize, usize)> {
+    let mut request = [-1];
+    let mut progress = [false];
+    let mut group = vec![ChPrepare::default(); score.len()];
+    let mut registers: SoundRegisters = Default::default();
+    let mut first_loop = None;
+    for frames in 1..=MAX_DRY_RUN_FRAMES {
+        let mut events = Vec::new();
+        let mut ctx = PrepareContext { start_ch: 0, suppress_last_silence: false, loop_points: Some(loop_points), params: PlayParams::default(), events: &mut events, looped: false };
+        prepare(0, &mut request, &mut progress, score, &mut group, &mut registers, &mut ctx);
+        if !progress[0] {
+            return None;
+        }
+        if ctx.looped {
+            match first_loop {
+                None => first_loop = Some(frames),
+                Some(first) => return Some((first.saturating_sub(frames - first), frames - first)),
+            }
+        }
+    }
+    None
+}
+
+// 終了時の要求の更新: 正の値は残り回数として減らし、負の値(ループ)はそのまま残す
+// prepare の元のドライバとの意図した違い (Original の配置でも同じ):
+// - 元は終了時に要求を 0 に戻す (CreditUpPre だけ回数を減らす)。回数指定とループのため、ここで減らすか残す
+// - 元は長さ 0 の音符で残りフレーム数がアンダーフローする。１フレームの音符とする
+// - 元は実行できない命令で読み進めずに止まる (無限ループ)。パートの終わりとする
+fn finish_request(idx: usize, request: &mut [i32]) {
+    if request[idx] > 0 {
+        request[idx] -= 1;
+    }
+}
+
+// prepare の演奏のしかたと出力先 (パートの状態以外)
+struct PrepareContext<'b> {
+    start_ch: usize,
+    suppress_last_silence: bool,
+    loop_points: Option<&'b [usize]>,
+    params: PlayParams,
+    events: &'b mut Vec<SoundEvent>,
+    // このフレームの終わりに全パートをループ位置に戻した
+    looped: bool,
+}
+
+fn prepare<'a>(idx: usize, request: &mut [i32], progress: &mut [bool], score: &[PartScore<'a>], group: &mut[ChPrepare<'a>], registers: &mut[ChRegisters], ctx: &mut PrepareContext) {
+    let (start_ch, suppress_last_silence, loop_points, params) = (ctx.start_ch, ctx.suppress_last_silence, ctx.loop_points, ctx.params);
+    let events = &mut *ctx.events;
+    let mut finishd = false;
+    // 終了してクリアしたパート (この番号以降) は残りフレーム数を減らさない
+    let mut cleared_from = score.len();
+    for (part_no, ch_score) in score.iter().enumerate() {
+        if finishd {
+            group[part_no].clear();
+            continue;
+        }
+        if !progress[idx] {
+            group[part_no].start(ch_score.0); // !! remain_frames のクリアは本来不要だが、アンダーフロー対策のため !!
+        }
+        let mut control_steps = 0;
+        loop {
+            let r0 = group[part_no].read_adr[0];
+            if r0 >= 0xf0 {
+                match r0 {
+                    0xf0 => {
+                        let r1 = group[part_no].read_adr[1];
+                        group[part_no].pre_data.wave_form = (r1 >> 4) as usize;
+                        group[part_no].advance(2);
+                        events.push(SoundEvent::WaveForm { part: PartId { sound_no: idx, part_no }, wave_form: group[part_no].pre_data.wave_form });
+                        continue;
+                    },
+                    0xf1 => {
+                        let r1 = group[part_no].read_adr[1];
+                        group[part_no].envelope = r1 as usize;
+                        group[part_no].work_c = 0;
+                        group[part_no].advance(2);
+                        events.push(SoundEvent::Envelope { part: PartId { sound_no: idx, part_no }, envelope: group[part_no].envelope });
+                        continue;
+                    },
+                    0xf2 => {
+                        let r1 = group[part_no].read_adr[1];
+                        group[part_no].unit_frames = r1 as usize;
+                        group[part_no].advance(2);
+                        events.push(SoundEvent::Control { part: PartId { sound_no: idx, part_no }, code: r0, value: group[part_no].unit_frames as i32 });
+                        continue;
+                    },
+                    0xf9 => {
+                        group[part_no].vibrato = group[part_no].read_adr[1] as usize;
+                        group[part_no].advance(2);
+                        events.push(SoundEvent::Control { part: PartId { sound_no: idx, part_no }, code: r0, value: group[part_no].vibrato as i32 });
+                        continue;
+                    },
+                    0xfa => {
+                        group[part_no].portamento = group[part_no].read_adr[1] as usize;
+                        group[part_no].advance(2);
+                        events.push(SoundEvent::Control { part: PartId { sound_no: idx, part_no }, code: r0, value: group[part_no].portamento as i32 });
+                        continue;
+                    },
+                    0xfb => {
+                        group[part_no].sweep = group[part_no].read_adr[1] as i8 as i32;
+                        group[part_no].advance(2);
+                        events.push(SoundEvent::Control { part: PartId { sound_no: idx, part_no }, code: r0, value: group[part_no].sweep });
+                        continue;
+                    },
+                    0xfc => {
+                        group[part_no].pre_data.pan = Some((group[part_no].read_adr[1] as i8 as i32).signum());
+                        group[part_no].advance(2);
+                        events.push(SoundEvent::Control { part: PartId { sound_no: idx, part_no }, code: r0, value: group[part_no].pre_data.pan.unwrap_or(0) });
+                        continue;
+                    },
+                    // 0xf3 と、実行できない制御命令は終了
+                    _ => {
+                        if r0 != 0xf3 && control_steps < MAX_CONTROL_STEPS && group[part_no].execute_flow() {
+                            control_steps += 1;
+                            continue;
+                        }
+                        finish_request(idx, request);
+                        progress[idx] = false;
+                        finishd = true;
+                        cleared_from = part_no;
+                        group[part_no].clear();
+                        break;
+                    },
+                }
+            } else {
+                let key = r0 >> 4;
+                let oct = r0 & 0x0f;
+                let base_freq = params.note_freq(ch_score.1, key, oct);
+                if group[part_no].remain_frames == 0 {
+                    group[part_no].start_note();
+                    // 長さ 0 の音符 (長さか unit が 0) は１フレームとする (残りフレーム数を減らせるように)
+                    let len = (group[part_no].read_adr[1] as usize * group[part_no].unit_frames).max(1);
+                    events.push(SoundEvent::NoteOn { part: PartId { sound_no: idx, part_no }, ch: None, key, oct, frames: len });
+                    group[part_no].remain_frames = len;
+                    if group[part_no].work_c == 0 {
+                        group[part_no].envelope_read_pos = 0;
+                    }
+                }
+                group[part_no].pre_data.freq = group[part_no].pitch(base_freq);
+                let envelope = group[part_no].envelope;
+                loop {
+                    let env_pos = group[part_no].envelope_read_pos;
+                    let g = ENVELOPE_TBL[envelope][env_pos];
+                    let gain = match g {
+                        0x10 => {
+                            let gain = ENVELOPE_TBL[envelope][env_pos - 1];
+                            gain
+                        },
+                        0x11 => {
+                            let gain = group[part_no].pre_data.gain;
+                            if gain > 0 {
+                                if (gain - 1) <= ENVELOPE_TBL[envelope][env_pos + 1] {
+                                    group[part_no].envelope_read_pos += 1;
+                                }
+                                gain - 1
+                            } else {
+                                group[part_no].envelope_read_pos += 1;
+                                0
+                            }
+                        },
+                        0x12 => {
+                            let remain = group[part_no].remain_frames;
+                            let gain = group[part_no].pre_data.gain;
+                            if remain > gain as usize {
+                                gain
+                            } else {
+                                (remain - 1) as i32
+                            }
+                        },
+                        0x13 => {
+                            group[part_no].work_c = 0;
+                            group[part_no].envelope_read_pos = 0;
+                            continue;
+                        },
+                        0x14 => {
+                            group[part_no].work_c = 1;
+                            group[part_no].envelope_read_pos += 1;
+                            continue;
+                        },
+                        _ => {
+                            group[part_no].envelope_read_pos += 1;
+                            g
+                        },
+                    };
+                    group[part_no].pre_data.gain = gain;
+                    break;
+                }
+            }
+            break;
+        }
+    }
+    if !finishd {
+        progress[idx] = true;
+    }
+    for part_no in 0..score.len() {
+        registers[start_ch + part_no] = ChRegisters { ..group[part_no].pre_data };
+        if part_no >= cleared_from {
+            continue;
+        }
+        group[part_no].remain_frames -= 1;
+        if group[part_no].remain_frames == 0 {
+            group[part_no].advance(2);
+            // 独自実装：末尾の無音１フレームを出力しない
+            if suppress_last_silence && !finishd && loop_points.is_none() {
+                let r0 = group[part_no].read_adr[0];
+                if r0 == 0xf3 || (r0 >= 0xf4 && group[part_no].flow.reaches_end(group[part_no].read_adr)) {
+                    finish_request(idx, request);
+                    progress[idx] = false;
+                    finishd = true;
+                    group[part_no].clear();
+                }
+            }
+        }
+    }
+    // ループ演奏: いずれかのパートが終了に達したら、次のフレームで全パートをループ位置から演奏する
+    // (終了のフレームを挟まないので継ぎ目に無音が入らない)
+    if let Some(loop_points) = loop_points {
+        if !finishd && (0..score.len()).any(|part_no| group[part_no].remain_frames == 0 && group[part_no].flow.reaches_end(group[part_no].read_adr)) {
+            for (part_no, ch_score) in score.iter().enumerate() {
+                let point = loop_points.get(part_no).copied().unwrap_or(0);
+                group[part_no].restart(ch_score.0, point);
+            }
+            ctx.looped = true;
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct SoundManager<'a> {
-    pub play_request: PlayRequest,
-    play_progress: PlayProgress,
-    group_0100: [ChPrepare<'a>; 7],
-    group_0169: [ChPrepare<'a>; 2],
-    group_0187: [ChPrepare<'a>; 4],
-    group_01c3: [ChPrepare<'a>; 4],
-    group_01ff: [ChPrepare<'a>; 4],
-    group_023b: [ChPrepare<'a>; 4],
-    group_0277: [ChPrepare<'a>; 2],
-    group_0295: [ChPrepare<'a>; 2],
-    group_02b3: [ChPrepare<'a>; 1],
-    group_02c2: [ChPrepare<'a>; 8],
-    group_033a: [ChPrepare<'a>; 4],
-    group_0376: [ChPrepare<'a>; 3],
+    table: SoundTable<'a>,
+    pub play_request: Vec<i32>,
+    play_progress: Vec<bool>,
+    sound_states: Vec<SoundState>,
+    follow_ups: Vec<PendingFollowUp>,
+    scheduler: Scheduler,
+    // 一時停止中のサウンドのパートの演奏状態 (Original では作業領域を他のサウンドが使うので写しを取っておく)
+    paused: Vec<Option<Vec<ChPrepare<'a>>>>,
+    voice_layout: VoiceLayout,
+    work_areas: Vec<Vec<ChPrepare<'a>>>,
+    sound_parts: Vec<Vec<ChPrepare<'a>>>,
+    durations: Vec<SoundDuration>,
+    voice_allocator: VoiceAllocator,
     registers: SoundRegisters,
+    channel_reports: [ChannelReport; 8],
+    events: Vec<SoundEvent>,
+    // ダッキングの深さ (VOLUME_ONE で Ducking の steps 段下げる)
+    duck_level: i32,
+    // 位置を指定したサウンドの定位と音量を決める聞き手 (None なら位置は使わない)
+    listener: Option<Listener>,
     pub suppress_last_silence: bool,
 }
 
+impl<'a> Default for SoundManager<'a> {
+    fn default() -> Self {
+        Self::new(SoundTable::original()).unwrap()
+    }
+}
+
 #[allow(dead_code)]
 impl<'a> SoundManager<'a> {
+    pub fn new(table: SoundTable<'a>) -> Result<Self, String> {
+        table.validate()?;
+        let num_sounds = table.sounds.len();
+        let work_areas = table.work_areas.iter()
+            .map(|area| (0..area.len).map(|_| ChPrepare::default()).collect())
+            .collect();
+        let sound_parts = Self::new_sound_parts(&table);
+        let durations = Self::new_durations(&table);
+        let voice_allocator = VoiceAllocator::new(&table);
+        Ok(Self {
+            table,
+            play_request: vec![0; num_sounds],
+            play_progress: vec![false; num_sounds],
+            sound_states: vec![SoundState::default(); num_sounds],
+            follow_ups: Vec::new(),
+            scheduler: Scheduler::default(),
+            paused: vec![None; num_sounds],
+            voice_layout: VoiceLayout::Original,
+            work_areas,
+            sound_parts,
+            durations,
+            voice_allocator,
+            registers: Default::default(),
+            channel_reports: Default::default(),
+            events: Vec::new(),
+            duck_level: 0,
+            listener: None,
+            suppress_last_silence: false,
+        })
+    }
+
+    fn new_durations(table: &SoundTable<'a>) -> Vec<SoundDuration> {
+        table.sounds.iter().map(|sound| SoundDuration::new(&sound.score, &sound.loop_points)).collect()
+    }
+
+    fn new_sound_parts(table: &SoundTable<'a>) -> Vec<Vec<ChPrepare<'a>>> {
+        table.sounds.iter()
+            .map(|sound| (0..sound.score.len()).map(|_| ChPrepare::default()).collect())
+            .collect()
+    }
+
+    pub fn voice_layout(&self) -> VoiceLayout {
+        self.voice_layout
+    }
+
+    // 演奏状態の置き場所が変わるので、切り替え時は全サウンドを止める
+    pub fn set_voice_layout(&mut self, voice_layout: VoiceLayout) {
+        self.voice_layout = voice_layout;
+        self.clear();
+    }
+
+    pub fn table(&self) -> &SoundTable<'a> {
+        &self.table
+    }
+
+    pub fn num_sounds(&self) -> usize {
+        self.table.sounds.len()
+    }
+
+    // テーブルの譜面だけを実行時に用意した譜面 (ROM から読んだものなど) に差し替える
+    pub fn set_scores(&mut self, scores: Vec<Vec<PartScore<'a>>>) -> Result<(), String> {
+        if scores.len() != self.table.sounds.len() {
+            return Err(format!("score table has {} sounds, {} expected", scores.len(), self.table.sounds.len()));
+        }
+        let mut table = self.table.clone();
+        for (sound, score) in table.sounds.iter_mut().zip(scores) {
+            sound.score = score;
+        }
+        table.validate()?;
+        self.sound_parts = Self::new_sound_parts(&table);
+        self.durations = Self::new_durations(&table);
+        self.voice_allocator = VoiceAllocator::new(&table);
+        self.table = table;
+        self.clear();
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
         for request in self.play_request.iter_mut() {
             *request = 0;
@@ -86,42 +728,23 @@ impl<'a> SoundManager<'a> {
         for progress in self.play_progress.iter_mut() {
             *progress = false;
         }
-        for ch_prepare in self.group_0100.iter_mut() {
-            ch_prepare.clear();
+        for state in self.sound_states.iter_mut() {
+            *state = SoundState::default();
         }
-        for ch_prepare in self.group_0169.iter_mut() {
-            ch_prepare.clear();
+        self.follow_ups.clear();
+        self.scheduler.clear();
+        for paused in self.paused.iter_mut() {
+            *paused = None;
         }
-        for ch_prepare in self.group_0187.iter_mut() {
+        for ch_prepare in self.work_areas.iter_mut().chain(self.sound_parts.iter_mut()).flatten() {
             ch_prepare.clear();
         }
-        for ch_prepare in self.group_01c3.iter_mut() {
-            ch_prepare.clear();
-        }
-        for ch_prepare in self.group_01ff.iter_mut() {
-            ch_prepare.clear();
-        }
-        for ch_prepare in self.group_023b.iter_mut() {
-            ch_prepare.clear();
-        }
-        for ch_prepare in self.group_0277.iter_mut() {
-            ch_prepare.clear();
-        }
-        for ch_prepare in self.group_0295.iter_mut() {
-            ch_prepare.clear();
-        }
-        for ch_prepare in self.group_02b3.iter_mut() {
-            ch_prepare.clear();
-        }
-        for ch_prepare in self.group_02c2.iter_mut() {
-            ch_prepare.clear();
-        }
-        for ch_prepare in self.group_033a.iter_mut() {
-            ch_prepare.clear();
-        }
-        for ch_prepare in self.group_0376.iter_mut() {
-            ch_prepare.clear();
+        self.voice_allocator.clear();
+        for report in self.channel_reports.iter_mut() {
+            report.clear();
         }
+        self.events.clear();
+        self.duck_level = 0;
         self.clear_ch_registers();
     }
 
@@ -138,6 +761,15 @@ impl<'a> SoundManager<'a> {
         ]
     }
 
+    // 各チャンネルの定位 (位置を指定したサウンドは位置から決めた定位、次に譜面の 0xfc、無ければサウンドテーブルの既定値。どれも無ければ None)
+    pub fn get_ch_pans(&self) -> [Option<i32>; 8] {
+        let mut pans = [None; 8];
+        for (pan, registers) in pans.iter_mut().zip(self.registers.iter()) {
+            *pan = registers.pan;
+        }
+        pans
+    }
+
     pub fn clear_ch_registers(&mut self) {
         self.registers[0].clear();
         self.registers[1].clear();
@@ -153,251 +785,1443 @@ impl<'a> SoundManager<'a> {
         self.play_progress[sound_index]
     }
 
-    pub fn run(&mut self) {
-        fn prepare<'a>(idx: usize, request: &mut PlayRequest, progress: &mut PlayProgress, score: &'a[&[(&[u8], &ScaleSet)]], group: &mut[ChPrepare<'a>], registers: &mut[ChRegisters], start_ch: usize, suppress_last_silence: bool) {
-            let mut finishd = false;
-            for (part_no, ch_score) in score[idx].iter().enumerate() {
-                if finishd {
-                    group[part_no].clear();
-                    continue;
+    // play_request の値の意味
+    //   OneShot: 正の値は残り演奏回数 (終了ごとに減る)、負の値はループ、0 は停止
+    //   Retriggerable: 0 以外を書くと先頭から演奏し直し、要求はすぐ 0 に戻る (演奏中はサウンドテーブルの retrigger の制限に従う)
+    //   Looping: 0 以外はループ (正の値も負の値にする)、Exclusive と Queued は OneShot と同じ
+    // 以下はこれを直接触らずに使うためのもの
+
+    // OneShot は演奏中でなければ１回演奏する (演奏中ならそのまま)、Retriggerable は先頭から演奏し直す
+    // Looping は演奏中でなければループ演奏する
+    pub fn play(&mut self, sound_index: usize) {
+        match self.table.sounds[sound_index].sound_type {
+            SoundType::OneShot | SoundType::Exclusive | SoundType::Queued => if !self.is_playing(sound_index) {
+                self.play_request[sound_index] = 1;
+            },
+            SoundType::Looping => if !self.is_playing(sound_index) {
+                self.play_request[sound_index] = -1;
+            },
+            SoundType::Retriggerable => self.play_times(sound_index, 1),
+        }
+    }
+
+    // OneShot は演奏中なら今回を含めた残り回数を times にする、Retriggerable は先頭から times 回演奏する
+    pub fn play_times(&mut self, sound_index: usize, times: usize) {
+        let sound_type = self.table.sounds[sound_index].sound_type;
+        request_times(sound_type, &mut self.play_request[sound_index], &mut self.sound_states[sound_index], times);
+    }
+
+    // 止めるまで繰り返す (OneShot は演奏中ならそのまま続けて繰り返す)
+    // 最後の音の次のフレームで、ループ位置から隙間なく演奏し直す
+    pub fn play_loop(&mut self, sound_index: usize) {
+        match self.table.sounds[sound_index].sound_type {
+            SoundType::OneShot | SoundType::Looping | SoundType::Exclusive | SoundType::Queued => self.play_request[sound_index] = -1,
+            SoundType::Retriggerable => {
+                self.play_request[sound_index] = 1;
+                self.sound_states[sound_index].repeats = -1;
+            },
+        }
+    }
+
+    // すぐに止める (続けて演奏するサウンドも要求しない)
+    pub fn stop(&mut self, sound_index: usize) {
+        if self.is_playing(sound_index) {
+            self.events.push(SoundEvent::Stopped { sound_no: sound_index });
+        }
+        self.play_request[sound_index] = 0;
+        self.play_progress[sound_index] = false;
+        self.sound_states[sound_index].repeats = 0;
+        self.sound_states[sound_index].queued = false;
+        self.sound_states[sound_index].looped = false;
+        self.sound_states[sound_index].position = None;
+        self.sound_states[sound_index].fade = None;
+        self.paused[sound_index] = None;
+        self.follow_ups.retain(|pending| pending.from != sound_index);
+    }
+
+    // 演奏位置・音符の残りフレーム数・エンベロープの位置をそのままにして止め、チャンネルを空ける
+    pub fn pause(&mut self, sound_index: usize) {
+        if self.paused[sound_index].is_some() || !self.is_playing(sound_index) {
+            return;
+        }
+        let info = &self.table.sounds[sound_index];
+        let group = match self.voice_layout {
+            VoiceLayout::Original => &self.work_areas[info.work_area],
+            VoiceLayout::Dynamic => &self.sound_parts[sound_index],
+        };
+        self.paused[sound_index] = Some(group[..info.score.len()].to_vec());
+    }
+
+    // 一時停止した位置から続ける
+    pub fn resume(&mut self, sound_index: usize) {
+        let parts = match self.paused[sound_index].take() {
+            Some(parts) => parts,
+            None => return,
+        };
+        let info = &self.table.sounds[sound_index];
+        let group = match self.voice_layout {
+            VoiceLayout::Original => &mut self.work_areas[info.work_area],
+            VoiceLayout::Dynamic => &mut self.sound_parts[sound_index],
+        };
+        for (ch_prepare, part) in group.iter_mut().zip(parts) {
+            *ch_prepare = part;
+        }
+    }
+
+    pub fn is_paused(&self, sound_index: usize) -> bool {
+        self.paused[sound_index].is_some()
+    }
+
+    // 先頭から frame フレーム進めた位置から演奏する (そこまでは音を出さずに進める)
+    // 演奏中なら先頭からやり直し、要求の回数やループ指定はそのまま使う。終わりを越えたら止まる
+    pub fn seek(&mut self, sound_index: usize, frame: usize) {
+        let info = &self.table.sounds[sound_index];
+        if self.play_request[sound_index] == 0 {
+            self.play_request[sound_index] = 1;
+        }
+        self.play_progress[sound_index] = false;
+        self.paused[sound_index] = None;
+        let group = match self.voice_layout {
+            VoiceLayout::Original => &mut self.work_areas[info.work_area][..],
+            VoiceLayout::Dynamic => &mut self.sound_parts[sound_index][..],
+        };
+        let state = &mut self.sound_states[sound_index];
+        let loop_points = loop_points(info, self.play_request[sound_index], state);
+        let mut registers: SoundRegisters = Default::default();
+        state.elapsed = 0;
+        state.looped = false;
+        for _ in 0..frame.min(MAX_DRY_RUN_FRAMES) {
+            if self.play_request[sound_index] == 0 && !self.play_progress[sound_index] {
+                break;
+            }
+            let mut events = Vec::new();
+            let mut ctx = PrepareContext { start_ch: 0, suppress_last_silence: self.suppress_last_silence, loop_points, params: state.params, events: &mut events, looped: false };
+            prepare(sound_index, &mut self.play_request, &mut self.play_progress, &info.score, group, &mut registers, &mut ctx);
+            state.looped = ctx.looped;
+            if info.sound_type == SoundType::Retriggerable {
+                self.play_request[sound_index] = 0;
+            }
+            state.elapsed += 1;
+        }
+    }
+
+    // bar 小節目 (0 から) から演奏する。小節の長さはサウンドテーブルの指定による
+    pub fn seek_bar(&mut self, sound_index: usize, bar: usize) -> Result<(), String> {
+        let bar_frames = self.table.sounds[sound_index].bar_frames
+            .ok_or(format!("sound {:02x} has no bar length", sound_index))?;
+        self.seek(sound_index, bar * bar_frames);
+        Ok(())
+    }
+
+    // 演奏を始めてから進めたフレーム数 (ループ演奏では通算)
+    pub fn position(&self, sound_index: usize) -> usize {
+        self.sound_states[sound_index].elapsed
+    }
+
+    // 構築時に求めた１回の演奏の長さ
+    pub fn duration(&self, sound_index: usize) -> &SoundDuration {
+        &self.durations[sound_index]
+    }
+
+    // 演奏中のサウンドの (経過フレーム数, 全体のフレーム数)
+    // ループ演奏では２回目からループ位置に戻った経過を返し、全体はループ位置までとループ１回を合わせた長さにする
+    pub fn elapsed(&self, sound_index: usize) -> Option<(usize, usize)> {
+        if !self.play_progress[sound_index] {
+            return None;
+        }
+        let position = self.position(sound_index);
+        let duration = &self.durations[sound_index];
+        let info = &self.table.sounds[sound_index];
+        match (loop_points(info, self.play_request[sound_index], &self.sound_states[sound_index]), duration.loop_frames) {
+            (Some(_), Some((intro, length))) => {
+                let elapsed = if position > intro { intro + (position - intro - 1) % length.max(1) + 1 } else { position };
+                Some((elapsed, intro + length))
+            },
+            _ => {
+                let total = duration.frames?;
+                Some((position % total.max(1), total))
+            },
+        }
+    }
+
+    // 今の音量から frames フレームかけて音を消し、消えたら止める
+    pub fn fade_out(&mut self, sound_index: usize, frames: usize) {
+        let state = &mut self.sound_states[sound_index];
+        state.start_fade(state.fade_level(), 0, frames);
+    }
+
+    // 今の音量 (演奏前なら無音) から frames フレームかけて元の音量にする
+    pub fn fade_in(&mut self, sound_index: usize, frames: usize) {
+        let state = &mut self.sound_states[sound_index];
+        let from = if self.play_progress[sound_index] || state.fade.is_some() { state.fade_level() } else { 0 };
+        state.start_fade(from, VOLUME_ONE, frames);
+    }
+
+    // ループ演奏で戻るパートごとの位置 (空なら先頭)
+    pub fn set_loop_points(&mut self, sound_index: usize, loop_points: Vec<usize>) -> Result<(), String> {
+        validate_loop_points(&self.table.sounds[sound_index].score, &loop_points)?;
+        self.durations[sound_index] = SoundDuration::new(&self.table.sounds[sound_index].score, &loop_points);
+        self.table.sounds[sound_index].loop_points = loop_points;
+        Ok(())
+    }
+
+    // 演奏を終えた後に続けて鳴らすサウンドを変更する
+    pub fn set_follow_up(&mut self, sound_index: usize, follow_up: Option<FollowUp>) -> Result<(), String> {
+        if let Some(follow_up) = follow_up {
+            if follow_up.next >= self.num_sounds() || follow_up.times == 0 {
+                return Err(format!("follow-up plays sound {:02x} {} times", follow_up.next, follow_up.times));
+            }
+        }
+        self.table.sounds[sound_index].follow_up = follow_up;
+        Ok(())
+    }
+
+    pub fn stop_all(&mut self) {
+        for sound_index in 0..self.num_sounds() {
+            self.stop(sound_index);
+        }
+    }
+
+    // テンポ、移調、ずれ (演奏中に変えてもよい)
+    pub fn set_play_params(&mut self, sound_index: usize, params: PlayParams) {
+        self.sound_states[sound_index].params = params;
+    }
+
+    pub fn play_params(&self, sound_index: usize) -> PlayParams {
+        self.sound_states[sound_index].params
+    }
+
+    // 演奏中、または次の run で演奏を始める
+    pub fn is_playing(&self, sound_index: usize) -> bool {
+        self.play_request[sound_index] != 0 || self.play_progress[sound_index]
+    }
+
+    pub fn sound_name(&self, sound_index: usize) -> &str {
+        &self.table.sounds[sound_index].name
+    }
+
+    // 直前の run でチャンネルに出力したパート
+    pub fn channel_owner(&self, ch: usize) -> Option<PartId> {
+        self.channel_reports[ch].owner
+    }
+
+    // 直前の run でチャンネルを取られて鳴らなかったパート
+    pub fn masked_parts(&self, ch: usize) -> &[PartId] {
+        &self.channel_reports[ch].masked
+    }
+
+    // 後の run の始めに実行する操作を予約する
+    pub fn schedule(&mut self, at: ScheduleAt, command: SoundCommand) -> Result<ScheduleHandle, String> {
+        let sound_no = match command {
+            SoundCommand::Play { sound_no } | SoundCommand::PlayTimes { sound_no, .. } | SoundCommand::PlayLoop { sound_no } |
+            SoundCommand::Stop { sound_no } | SoundCommand::FadeOut { sound_no, .. } | SoundCommand::FadeIn { sound_no, .. } => sound_no,
+        };
+        if sound_no >= self.num_sounds() {
+            return Err(format!("scheduled command for sound {:02x}", sound_no));
+        }
+        if let ScheduleAt::NextNote { sound_no, part_no } = at {
+            if sound_no >= self.num_sounds() || part_no >= self.table.sounds[sound_no].score.len() {
+                return Err(format!("sound {:02x} has no part {}", sound_no, part_no));
+            }
+        }
+        Ok(self.scheduler.push(at, command))
+    }
+
+    // 予約を取り消す (実行済みなら false)
+    pub fn cancel_scheduled(&mut self, handle: ScheduleHandle) -> bool {
+        self.scheduler.cancel(handle)
+    }
+
+    pub fn is_scheduled(&self, handle: ScheduleHandle) -> bool {
+        self.scheduler.contains(handle)
+    }
+
+    // 次の run でパートが次の音符を鳴らし始めるか (演奏していなければ true、一時停止中は false)
+    fn at_note_boundary(&self, sound_index: usize, part_no: usize) -> bool {
+        if self.paused[sound_index].is_some() {
+            return false;
+        }
+        if !self.play_progress[sound_index] {
+            return true;
+        }
+        let info = &self.table.sounds[sound_index];
+        let group = match self.voice_layout {
+            VoiceLayout::Original => &self.work_areas[info.work_area],
+            VoiceLayout::Dynamic => &self.sound_parts[sound_index],
+        };
+        group[part_no].remain_frames == 0
+    }
+
+    fn apply_command(&mut self, command: SoundCommand) {
+        match command {
+            SoundCommand::Play { sound_no } => self.play(sound_no),
+            SoundCommand::PlayTimes { sound_no, times } => self.play_times(sound_no, times),
+            SoundCommand::PlayLoop { sound_no } => self.play_loop(sound_no),
+            SoundCommand::Stop { sound_no } => self.stop(sound_no),
+            SoundCommand::FadeOut { sound_no, frames } => self.fade_out(sound_no, frames),
+            SoundCommand::FadeIn { sound_no, frames } => self.fade_in(sound_no, frames),
+        }
+    }
+
+    // 他のサウンドが演奏中 (一時停止中を除く) で、チャンネルが重なっているか
+    fn channels_busy(&self, sound_index: usize) -> bool {
+        let info = &self.table.sounds[sound_index];
+        self.table.sounds.iter().enumerate().any(|(other, other_info)| {
+            other != sound_index && self.play_progress[other] && self.paused[other].is_none() && info.shares_channels(other_info)
+        })
+    }
+
+    // このフレームで演奏を始める Exclusive は、チャンネルが重なる他のサウンドを止める
+    // 同じフレームに複数あれば優先度の高いもの (同じなら番号の大きいもの) が残る
+    fn stop_for_exclusive(&mut self) {
+        let mut starting: Vec<usize> = (0..self.num_sounds())
+            .filter(|&idx| self.table.sounds[idx].sound_type == SoundType::Exclusive && self.play_request[idx] != 0
+                && !self.play_progress[idx] && self.paused[idx].is_none())
+            .collect();
+        starting.sort_by_key(|&idx| std::cmp::Reverse((self.table.sounds[idx].priority, idx)));
+        for idx in starting {
+            if !self.is_playing(idx) {
+                continue;
+            }
+            for other in 0..self.num_sounds() {
+                if other != idx && self.is_playing(other) && self.table.sounds[idx].shares_channels(&self.table.sounds[other]) {
+                    self.stop(other);
                 }
-                if !progress[idx] {
-                    group[part_no].read_adr = score[idx][part_no].0;
-                    group[part_no].remain_frames = 0; // !! 本来不要だが、remain_frames のアンダーフロー対策のため !!
+            }
+        }
+    }
+
+    // 効果音の間、音楽の音量を下げる (None で下げない)
+    pub fn set_ducking(&mut self, ducking: Option<Ducking>) -> Result<(), String> {
+        let mut table = self.table.clone();
+        table.ducking = ducking;
+        table.validate()?;
+        self.table.ducking = ducking;
+        Ok(())
+    }
+
+    pub fn ducking(&self) -> Option<Ducking> {
+        self.table.ducking
+    }
+
+    // 今、音楽の音量を下げている段数
+    pub fn ducked_steps(&self) -> i32 {
+        self.table.ducking.map_or(0, |ducking| ducking.steps * self.duck_level / VOLUME_ONE)
+    }
+
+    // 効果音が演奏中 (またはこのフレームで始まる) ならダッキングを深め、無ければ戻す
+    fn advance_ducking(&mut self) {
+        let ducking = match self.table.ducking {
+            Some(ducking) => ducking,
+            None => {
+                self.duck_level = 0;
+                return;
+            },
+        };
+        let active = self.table.sounds.iter().enumerate().any(|(idx, info)| {
+            info.category == Some(SoundCategory::Effect) && self.is_playing(idx) && self.paused[idx].is_none()
+        });
+        let (target, frames) = if active { (VOLUME_ONE, ducking.attack) } else { (0, ducking.release) };
+        let step = if frames == 0 { VOLUME_ONE } else { (VOLUME_ONE + frames as i32 - 1) / frames as i32 };
+        self.duck_level = if target > self.duck_level {
+            (self.duck_level + step).min(target)
+        } else {
+            (self.duck_level - step).max(target)
+        };
+    }
+
+    pub fn set_listener(&mut self, listener: Option<Listener>) -> Result<(), String> {
+        if let Some(listener) = listener {
+            listener.validate()?;
+        }
+        self.listener = listener;
+        Ok(())
+    }
+
+    pub fn listener(&self) -> Option<Listener> {
+        self.listener
+    }
+
+    // 位置 (x, y) に置いて演奏する (play と同じく、演奏中の扱いはサウンドの種類による)
+    pub fn play_at(&mut self, sound_index: usize, x: i32, y: i32) {
+        self.set_source_position(sound_index, Some((x, y)));
+        self.play(sound_index);
+    }
+
+    // 音源の位置を変える (演奏中でもよい、None で位置を使わない)
+    pub fn set_source_position(&mut self, sound_index: usize, position: Option<(i32, i32)>) {
+        self.sound_states[sound_index].position = position;
+    }
+
+    pub fn source_position(&self, sound_index: usize) -> Option<(i32, i32)> {
+        self.sound_states[sound_index].position
+    }
+
+    // 位置から決めた (定位, 音量の下げ幅) (位置か聞き手が無ければ None)
+    pub fn spatial(&self, sound_index: usize) -> Option<(i32, i32)> {
+        let (x, y) = self.sound_states[sound_index].position?;
+        Some(self.listener?.pan_and_attenuation(x, y))
+    }
+
+    // 溜まった通知を古い順に取り出す
+    pub fn drain_events(&mut self) -> Vec<SoundEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn run(&mut self) {
+        // 前のフレームで既に鳴っていなかったパートは、取られたと通知し直さない
+        let was_masked: Vec<PartId> = self.channel_reports.iter().flat_map(|report| report.masked.iter().copied()).collect();
+        for report in self.channel_reports.iter_mut() {
+            report.clear();
+        }
+        let first_event = self.events.len();
+        let mut due = Vec::new();
+        let paused = &self.paused;
+        self.follow_ups.retain_mut(|pending| {
+            // 一時停止中のサウンドの続きは待たせておく
+            if paused[pending.from].is_some() {
+                return true;
+            }
+            pending.delay -= 1;
+            if pending.delay == 0 {
+                due.push(*pending);
+            }
+            pending.delay > 0
+        });
+        for pending in due {
+            self.play_times(pending.next, pending.times);
+        }
+        let mut scheduler = std::mem::take(&mut self.scheduler);
+        let commands = scheduler.take_due(|sound_index, part_no| self.at_note_boundary(sound_index, part_no));
+        self.scheduler = scheduler;
+        for command in commands {
+            self.apply_command(command);
+        }
+        self.stop_for_exclusive();
+        self.advance_ducking();
+        let ducked_steps = self.ducked_steps();
+
+        let mut outputs = Vec::new();
+        let mut faded_out = Vec::new();
+        for (idx, info) in self.table.sounds.iter().enumerate() {
+            if self.paused[idx].is_some() {
+                continue;
+            }
+            match info.sound_type {
+                SoundType::Looping if self.play_request[idx] > 0 => self.play_request[idx] = -1,
+                // チャンネルが空くまで要求を残したまま待つ
+                SoundType::Queued if self.play_request[idx] != 0 && !self.play_progress[idx] && self.channels_busy(idx) => continue,
+                _ => {},
+            }
+            let (spatial_pan, attenuation) = match self.spatial(idx) {
+                Some((pan, steps)) => (Some(pan), steps),
+                None => (None, 0),
+            };
+            let score = &info.score[..];
+            let mut part_registers: SoundRegisters = Default::default();
+            let (group, registers, start_ch) = match self.voice_layout {
+                VoiceLayout::Original => (&mut self.work_areas[info.work_area][..], &mut self.registers[..], info.start_ch),
+                VoiceLayout::Dynamic => (&mut self.sound_parts[idx][..], &mut part_registers[..], 0),
+            };
+            if info.sound_type == SoundType::Retriggerable && self.play_request[idx] != 0 && self.play_progress[idx] {
+                gate_retrigger(info, &mut self.play_request[idx], &mut self.sound_states[idx], group);
+            }
+            let loop_points = loop_points(info, self.play_request[idx], &self.sound_states[idx]);
+            // テンポに応じて、このフレームで 0 回以上進める (Retriggerable の新しい要求は演奏し直しなので必ず進める)
+            let started = self.play_progress[idx] && !(info.sound_type == SoundType::Retriggerable && self.play_request[idx] != 0);
+            let ticks = self.sound_states[idx].ticks(started);
+            let params = self.sound_states[idx].params;
+            let mut ctx = PrepareContext { start_ch, suppress_last_silence: self.suppress_last_silence, loop_points, params, events: &mut self.events, looped: false };
+            let mut prepared = false;
+            if ticks == 0 {
+                // 進めないフレームは前のフレームの出力をそのまま出す
+                for (part_no, ch_prepare) in group.iter().enumerate().take(score.len()) {
+                    registers[start_ch + part_no] = ChRegisters { ..ch_prepare.pre_data };
                 }
-                loop {
-                    let r0 = group[part_no].read_adr[0];
-                    if r0 >= 0xf0 {
-                        match r0 {
-                            0xf0 => {
-                                let r1 = group[part_no].read_adr[1];
-                                group[part_no].pre_data.wave_form = (r1 >> 4) as usize;
-                                group[part_no].read_adr = &group[part_no].read_adr[2..];
-                                #[cfg(feature="develop")]
-                                {
-                                    println!("{}.wave form:{}", part_no, group[part_no].pre_data.wave_form);
-                                }
-                                continue;
-                            },
-                            0xf1 => {
-                                let r1 = group[part_no].read_adr[1];
-                                group[part_no].envelope = r1 as usize;
-                                group[part_no].work_c = 0;
-                                group[part_no].read_adr = &group[part_no].read_adr[2..];
-                                #[cfg(feature="develop")]
-                                {
-                                    println!("{}.envelope:{}", part_no, group[part_no].envelope);
-                                }
-                                continue;
-                            },
-                            0xf2 => {
-                                let r1 = group[part_no].read_adr[1];
-                                group[part_no].unit_frames = r1 as usize;
-                                group[part_no].read_adr = &group[part_no].read_adr[2..];
-                                #[cfg(feature="develop")]
-                                {
-                                    println!("{}.unit:{}", part_no, group[part_no].unit_frames);
-                                }
-                                continue;
-                            },
-                            0xf3 => {
-                                if idx == SoundIdx::CreditUpPre as usize {
-                                    request[idx] -= 1;
-                                    if request[idx] == 0 {
-                                        request[SoundIdx::CreditUpPost as usize] = 1;
-                                    }
-                                } else {
-                                    request[idx] = 0;
-                                }
-                                progress[idx] = false;
-                                finishd = true;
-                                group[part_no].clear();
-                                #[cfg(feature="develop")]
-                                {
-                                    println!("{}.end mark", part_no);
-                                }
-                                break;
-                            },
-                            _ => continue,
-                        }
+                prepared = true;
+            }
+            for _ in 0..ticks {
+                let starting = !self.play_progress[idx] || (info.sound_type == SoundType::Retriggerable && self.play_request[idx] != 0);
+                let loop_started = std::mem::take(&mut self.sound_states[idx].looped);
+                let tick_event = ctx.events.len();
+                let ticked = match info.sound_type {
+                    SoundType::OneShot | SoundType::Looping | SoundType::Exclusive | SoundType::Queued => if self.play_request[idx] != 0 {
+                        prepare(idx, &mut self.play_request, &mut self.play_progress, score, group, registers, &mut ctx);
+                        true
                     } else {
-                        let key = r0 >> 4;
-                        let oct = r0 & 0x0f;
-                        group[part_no].pre_data.freq = ch_score.1[key as usize] >> oct;
-                        if group[part_no].remain_frames == 0 {
-                            let len = group[part_no].read_adr[1] as usize * group[part_no].unit_frames;
-                            #[cfg(feature="develop")]
-                            {
-                                println!("{}.key:{} oct:{} len:{}", part_no, key, oct, len);
-                            }
-                            group[part_no].remain_frames = len;
-                            if group[part_no].work_c == 0 {
-                                group[part_no].envelope_read_pos = 0;
-                            }
+                        self.play_progress[idx] = false;
+                        false
+                    }
+                    SoundType::Retriggerable => if self.play_request[idx] != 0 {
+                        self.play_progress[idx] = false;
+                        prepare(idx, &mut self.play_request, &mut self.play_progress, score, group, registers, &mut ctx);
+                        self.play_request[idx] = 0;
+                        true
+                    } else if self.play_progress[idx] {
+                        prepare(idx, &mut self.play_request, &mut self.play_progress, score, group, registers, &mut ctx);
+                        true
+                    } else {
+                        false
+                    }
+                };
+                // 始めたフレームとループ位置から演奏し直したフレームの通知は Started から並べる
+                if ticked && (starting || loop_started) {
+                    ctx.events.insert(tick_event, SoundEvent::Started { sound_no: idx });
+                }
+                self.sound_states[idx].looped = std::mem::take(&mut ctx.looped);
+                if ticked {
+                    let state = &mut self.sound_states[idx];
+                    state.elapsed = if starting { 1 } else { state.elapsed + 1 };
+                }
+                // Retriggerable の繰り返しと待たせていた要求は、終わったフレームで次の要求を出す
+                if ticked && info.sound_type == SoundType::Retriggerable && !self.play_progress[idx] {
+                    let state = &mut self.sound_states[idx];
+                    if state.repeats != 0 {
+                        self.play_request[idx] = 1;
+                        if state.repeats > 0 {
+                            state.repeats -= 1;
                         }
-                        let envelope = group[part_no].envelope;
-                        loop {
-                            let env_pos = group[part_no].envelope_read_pos;
-                            let g = ENVELOPE_TBL[envelope][env_pos];
-                            let gain = match g {
-                                0x10 => {
-                                    let gain = ENVELOPE_TBL[envelope][env_pos - 1];
-                                    gain
-                                },
-                                0x11 => {
-                                    let gain = group[part_no].pre_data.gain;
-                                    if gain > 0 {
-                                        if (gain - 1) <= ENVELOPE_TBL[envelope][env_pos + 1] {
-                                            group[part_no].envelope_read_pos += 1;
-                                        }
-                                        gain - 1
-                                    } else {
-                                        group[part_no].envelope_read_pos += 1;
-                                        0
-                                    }
-                                },
-                                0x12 => {
-                                    let remain = group[part_no].remain_frames;
-                                    let gain = group[part_no].pre_data.gain;
-                                    if remain > gain as usize {
-                                        gain
-                                    } else {
-                                        (remain - 1) as i32
-                                    }
-                                },
-                                0x13 => {
-                                    group[part_no].work_c = 0;
-                                    group[part_no].envelope_read_pos = 0;
-                                    continue;
-                                },
-                                0x14 => {
-                                    group[part_no].work_c = 1;
-                                    group[part_no].envelope_read_pos += 1;
-                                    continue;
-                                },
-                                _ => {
-                                    group[part_no].envelope_read_pos += 1;
-                                    g
-                                },
-                            };
-                            group[part_no].pre_data.gain = gain;
-                            break;
+                    } else if state.queued {
+                        self.play_request[idx] = 1;
+                        state.queued = false;
+                    }
+                }
+                // 最後の回を終えたら続きのサウンドを要求する (遅延 0 なら同じフレームで要求する)
+                if ticked && !self.play_progress[idx] && self.play_request[idx] == 0 {
+                    ctx.events.push(SoundEvent::Finished { sound_no: idx });
+                    self.sound_states[idx].position = None;
+                    // 途中のフェードを次の演奏に持ち越さない
+                    self.sound_states[idx].fade = None;
+                    if let Some(follow_up) = info.follow_up {
+                        if follow_up.delay == 0 {
+                            let next_type = self.table.sounds[follow_up.next].sound_type;
+                            request_times(next_type, &mut self.play_request[follow_up.next], &mut self.sound_states[follow_up.next], follow_up.times);
+                        } else {
+                            self.follow_ups.push(PendingFollowUp { from: idx, next: follow_up.next, times: follow_up.times, delay: follow_up.delay });
                         }
                     }
-                    break;
                 }
+                prepared |= ticked;
             }
-            if !finishd {
-                progress[idx] = true;
+            if !prepared {
+                continue;
             }
-            for part_no in 0..score[idx].len() {
-                registers[start_ch + part_no] = ChRegisters { ..group[part_no].pre_data };
-                group[part_no].remain_frames -= 1; // !! usize がアンダーフロー(0以下)になるケース有り !!
-                if group[part_no].remain_frames == 0 {
-                    group[part_no].read_adr = &group[part_no].read_adr[2..];
-                    // 独自実装：末尾の無音１フレームを出力しない
-                    if suppress_last_silence && !finishd {
-                        if group[part_no].read_adr[0] == 0xf3 {
-                            if idx == SoundIdx::CreditUpPre as usize {
-                                if request[idx] > 0 {
-                                    request[idx] -= 1;
-                                    if request[idx] == 0 {
-                                        request[SoundIdx::CreditUpPost as usize] = 1;
-                                    }
-                                }
-                            } else {
-                                request[idx] = 0;
-                            }
-                            progress[idx] = false;
-                            finishd = true;
-                            group[part_no].clear();
-                            #[cfg(feature="develop")]
-                            {
-                                println!("{}.end mark", part_no);
-                            }
-                        }
-                    }
+            // 音量とフェード、ダッキング、位置による下げ幅は出力だけに掛ける (エンベロープは元の音量のまま進める)
+            let level = self.sound_states[idx].level();
+            let duck = if info.category == Some(SoundCategory::Music) { ducked_steps } else { 0 };
+            for registers in registers[start_ch..start_ch + score.len()].iter_mut() {
+                registers.gain = (registers.gain * level / VOLUME_ONE - duck - attenuation).max(0);
+            }
+            if self.sound_states[idx].advance_fade() {
+                faded_out.push(idx);
+            }
+            match self.voice_layout {
+                VoiceLayout::Original => for part_no in 0..score.len() {
+                    let ch = info.start_ch + part_no;
+                    self.registers[ch].pan = spatial_pan.or(self.registers[ch].pan).or(info.default_pan(part_no));
+                    self.channel_reports[ch].overwrite(PartId { sound_no: idx, part_no });
+                },
+                VoiceLayout::Dynamic => for (part_no, registers) in part_registers.iter().enumerate().take(score.len()) {
+                    let pan = spatial_pan.or(registers.pan).or(info.default_pan(part_no));
+                    outputs.push(PartOutput { sound_no: idx, part_no, registers: registers.get_registers(), pan });
+                },
+            }
+        }
+        if self.voice_layout == VoiceLayout::Dynamic {
+            let allocation = self.voice_allocator.allocate(&self.table, &outputs);
+            for (ch, output) in allocation.channels.iter().enumerate() {
+                if let Some(i) = output {
+                    let (wave_form, freq, gain) = outputs[*i].registers;
+                    self.registers[ch] = ChRegisters { wave_form, freq, gain, pan: outputs[*i].pan };
+                    self.channel_reports[ch].owner = Some(PartId { sound_no: outputs[*i].sound_no, part_no: outputs[*i].part_no });
                 }
             }
+            // マスクされたパートは本来のチャンネル (開始チャンネル + パート番号) に記録する
+            for &i in allocation.masked.iter() {
+                let output = &outputs[i];
+                let ch = self.table.sounds[output.sound_no].start_ch + output.part_no;
+                self.channel_reports[ch].masked.push(PartId { sound_no: output.sound_no, part_no: output.part_no });
+            }
+        }
+        // 鳴らし始めた音符に出力したチャンネルを記す
+        for event in self.events[first_event..].iter_mut() {
+            if let SoundEvent::NoteOn { part, ch, .. } = event {
+                *ch = self.channel_reports.iter().position(|report| report.owner == Some(*part));
+            }
+        }
+        for (ch, report) in self.channel_reports.iter().enumerate() {
+            for part in report.masked.iter().filter(|part| !was_masked.contains(part)) {
+                self.events.push(SoundEvent::Stolen { part: *part, ch, by: report.owner });
+            }
+        }
+        for sound_index in faded_out {
+            self.stop(sound_index);
         }
+        if self.events.len() > MAX_QUEUED_EVENTS {
+            let excess = self.events.len() - MAX_QUEUED_EVENTS;
+            self.events.drain(..excess);
+        }
+    }
+}
 
-        enum SoundType {
-            OneShot,
-            Retriggerable,
-        }
-
-        enum Group {
-            G0100, G0169, G0187, G01c3, G01ff, G023b,
-            G0277, G0295, G02b3, G02c2, G033a, G0376,
-        }
-
-        const SOUND_INFO: [(SoundType, Group, usize); SoundIdx::_EndOfVariants as usize] = [
-            (SoundType::OneShot      , Group::G02c2, 0), // FloorStart
-            (SoundType::OneShot      , Group::G02c2, 0), // FloorFinish
-            (SoundType::OneShot      , Group::G02c2, 0), // FinalFloorFinish
-            (SoundType::OneShot      , Group::G02c2, 0), // Zapped
-            (SoundType::OneShot      , Group::G0100, 0), // IshtarFloor
-            (SoundType::OneShot      , Group::G0100, 0), // NormalFloor
-            (SoundType::OneShot      , Group::G0100, 0), // DragonFloor
-            (SoundType::OneShot      , Group::G0100, 0), // DruagaFloor
-            (SoundType::OneShot      , Group::G01ff, 0), // Chime
-            (SoundType::Retriggerable, Group::G0169, 4), // SlimeMove
-            (SoundType::OneShot      , Group::G0295, 4), // Spell
-            (SoundType::Retriggerable, Group::G0277, 4), // Fire
-            (SoundType::Retriggerable, Group::G0187, 3), // BreakWall
-            (SoundType::OneShot      , Group::G0376, 3), // DragonFlame
-            (SoundType::Retriggerable, Group::G01c3, 5), // Sword1
-            (SoundType::Retriggerable, Group::G01c3, 5), // Sword2
-            (SoundType::OneShot      , Group::G01c3, 5), // Sword3
-            (SoundType::Retriggerable, Group::G01c3, 5), // Sword4
-            (SoundType::Retriggerable, Group::G01c3, 4), // CutMonster
-            (SoundType::Retriggerable, Group::G01c3, 5), // NoUse1
-            (SoundType::OneShot      , Group::G01c3, 5), // BlockSpell
-            (SoundType::OneShot      , Group::G01ff, 0), // OpenDoor
-            (SoundType::OneShot      , Group::G01ff, 0), // GetKey
-            (SoundType::OneShot      , Group::G01ff, 0), // GetItem
-            (SoundType::OneShot      , Group::G0100, 0), // NoUse2
-            (SoundType::OneShot      , Group::G02b3, 7), // GilWalk
-            (SoundType::OneShot      , Group::G033a, 4), // CreditUpPost
-            (SoundType::OneShot      , Group::G02c2, 0), // Miss
-            (SoundType::OneShot      , Group::G02c2, 0), // GameOver
-            (SoundType::OneShot      , Group::G02c2, 0), // NameEntry
-            (SoundType::OneShot      , Group::G033a, 4), // Extend
-            (SoundType::OneShot      , Group::G023b, 4), // CreditUpPre
-        ];
-
-        for (idx, info) in SOUND_INFO.iter().enumerate() {
-            let start_ch = info.2;
-            let group = match info.1 {
-                Group::G0100 => &mut self.group_0100[0..],
-                Group::G0169 => &mut self.group_0169[0..],
-                Group::G0187 => &mut self.group_0187[0..],
-                Group::G01c3 => &mut self.group_01c3[0..],
-                Group::G01ff => &mut self.group_01ff[0..],
-                Group::G023b => &mut self.group_023b[0..],
-                Group::G0277 => &mut self.group_0277[0..],
-                Group::G0295 => &mut self.group_0295[0..],
-                Group::G02b3 => &mut self.group_02b3[0..],
-                Group::G02c2 => &mut self.group_02c2[0..],
-                Group::G033a => &mut self.group_033a[0..],
-                Group::G0376 => &mut self.group_0376[0..],
-            };
-            match info.0 {
-                SoundType::OneShot => if self.play_request[idx] != 0 {
-                    prepare(idx, &mut self.play_request, &mut self.play_progress, MUSIC_SCORES, group, &mut self.registers, start_ch, self.suppress_last_silence);
-                } else {
-                    self.play_progress[idx] = false
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUTS: [VoiceLayout; 2] = [VoiceLayout::Original, VoiceLayout::Dynamic];
+
+    fn manager(voice_layout: VoiceLayout, types: &[(SoundIdx, SoundType)]) -> SoundManager<'static> {
+        let mut table = SoundTable::original();
+        for &(sound, sound_type) in types {
+            table.sounds[sound as usize].sound_type = sound_type;
+        }
+        let mut manager = SoundManager::new(table).unwrap();
+        manager.set_voice_layout(voice_layout);
+        manager
+    }
+
+    fn run_frames(manager: &mut SoundManager, frames: usize) -> Vec<SoundEvent> {
+        let mut events = Vec::new();
+        for _ in 0..frames {
+            manager.run();
+            events.extend(manager.drain_events());
+        }
+        events
+    }
+
+    #[test]
+    fn duration_matches_playthrough() {
+        let sounds = [SoundIdx::NormalFloor, SoundIdx::Chime, SoundIdx::Sword1, SoundIdx::GetItem, SoundIdx::Miss, SoundIdx::NameEntry];
+        for voice_layout in LAYOUTS {
+            let mut manager = manager(voice_layout, &[]);
+            for sound in sounds {
+                let sound = sound as usize;
+                manager.play(sound);
+                let mut frames = 0;
+                while manager.is_playing(sound) {
+                    manager.run();
+                    frames += 1;
+                }
+                assert_eq!(manager.duration(sound).frames, Some(frames), "sound {:02x}", sound);
+            }
+        }
+    }
+
+    #[test]
+    fn retrigger_after_counts_bytes_played_across_loops() {
+        let chime = SoundIdx::Chime as usize;
+        let length = SoundDuration::new(&SoundTable::original().sounds[chime].score, &[]).frames.unwrap();
+        for voice_layout in LAYOUTS {
+            let mut table = SoundTable::original();
+            let sound = &mut table.sounds[chime];
+            // パート 0 の終了の直前の命令までのバイト数 (演奏の終わり近くで届く)
+            let part = sound.score[0].0;
+            let mut offset = 0;
+            let mut adr = 0;
+            while part[adr] != 0xf3 {
+                offset = adr;
+                adr += instruction_len(part[adr]).unwrap();
+            }
+            sound.sound_type = SoundType::Retriggerable;
+            sound.retrigger = Some(RetriggerPolicy { min_interval: 0, mode: RetriggerMode::After { part_no: 0, offset } });
+            let mut manager = SoundManager::new(table).unwrap();
+            manager.set_voice_layout(voice_layout);
+            manager.play_loop(chime);
+            run_frames(&mut manager, 2);
+            // まだ offset に達していない
+            manager.play(chime);
+            assert!(!run_frames(&mut manager, 1).contains(&SoundEvent::Started { sound_no: chime }));
+            // ループして先頭に戻っても読み進めたバイト数は減らない
+            manager.play_loop(chime);
+            run_frames(&mut manager, length + 2);
+            assert!(manager.play_progress(chime));
+            manager.play(chime);
+            assert!(run_frames(&mut manager, 1).contains(&SoundEvent::Started { sound_no: chime }));
+        }
+    }
+
+    #[test]
+    fn sound_type_names_round_trip() {
+        let mut table = SoundTable::original();
+        table.sounds[SoundIdx::Chime as usize].sound_type = SoundType::Looping;
+        table.sounds[SoundIdx::GetKey as usize].sound_type = SoundType::Exclusive;
+        table.sounds[SoundIdx::GetItem as usize].sound_type = SoundType::Queued;
+        let scores: Vec<Vec<PartScore>> = table.sounds.iter().map(|sound| sound.score.clone()).collect();
+        let parsed = SoundTable::parse(&table.to_text(), &scores).unwrap();
+        for (sound, parsed) in table.sounds.iter().zip(parsed.sounds.iter()) {
+            assert_eq!(sound.sound_type, parsed.sound_type);
+        }
+    }
+
+    #[test]
+    fn looping_restarts_until_stopped() {
+        let chime = SoundIdx::Chime as usize;
+        let length = SoundDuration::new(&SoundTable::original().sounds[chime].score, &[]).frames.unwrap();
+        for voice_layout in LAYOUTS {
+            let mut manager = manager(voice_layout, &[(SoundIdx::Chime, SoundType::Looping)]);
+            manager.play_times(chime, 1);
+            let events = run_frames(&mut manager, length * 3);
+            assert!(manager.play_progress(chime));
+            assert!(!events.contains(&SoundEvent::Finished { sound_no: chime }));
+            // 書き込まれた正の要求もループにする
+            manager.stop(chime);
+            manager.play_request[chime] = 1;
+            run_frames(&mut manager, length * 3);
+            assert!(manager.play_progress(chime));
+            manager.stop(chime);
+            run_frames(&mut manager, 1);
+            assert!(!manager.is_playing(chime));
+        }
+    }
+
+    #[test]
+    fn exclusive_stops_sounds_sharing_channels() {
+        let (music, chime, sword) = (SoundIdx::NormalFloor as usize, SoundIdx::Chime as usize, SoundIdx::Sword1 as usize);
+        for voice_layout in LAYOUTS {
+            let mut manager = manager(voice_layout, &[(SoundIdx::Chime, SoundType::Exclusive)]);
+            manager.play(music);
+            manager.play(sword);
+            run_frames(&mut manager, 2);
+            manager.play(chime);
+            let events = run_frames(&mut manager, 1);
+            assert!(events.contains(&SoundEvent::Stopped { sound_no: music }));
+            assert!(!manager.is_playing(music));
+            // チャンネルが重ならないサウンドはそのまま
+            assert!(manager.play_progress(sword));
+            assert!(manager.play_progress(chime));
+        }
+    }
+
+    #[test]
+    fn exclusive_with_higher_priority_wins() {
+        let (get_key, get_item) = (SoundIdx::GetKey as usize, SoundIdx::GetItem as usize);
+        for voice_layout in LAYOUTS {
+            let mut manager = manager(voice_layout, &[(SoundIdx::GetKey, SoundType::Exclusive), (SoundIdx::GetItem, SoundType::Exclusive)]);
+            manager.play(get_key);
+            manager.play(get_item);
+            run_frames(&mut manager, 1);
+            assert!(!manager.is_playing(get_key));
+            assert!(manager.play_progress(get_item));
+        }
+    }
+
+    #[test]
+    fn queued_waits_until_channels_are_free() {
+        let (chime, open_door) = (SoundIdx::Chime as usize, SoundIdx::OpenDoor as usize);
+        let length = SoundDuration::new(&SoundTable::original().sounds[chime].score, &[]).frames.unwrap();
+        for voice_layout in LAYOUTS {
+            let mut manager = manager(voice_layout, &[(SoundIdx::OpenDoor, SoundType::Queued)]);
+            manager.play(chime);
+            run_frames(&mut manager, 1);
+            manager.play(open_door);
+            let events = run_frames(&mut manager, length - 2);
+            assert!(!events.contains(&SoundEvent::Started { sound_no: open_door }));
+            assert!(manager.is_playing(open_door) && !manager.play_progress(open_door));
+            // 先に処理するサウンドが終わったフレームで始める
+            let events = run_frames(&mut manager, 1);
+            assert_eq!(events.first(), Some(&SoundEvent::Finished { sound_no: chime }));
+            assert!(events.contains(&SoundEvent::Started { sound_no: open_door }));
+        }
+    }
+
+    #[test]
+    fn queued_starts_at_once_when_channels_are_free() {
+        let (sword, open_door) = (SoundIdx::Sword1 as usize, SoundIdx::OpenDoor as usize);
+        for voice_layout in LAYOUTS {
+            let mut manager = manager(voice_layout, &[(SoundIdx::OpenDoor, SoundType::Queued)]);
+            manager.play(sword);
+            manager.play(open_door);
+            let events = run_frames(&mut manager, 1);
+            assert!(events.contains(&SoundEvent::Started { sound_no: open_door }));
+        }
+    }
+
+    #[test]
+    fn channel_reports_show_owners_and_masked_parts() {
+        let (music, chime) = (SoundIdx::NormalFloor as usize, SoundIdx::Chime as usize);
+        for voice_layout in [VoiceLayout::Original, VoiceLayout::Dynamic] {
+            let mut manager = SoundManager::default();
+            manager.set_voice_layout(voice_layout);
+            manager.play_request[music] = 1;
+            manager.play_request[chime] = 1;
+            manager.run();
+            // どちらもチャンネル 0 から置かれ、Original では後のサウンドが上書きし、Dynamic では優先度の高い Chime が勝つ
+            assert_eq!(manager.channel_owner(0), Some(PartId { sound_no: chime, part_no: 0 }));
+            assert_eq!(manager.masked_parts(0), [PartId { sound_no: music, part_no: 0 }]);
+            assert_eq!(manager.channel_owner(7), None);
+            assert!(manager.masked_parts(7).is_empty());
+            manager.clear();
+            assert_eq!(manager.channel_owner(0), None);
+        }
+    }
+
+    // 演奏が始まった回数を数える
+    fn count_starts(manager: &mut SoundManager, sounds: &[usize], frames: usize) -> Vec<usize> {
+        let mut starts = vec![0; sounds.len()];
+        let mut prev = vec![false; sounds.len()];
+        for _ in 0..frames {
+            manager.run();
+            for (i, &sound_no) in sounds.iter().enumerate() {
+                let progress = manager.play_progress(sound_no);
+                if progress && !prev[i] {
+                    starts[i] += 1;
+                }
+                prev[i] = progress;
+            }
+        }
+        starts
+    }
+
+    #[test]
+    fn play_times_and_loop_of_each_sound_type() {
+        // 同じワークエリアを使わない組み合わせにする
+        let (slime, chime, flame) = (SoundIdx::SlimeMove as usize, SoundIdx::Chime as usize, SoundIdx::DragonFlame as usize);
+        let mut manager = SoundManager::default();
+        manager.suppress_last_silence = true;
+        manager.play_times(slime, 3);
+        manager.play_times(chime, 2);
+        manager.play_loop(flame);
+        assert!(manager.is_playing(slime) && manager.is_playing(chime) && manager.is_playing(flame));
+        let starts = count_starts(&mut manager, &[slime, chime, flame], 1500);
+        // ループは隙間なく続くので始まりは１回
+        assert_eq!(starts, [3, 2, 1]);
+        assert!(!manager.is_playing(slime) && !manager.is_playing(chime) && manager.is_playing(flame));
+        manager.stop(flame);
+        assert!(!manager.is_playing(flame));
+        assert_eq!(count_starts(&mut manager, &[flame], 10), [0]);
+    }
+
+    #[test]
+    fn play_does_not_restart_a_playing_one_shot() {
+        let chime = SoundIdx::Chime as usize;
+        let mut manager = SoundManager::default();
+        manager.suppress_last_silence = true;
+        manager.play(chime);
+        manager.run();
+        manager.play(chime);
+        assert_eq!(manager.play_request[chime], 1);
+        manager.play_times(chime, 0);
+        assert_eq!(manager.play_request[chime], 1);
+        manager.stop_all();
+        assert!(!manager.is_playing(chime));
+    }
+
+    #[test]
+    fn follow_up_waits_for_the_last_time_and_the_delay() {
+        let (chime, flame) = (SoundIdx::Chime as usize, SoundIdx::DragonFlame as usize);
+        let mut manager = SoundManager::default();
+        manager.suppress_last_silence = true;
+        assert!(manager.set_follow_up(chime, Some(FollowUp { next: flame, times: 0, delay: 0 })).is_err());
+        manager.set_follow_up(chime, Some(FollowUp { next: flame, times: 2, delay: 10 })).unwrap();
+        manager.play_times(chime, 2);
+        let mut chime_end = None;
+        let mut flame_start = None;
+        for frame in 0..3000 {
+            manager.run();
+            if chime_end.is_none() && !manager.is_playing(chime) {
+                chime_end = Some(frame);
+            }
+            if flame_start.is_none() && manager.play_progress(flame) {
+                flame_start = Some(frame);
+            }
+        }
+        // 2 回目を終えたフレームから 10 フレーム後に要求され、次の run で始まる
+        assert_eq!(flame_start.unwrap() - chime_end.unwrap(), 10);
+        assert!(!manager.is_playing(flame));
+    }
+
+    #[test]
+    fn credit_sound_follows_up_after_the_requested_times() {
+        let (pre, post) = (SoundIdx::CreditUpPre as usize, SoundIdx::CreditUpPost as usize);
+        let mut manager = SoundManager::default();
+        manager.suppress_last_silence = true;
+        manager.play_times(pre, 2);
+        let starts = count_starts(&mut manager, &[pre, post], 1000);
+        assert_eq!(starts, [2, 1]);
+    }
+
+    // 1 パートだけのサウンドに差し替えたマネージャ
+    fn single_part_manager(sound_no: usize, part: &'static [u8]) -> SoundManager<'static> {
+        let mut scores: Vec<Vec<PartScore<'static>>> = MUSIC_SCORES.iter().map(|score| score.to_vec()).collect();
+        scores[sound_no] = vec![(part, MUSIC_SCORES[sound_no][0].1)];
+        let mut manager = SoundManager::default();
+        manager.suppress_last_silence = true;
+        manager.set_scores(scores).unwrap();
+        manager
+    }
+
+    // 各フレームのチャンネルのレジスタ
+    fn channel_frames(manager: &mut SoundManager, ch: usize, frames: usize) -> Vec<(usize, i32, i32)> {
+        (0..frames).map(|_| {
+            manager.run();
+            let registers = manager.get_ch_registers()[ch];
+            manager.clear_ch_registers();
+            registers
+        }).collect()
+    }
+
+    #[test]
+    fn loop_restarts_without_a_gap() {
+        let music = SoundIdx::NormalFloor as usize;
+        let mut solo = SoundManager::default();
+        solo.suppress_last_silence = true;
+        solo.play(music);
+        let mut frames = Vec::new();
+        while solo.is_playing(music) {
+            solo.run();
+            frames.push(solo.get_ch_registers());
+            solo.clear_ch_registers();
+        }
+        let mut looping = SoundManager::default();
+        looping.play_loop(music);
+        for frame in 0..frames.len() * 3 {
+            looping.run();
+            assert_eq!(looping.get_ch_registers(), frames[frame % frames.len()], "frame {}", frame);
+            looping.clear_ch_registers();
+        }
+        assert!(looping.is_playing(music));
+    }
+
+    #[test]
+    fn loop_returns_to_the_loop_point() {
+        let music = SoundIdx::NormalFloor as usize;
+        // 4 フレームの音を２つ鳴らし、２つ目の音 (+8) に戻る
+        let mut manager = single_part_manager(music, &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x04, 0x40, 0x01, 0x60, 0x01, 0xf3]);
+        assert!(manager.set_loop_points(music, vec![7]).is_err());
+        assert!(manager.set_loop_points(music, vec![10]).is_err());
+        assert!(manager.set_loop_points(music, vec![8, 8]).is_err());
+        manager.play(music);
+        let once = channel_frames(&mut manager, 0, 8);
+        assert!(!manager.is_playing(music));
+        manager.set_loop_points(music, vec![8]).unwrap();
+        manager.play_loop(music);
+        let looped = channel_frames(&mut manager, 0, 20);
+        assert_eq!(looped[..8], once[..]);
+        for frame in 8..20 {
+            assert_eq!(looped[frame], once[4 + frame % 4], "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn loop_elapsed_and_starts_follow_the_loop_point() {
+        let music = SoundIdx::NormalFloor as usize;
+        // 2 フレームの音のあとの 3 フレームの音 (+8) に戻る
+        let mut manager = single_part_manager(music, &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x02, 0x60, 0x03, 0xf3]);
+        manager.set_loop_points(music, vec![8]).unwrap();
+        assert_eq!(manager.duration(music).loop_frames, Some((2, 3)));
+        manager.play_loop(music);
+        let mut elapsed = Vec::new();
+        let mut starts = Vec::new();
+        for frame in 1..=12 {
+            manager.run();
+            elapsed.push(manager.elapsed(music).unwrap());
+            if manager.drain_events().contains(&SoundEvent::Started { sound_no: music }) {
+                starts.push(frame);
+            }
+        }
+        assert_eq!(elapsed.iter().map(|e| e.0).collect::<Vec<_>>(), [1, 2, 3, 4, 5, 3, 4, 5, 3, 4, 5, 3]);
+        assert!(elapsed.iter().all(|e| e.1 == 5));
+        assert_eq!(starts, [1, 6, 9, 12]);
+        assert_eq!(manager.position(music), 12);
+        // ループしない演奏では末尾の無音までを１回の長さとする
+        manager.stop(music);
+        manager.play(music);
+        manager.run();
+        assert_eq!(manager.elapsed(music), Some((1, 6)));
+    }
+
+    #[test]
+    fn loops_and_calls_play_like_the_unrolled_part() {
+        let music = SoundIdx::NormalFloor as usize;
+        let unrolled = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x04, 0x40, 0x02, 0x60, 0x02, 0x40, 0x02, 0x60, 0x02, 0x40, 0x02, 0x60, 0x02, 0xf3];
+        let looped = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x04, 0xf4, 0x03, 0xf6, 0x00, 0x0d, 0xf5, 0xf3, 0x40, 0x02, 0x60, 0x02, 0xf7];
+        let mut frames = Vec::new();
+        for part in [&unrolled[..], &looped[..]] {
+            let mut manager = single_part_manager(music, part);
+            manager.play(music);
+            frames.push(channel_frames(&mut manager, 0, 60));
+            assert!(!manager.is_playing(music));
+        }
+        assert_eq!(frames[0], frames[1]);
+    }
+
+    // 譜面のテキストを組み立てて 1 パートのサウンドにし、チャンネル 0 の周波数を並べる
+    fn part_frequencies(text: &str, frames: usize) -> Vec<i32> {
+        let music = SoundIdx::NormalFloor as usize;
+        let part: &'static [u8] = Box::leak(assemble(text).unwrap().into_boxed_slice());
+        let mut manager = single_part_manager(music, part);
+        manager.play(music);
+        channel_frames(&mut manager, 0, frames).iter().map(|registers| registers.1).collect()
+    }
+
+    #[test]
+    fn pitch_effects_move_the_frequency() {
+        let scale = MUSIC_SCORES[SoundIdx::NormalFloor as usize][0].1;
+        let (a4, c4) = (scale[0] >> 4, scale[3] >> 4);
+        assert_eq!(part_frequencies("wave 2\nenv 0\nunit 1\na4 20\nend", 20), [a4; 20]);
+
+        // VIBRATO_TBL の 6 番は遅れ無しで１フレームに 16 ずつ位相を進める
+        let vibrato = part_frequencies("wave 2\nenv 0\nunit 1\nvib 6\na4 20\nend", 8);
+        let depth = |phase| a4 + a4 * 24 * vibrato_offset(phase) / (16 * 1024);
+        assert_eq!(vibrato, (0..8).map(|frame| depth(frame * 16)).collect::<Vec<_>>());
+
+        let portamento = part_frequencies("wave 2\nenv 0\nunit 1\nporta 8\na4 4\nc4 12\nend", 16);
+        assert_eq!(portamento[..4], [a4; 4]);
+        for frame in 0..8 {
+            assert_eq!(portamento[4 + frame], a4 + (c4 - a4) * frame as i32 / 8, "frame {}", frame);
+        }
+        assert_eq!(portamento[12..], [c4; 4]);
+
+        let sweep = part_frequencies("wave 2\nenv 0\nunit 1\nsweep -8\na3 12\nend", 12);
+        let a3 = scale[0] >> 3;
+        assert_eq!(sweep, (0..12).map(|frame| a3 - a3 * 8 * frame / 256).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn default_pans_apply_to_the_parts_channels() {
+        let music = SoundIdx::NormalFloor as usize;
+        let mut table = SoundTable::original();
+        table.sounds[music].pans = vec![Some(-1), Some(1), None, Some(0)];
+        for voice_layout in [VoiceLayout::Original, VoiceLayout::Dynamic] {
+            let mut manager = SoundManager::new(table.clone()).unwrap();
+            manager.set_voice_layout(voice_layout);
+            manager.play(music);
+            manager.run();
+            assert_eq!(manager.get_ch_pans()[0..4], [Some(-1), Some(1), None, Some(0)], "{:?}", voice_layout);
+            assert_eq!(manager.get_ch_pans()[4..], [None; 4]);
+        }
+    }
+
+    #[test]
+    fn pan_command_overrides_the_default_pan() {
+        let music = SoundIdx::NormalFloor as usize;
+        let part: &'static [u8] = Box::leak(assemble("wave 2\nenv 0\nunit 1\na4 2\npan R\na4 2\npan L\na4 2\nend").unwrap().into_boxed_slice());
+        let mut manager = single_part_manager(music, part);
+        manager.play(music);
+        let mut pans = Vec::new();
+        for _ in 0..6 {
+            manager.run();
+            pans.push(manager.get_ch_pans()[0]);
+            manager.clear_ch_registers();
+        }
+        assert_eq!(pans, [None, None, Some(1), Some(1), Some(-1), Some(-1)]);
+    }
+
+    #[test]
+    fn loop_keeps_the_pan_and_pitch_effects() {
+        let music = SoundIdx::NormalFloor as usize;
+        // 定位とビブラートを決めてから、ループ位置の音 (+10) を鳴らす
+        let part: &'static [u8] = Box::leak(assemble("wave 2\nenv 0\nunit 1\npan R\nvib 6\na4 4\nend").unwrap().into_boxed_slice());
+        let mut manager = single_part_manager(music, part);
+        manager.set_loop_points(music, vec![10]).unwrap();
+        manager.play_loop(music);
+        let mut frames = Vec::new();
+        for _ in 0..12 {
+            manager.run();
+            frames.push((manager.get_ch_registers()[0], manager.get_ch_pans()[0]));
+            manager.clear_ch_registers();
+        }
+        assert!(frames.iter().all(|(_, pan)| *pan == Some(1)));
+        // ビブラートは音符ごとに掛け直すので、ループのたびに同じ周波数の並びになる
+        assert_ne!(frames[0].0.1, frames[1].0.1);
+        assert_eq!(frames[4..8], frames[..4]);
+        assert_eq!(frames[8..12], frames[..4]);
+    }
+
+    #[test]
+    fn tempo_changes_how_fast_the_score_advances() {
+        let music = SoundIdx::NormalFloor as usize;
+        let part = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x01, 0x50, 0x01, 0x60, 0x01, 0x70, 0x01, 0x80, 0x01, 0x90, 0x01, 0xf3];
+        let frames_at = |tempo: f32, frames: usize| {
+            let mut manager = single_part_manager(music, part);
+            manager.set_play_params(music, PlayParams { tempo, ..Default::default() });
+            manager.play(music);
+            channel_frames(&mut manager, 0, frames)
+        };
+        let normal = frames_at(1.0, 6);
+        // 演奏を始めるフレームはテンポによらず進め、その後はテンポの割合で進める
+        let half = frames_at(0.5, 10);
+        for frame in 0..10 {
+            assert_eq!(half[frame], normal[frame / 2], "frame {}", frame);
+        }
+        let double = frames_at(2.0, 3);
+        for frame in 0..3 {
+            assert_eq!(double[frame], normal[frame * 2 + 1], "frame {}", frame);
+        }
+        assert_eq!(frames_at(100.0, 2)[0], frames_at(MAX_TEMPO, 2)[0]);
+    }
+
+    #[test]
+    fn transpose_and_detune_move_the_note() {
+        let scale = MUSIC_SCORES[SoundIdx::NormalFloor as usize][0].1;
+        let params = |transpose, detune| PlayParams { transpose, detune, ..Default::default() };
+        assert_eq!(params(0, 0).note_freq(scale, 0, 4), scale[0] >> 4);
+        assert_eq!(params(12, 0).note_freq(scale, 0, 4), scale[0] >> 3);
+        assert_eq!(params(-1, 0).note_freq(scale, 0, 4), scale[11] >> 5);
+        assert_eq!(params(3, 0).note_freq(scale, 10, 4), scale[1] >> 3);
+        assert_eq!(params(0, 1200).note_freq(scale, 0, 4), (scale[0] >> 4) * 2);
+        // 休符 (キー 12) は移調しない
+        assert_eq!(params(5, 100).note_freq(scale, 12, 0), scale[12]);
+
+        let music = SoundIdx::NormalFloor as usize;
+        let mut manager = single_part_manager(music, &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x04, 0x04, 0xf3]);
+        manager.set_play_params(music, params(12, 0));
+        assert_eq!(manager.play_params(music), params(12, 0));
+        manager.play(music);
+        assert_eq!(channel_frames(&mut manager, 0, 1)[0].1, scale[0] >> 3);
+    }
+
+    // 長い音１つのサウンドで、音量やフェードを掛けた各フレームのゲイン
+    fn faded_gains(setup: impl Fn(&mut SoundManager, usize), frames: usize) -> (Vec<i32>, bool) {
+        let music = SoundIdx::NormalFloor as usize;
+        let mut manager = single_part_manager(music, &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x40, 0xf3]);
+        manager.play(music);
+        setup(&mut manager, music);
+        let gains = channel_frames(&mut manager, 0, frames).iter().map(|registers| registers.2).collect();
+        (gains, manager.is_playing(music))
+    }
+
+    #[test]
+    fn volume_and_fades_scale_the_gain() {
+        let (normal, _) = faded_gains(|_, _| (), 20);
+        assert!(normal.iter().all(|&gain| gain > 0));
+
+        let (half, _) = faded_gains(|manager, music| manager.set_play_params(music, PlayParams { volume: 0.5, ..Default::default() }), 20);
+        assert_eq!(half, normal.iter().map(|gain| gain * 128 / VOLUME_ONE).collect::<Vec<_>>());
+
+        // フェードアウトを終えたフレームで止まる
+        let (fade_out, playing) = faded_gains(|manager, music| manager.fade_out(music, 8), 20);
+        for frame in 0..8 {
+            assert_eq!(fade_out[frame], normal[frame] * (VOLUME_ONE - VOLUME_ONE * frame as i32 / 8) / VOLUME_ONE, "frame {}", frame);
+        }
+        assert_eq!(fade_out[8..], [0; 12]);
+        assert!(!playing);
+
+        let (fade_in, playing) = faded_gains(|manager, music| manager.fade_in(music, 8), 20);
+        for frame in 0..8 {
+            assert_eq!(fade_in[frame], normal[frame] * (VOLUME_ONE * frame as i32 / 8) / VOLUME_ONE, "frame {}", frame);
+        }
+        assert_eq!(fade_in[8..], normal[8..]);
+        assert!(playing);
+    }
+
+    #[test]
+    fn fade_ends_with_the_sound() {
+        let music = SoundIdx::NormalFloor as usize;
+        let mut manager = single_part_manager(music, &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x04, 0xf3]);
+        manager.play(music);
+        let normal = channel_frames(&mut manager, 0, 4);
+        // 終わるまでにフェードアウトを終えない
+        manager.play(music);
+        manager.fade_out(music, 100);
+        let faded = channel_frames(&mut manager, 0, 8);
+        assert!(faded[1].2 < normal[1].2);
+        assert!(!manager.is_playing(music));
+        manager.play(music);
+        assert_eq!(channel_frames(&mut manager, 0, 4), normal);
+    }
+
+    #[test]
+    fn pause_keeps_the_position_while_another_sound_uses_the_work_area() {
+        let (music, other) = (SoundIdx::NormalFloor as usize, SoundIdx::IshtarFloor as usize);
+        for voice_layout in [VoiceLayout::Original, VoiceLayout::Dynamic] {
+            let mut normal = SoundManager::default();
+            normal.set_voice_layout(voice_layout);
+            normal.play(music);
+            let expected: Vec<_> = (0..40).map(|_| {
+                normal.run();
+                let registers = normal.get_ch_registers();
+                normal.clear_ch_registers();
+                registers
+            }).collect();
+
+            let mut manager = SoundManager::default();
+            manager.set_voice_layout(voice_layout);
+            manager.play(music);
+            let mut frames = Vec::new();
+            for frame in 0..70 {
+                if frame == 10 {
+                    manager.pause(music);
+                    assert!(manager.is_paused(music) && manager.is_playing(music));
+                    // Original では同じ作業領域を使うサウンド
+                    manager.play(other);
+                }
+                if frame == 40 {
+                    manager.stop(other);
+                    manager.resume(music);
+                    assert!(!manager.is_paused(music));
+                }
+                manager.run();
+                if !(10..40).contains(&frame) {
+                    frames.push(manager.get_ch_registers());
                 }
-                SoundType::Retriggerable => if self.play_request[idx] != 0 {
-                    self.play_progress[idx] = false;
-                    prepare(idx, &mut self.play_request, &mut self.play_progress, MUSIC_SCORES, group, &mut self.registers, start_ch, self.suppress_last_silence);
-                    self.play_request[idx] = 0;
-                } else {
-                    if self.play_progress[idx] {
-                        prepare(idx, &mut self.play_request, &mut self.play_progress, MUSIC_SCORES, group, &mut self.registers, start_ch, self.suppress_last_silence);
+                manager.clear_ch_registers();
+            }
+            assert_eq!(frames, expected, "{:?}", voice_layout);
+        }
+    }
+
+    #[test]
+    fn seek_continues_from_the_frame() {
+        let music = SoundIdx::NormalFloor as usize;
+        for voice_layout in [VoiceLayout::Original, VoiceLayout::Dynamic] {
+            let mut normal = SoundManager::default();
+            normal.suppress_last_silence = true;
+            normal.set_voice_layout(voice_layout);
+            let length = normal.duration(music).frames.unwrap();
+            normal.play(music);
+            let mut expected = Vec::new();
+            while normal.is_playing(music) {
+                normal.run();
+                expected.push(normal.get_ch_registers());
+                normal.clear_ch_registers();
+            }
+            // 末尾の無音の１フレームを出さない分だけ短い
+            assert_eq!(expected.len() + 1, length);
+
+            let mut manager = SoundManager::default();
+            manager.suppress_last_silence = true;
+            manager.set_voice_layout(voice_layout);
+            manager.seek(music, 100);
+            assert_eq!(manager.position(music), 100);
+            for frame in 100..110 {
+                manager.run();
+                assert_eq!(manager.get_ch_registers(), expected[frame], "{:?} frame {}", voice_layout, frame);
+                manager.clear_ch_registers();
+            }
+            assert_eq!(manager.position(music), 110);
+            manager.seek(music, length + 10);
+            assert!(!manager.is_playing(music));
+        }
+    }
+
+    #[test]
+    fn seek_bar_uses_the_bar_length() {
+        let music = SoundIdx::NormalFloor as usize;
+        let mut table = SoundTable::original();
+        let mut manager = SoundManager::new(table.clone()).unwrap();
+        assert_eq!(manager.seek_bar(music, 2).unwrap_err(), "sound 05 has no bar length");
+        table.sounds[music].bar_frames = Some(48);
+        manager = SoundManager::new(table).unwrap();
+        manager.seek_bar(music, 2).unwrap();
+        assert_eq!(manager.position(music), 96);
+    }
+
+    #[test]
+    fn durations_of_sounds_and_parts() {
+        let music = SoundIdx::NormalFloor as usize;
+        let short: &'static [u8] = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x04, 0xf3];
+        let long: &'static [u8] = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x08, 0xf3];
+        let endless: &'static [u8] = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x08, 0xf8, 0x00, 0x06];
+        let scale = MUSIC_SCORES[music][0].1;
+        let mut scores: Vec<Vec<PartScore<'static>>> = MUSIC_SCORES.iter().map(|score| score.to_vec()).collect();
+        scores[music] = vec![(short, scale), (long, scale), (endless, scale)];
+        let mut manager = SoundManager::default();
+        manager.suppress_last_silence = true;
+        manager.set_scores(scores).unwrap();
+        // 末尾の無音の１フレームを含み、最初に終わるパートで全体が終わる
+        assert_eq!(manager.duration(music), &SoundDuration { frames: Some(5), part_frames: vec![Some(5), Some(9), None], loop_frames: Some((0, 4)) });
+        assert!(!manager.duration(music).parts_match());
+        assert!(manager.duration(SoundIdx::Chime as usize).parts_match());
+
+        assert_eq!(manager.elapsed(music), None);
+        manager.play(music);
+        for _ in 0..3 {
+            manager.run();
+        }
+        assert_eq!(manager.elapsed(music), Some((3, 5)));
+    }
+
+    #[test]
+    fn events_report_the_playthrough() {
+        let music = SoundIdx::NormalFloor as usize;
+        let part_id = PartId { sound_no: music, part_no: 0 };
+        let mut manager = single_part_manager(music, &[0xf0, 0x20, 0xf1, 0x03, 0xf2, 0x02, 0x40, 0x01, 0x23, 0x01, 0xf3]);
+        manager.play(music);
+        let mut events = Vec::new();
+        for _ in 0..10 {
+            manager.run();
+            events.extend(manager.drain_events());
+        }
+        assert!(manager.drain_events().is_empty());
+        assert_eq!(events, [
+            SoundEvent::Started { sound_no: music },
+            SoundEvent::WaveForm { part: part_id, wave_form: 2 },
+            SoundEvent::Envelope { part: part_id, envelope: 3 },
+            SoundEvent::Control { part: part_id, code: 0xf2, value: 2 },
+            SoundEvent::NoteOn { part: part_id, ch: Some(0), key: 4, oct: 0, frames: 2 },
+            SoundEvent::NoteOn { part: part_id, ch: Some(0), key: 2, oct: 3, frames: 2 },
+            SoundEvent::Finished { sound_no: music },
+        ]);
+        assert_eq!(events[4].to_string(), "05.0.key:4 oct:0 len:2 ch:0");
+    }
+
+    #[test]
+    fn events_report_stops_and_stolen_channels() {
+        let (music, chime) = (SoundIdx::NormalFloor as usize, SoundIdx::Chime as usize);
+        let mut manager = SoundManager::default();
+        manager.suppress_last_silence = true;
+        manager.play(music);
+        manager.run();
+        manager.drain_events();
+        manager.play(chime);
+        manager.run();
+        let stolen: Vec<_> = manager.drain_events().into_iter().filter(|event| matches!(event, SoundEvent::Stolen { .. })).collect();
+        assert_eq!(stolen[0], SoundEvent::Stolen {
+            part: PartId { sound_no: music, part_no: 0 },
+            ch: 0,
+            by: Some(PartId { sound_no: chime, part_no: 0 }),
+        });
+        // 取られたままのパートは次のフレームで通知し直さない
+        manager.run();
+        assert!(!manager.drain_events().iter().any(|event| matches!(event, SoundEvent::Stolen { .. })));
+        manager.stop(music);
+        assert_eq!(manager.drain_events(), [SoundEvent::Stopped { sound_no: music }]);
+        manager.stop(music);
+        assert!(manager.drain_events().is_empty());
+    }
+
+    #[test]
+    fn scheduled_commands_run_at_the_frame_or_note() {
+        let music = SoundIdx::NormalFloor as usize;
+        let mut manager = single_part_manager(music, &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x02, 0x40, 0x01, 0x50, 0x01, 0x60, 0x01, 0xf3]);
+        assert_eq!(manager.schedule(ScheduleAt::Frames(0), SoundCommand::Play { sound_no: 0x40 }).unwrap_err(), "scheduled command for sound 40");
+        assert_eq!(manager.schedule(ScheduleAt::NextNote { sound_no: music, part_no: 1 }, SoundCommand::Stop { sound_no: music }).unwrap_err(), "sound 05 has no part 1");
+
+        let handle = manager.schedule(ScheduleAt::Frames(2), SoundCommand::Play { sound_no: music }).unwrap();
+        manager.run();
+        manager.run();
+        assert!(!manager.is_playing(music) && manager.is_scheduled(handle));
+        manager.run();
+        assert!(manager.is_playing(music) && !manager.is_scheduled(handle));
+        assert!(!manager.cancel_scheduled(handle));
+
+        // １つ目の音符 (2 フレーム) を鳴らし終えた次の run の始めに止める
+        manager.schedule(ScheduleAt::NextNote { sound_no: music, part_no: 0 }, SoundCommand::Stop { sound_no: music }).unwrap();
+        manager.run();
+        assert!(manager.is_playing(music));
+        manager.run();
+        assert!(!manager.is_playing(music));
+    }
+
+    // 指定したフレームで要求し直し、Started の数を数える
+    fn retrigger_starts(policy: Option<RetriggerPolicy>, requests: &[usize], frames: usize) -> usize {
+        let slime = SoundIdx::SlimeMove as usize;
+        let mut table = SoundTable::original();
+        table.sounds[slime].retrigger = policy;
+        let mut manager = SoundManager::new(table).unwrap();
+        let mut starts = 0;
+        for frame in 0..frames {
+            if requests.contains(&frame) {
+                manager.play(slime);
+            }
+            manager.run();
+            starts += manager.drain_events().iter().filter(|event| **event == SoundEvent::Started { sound_no: slime }).count();
+        }
+        starts
+    }
+
+    #[test]
+    fn retrigger_policies_limit_restarts() {
+        let length = SoundManager::default().duration(SoundIdx::SlimeMove as usize).frames.unwrap();
+        assert!(length > 8);
+        let policy = |min_interval, mode| Some(RetriggerPolicy { min_interval, mode });
+        let requests = [0, 3, 8];
+        assert_eq!(retrigger_starts(None, &requests, 8 + length), 3);
+        assert_eq!(retrigger_starts(policy(6, RetriggerMode::Restart), &requests, 8 + length), 2);
+        assert_eq!(retrigger_starts(policy(0, RetriggerMode::Ignore), &requests, 8 + length), 1);
+        // 待たせた要求は１つだけ覚えておき、演奏を終えたら演奏する
+        assert_eq!(retrigger_starts(policy(0, RetriggerMode::Queue), &requests, 2 * length + 2), 2);
+    }
+
+    #[test]
+    fn ducking_lowers_music_while_effects_play() {
+        let (music, sword) = (SoundIdx::NormalFloor as usize, SoundIdx::Sword1 as usize);
+        let sword_length = SoundDuration::new(&SoundTable::original().sounds[sword].score, &[]).frames.unwrap();
+        for voice_layout in LAYOUTS {
+            let mut normal = manager(voice_layout, &[]);
+            let mut ducked = manager(voice_layout, &[]);
+            assert!(ducked.set_ducking(Some(Ducking { steps: 16, attack: 2, release: 4 })).is_err());
+            ducked.set_ducking(Some(Ducking { steps: 4, attack: 2, release: 4 })).unwrap();
+            let mut steps = Vec::new();
+            for frame in 0..sword_length + 8 {
+                for manager in [&mut normal, &mut ducked] {
+                    if frame == 0 {
+                        manager.play(music);
+                    }
+                    if frame == 2 {
+                        manager.play(sword);
                     }
+                    manager.run();
                 }
+                steps.push(ducked.ducked_steps());
+                // 効果音の鳴っていないチャンネルの音楽だけ下がる
+                let (normal_gain, ducked_gain) = (normal.get_ch_registers()[0].2, ducked.get_ch_registers()[0].2);
+                assert_eq!(ducked_gain, (normal_gain - ducked.ducked_steps()).max(0), "{:?} frame {}", voice_layout, frame);
+                normal.clear_ch_registers();
+                ducked.clear_ch_registers();
             }
+            assert_eq!(steps[..4], [0, 0, 2, 4]);
+            assert_eq!(steps[sword_length + 2..], [3, 2, 1, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn positioned_sounds_pan_and_attenuate() {
+        let sword = SoundIdx::Sword1 as usize;
+        let ch = SoundTable::original().sounds[sword].start_ch;
+        let listener = Listener::centered(224, 288);
+        let mut normal = manager(VoiceLayout::Original, &[]);
+        let mut positioned = manager(VoiceLayout::Original, &[]);
+        assert!(positioned.set_listener(Some(Listener { width: 0, ..listener })).is_err());
+        positioned.set_listener(Some(listener)).unwrap();
+        normal.play(sword);
+        positioned.play_at(sword, 0, 144);
+        assert_eq!(positioned.spatial(sword), Some((-1, 6)));
+        for frame in 0..4 {
+            normal.run();
+            positioned.run();
+            let (normal_gain, gain) = (normal.get_ch_registers()[ch].2, positioned.get_ch_registers()[ch].2);
+            assert_eq!(gain, (normal_gain - 6).max(0), "frame {}", frame);
+            assert_eq!(positioned.get_ch_pans()[ch], Some(-1));
+            normal.clear_ch_registers();
+            positioned.clear_ch_registers();
         }
+        // 止めたら位置は消える
+        positioned.stop(sword);
+        assert_eq!(positioned.source_position(sword), None);
+        assert_eq!(positioned.spatial(sword), None);
+    }
+
+    #[test]
+    fn departures_from_the_original_driver() {
+        let chime = SoundIdx::Chime as usize;
+        let length = SoundDuration::new(&SoundTable::original().sounds[chime].score, &[]).frames.unwrap();
+        let mut manager = SoundManager::default();
+        // 正の要求は回数として減らす (元は１回で 0 に戻す)
+        manager.play_request[chime] = 2;
+        assert_eq!(count_starts(&mut manager, &[chime], length * 3), [2]);
+        assert_eq!(manager.play_request[chime], 0);
+        // 負の要求 (ループ) は終わりに達しても残す
+        manager.play_request[chime] = -1;
+        run_frames(&mut manager, length * 2);
+        assert_eq!(manager.play_request[chime], -1);
+        assert!(manager.play_progress(chime));
+
+        // 実行できない命令 (fd) でパートが終わる (元は止まる。テーブルの検証を通らないので譜面を直接演奏する)
+        let scale = MUSIC_SCORES[SoundIdx::NormalFloor as usize][0].1;
+        let part: &[u8] = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x02, 0xfd, 0x50, 0x02, 0xf3];
+        assert_eq!(dry_run(&[(part, scale)]), Some(3));
+    }
+
+    #[test]
+    fn zero_length_notes_last_one_frame() {
+        let music = SoundIdx::NormalFloor as usize;
+        let mut manager = single_part_manager(music, &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x00, 0x40, 0x04, 0xf2, 0x01, 0x50, 0x00, 0xf3]);
+        assert_eq!(manager.duration(music).frames, Some(3));
+        manager.play(music);
+        let frames = channel_frames(&mut manager, 0, 3);
+        assert_ne!(frames[0].1, frames[1].1);
+        assert!(!manager.is_playing(music));
     }
 }
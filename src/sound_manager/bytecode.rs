@@ -0,0 +1,454 @@
+// パートのバイトコード
+//   ノート: (キー << 4 | オクターブ) 長さ、キー 12 は休符
+//   f0 ww: 波形 (ww >> 4)、f1 ee: エンベロープ、f2 uu: 長さの単位フレーム数、f3: 終了
+//   f4 nn: ループ開始 (nn 回)、f5: ループ終了
+//   f6 hh ll: サブルーチン呼び出し、f7: 復帰、f8 hh ll: ジャンプ
+//   (f6, f8 の飛び先はパート先頭からのバイト位置)
+//   f9 vv: ビブラート (VIBRATO_TBL の番号)、fa nn: ポルタメント (次の音へ nn フレームで移る、0 で無し)
+//   fb dd: スイープ (１フレームごとに音程の dd/256 を加える、dd は符号付き、0 で無し)
+//   fc pp: 定位 (pp は符号付きで、負: 左、0: 中央、正: 右)
+
+use super::vibrato_tbl::*;
+
+pub const MAX_LOOP_NEST: usize = 4;
+pub const MAX_CALL_NEST: usize = 4;
+// １フレームに続けて実行できる制御命令の数 (ノートの無い無限ループ対策)
+pub const MAX_CONTROL_STEPS: usize = 256;
+
+const KEY_NAMES: [&str; 13] = ["a", "a+", "b", "c", "c+", "d", "d+", "e", "f", "f+", "g", "g+", "r"];
+
+pub fn instruction_len(r0: u8) -> Option<usize> {
+    match r0 {
+        0x00..=0xf2 | 0xf4 | 0xf9..=0xfc => Some(2),
+        0xf3 | 0xf5 | 0xf7 => Some(1),
+        0xf6 | 0xf8 => Some(3),
+        _ => None,
+    }
+}
+
+fn target(bytes: &[u8], pos: usize) -> usize {
+    (bytes[pos + 1] as usize) << 8 | bytes[pos + 2] as usize
+}
+
+// パートのバイトコードを、0xf3 と、呼び出し・ジャンプで到達する範囲まで解析し、その長さを返す
+pub fn part_length(bytes: &[u8]) -> Result<usize, String> {
+    let mut visited = vec![false; bytes.len()];
+    let mut pending = vec![0];
+    let mut length = 0;
+    while let Some(start) = pending.pop() {
+        let mut pos = start;
+        loop {
+            if pos >= bytes.len() {
+                return Err(format!("no end mark (0xf3) within {} bytes", bytes.len()));
+            }
+            if visited[pos] {
+                break;
+            }
+            visited[pos] = true;
+            let r0 = bytes[pos];
+            let len = instruction_len(r0).ok_or(format!("unknown command {:02x} at +{:04x}", r0, pos))?;
+            if pos + len > bytes.len() {
+                return Err(format!("command {:02x} at +{:04x} is cut off", r0, pos));
+            }
+            length = length.max(pos + len);
+            match r0 {
+                0xf3 | 0xf7 => break,
+                0xf6 => pending.push(target(bytes, pos)),
+                0xf8 => {
+                    pos = target(bytes, pos);
+                    continue;
+                },
+                _ => (),
+            }
+            pos += len;
+        }
+    }
+    Ok(length)
+}
+
+// ループの対応、入れ子の深さ、飛び先を確かめる
+pub fn validate_part(bytes: &[u8]) -> Result<(), String> {
+    let len = part_length(bytes)?;
+    let bytes = &bytes[..len];
+    let mut starts = vec![false; len];
+    let mut pos = 0;
+    while pos < len {
+        starts[pos] = true;
+        pos += instruction_len(bytes[pos]).unwrap_or(1);
+    }
+    // (位置, ループの深さ, 呼び出しの深さ, 呼び出し時のループの深さ)
+    let mut visited = Vec::new();
+    let mut pending = vec![(0, 0, 0, 0)];
+    while let Some((start, loop_depth, call_depth, entry_depth)) = pending.pop() {
+        let (mut pos, mut loop_depth) = (start, loop_depth);
+        loop {
+            let state = (pos, loop_depth, call_depth, entry_depth);
+            if visited.contains(&state) {
+                break;
+            }
+            visited.push(state);
+            let r0 = bytes[pos];
+            match r0 {
+                0xf3 => {
+                    if loop_depth > 0 {
+                        return Err(format!("end mark at +{:04x} inside a loop", pos));
+                    }
+                    break;
+                },
+                0xf4 => {
+                    if bytes[pos + 1] == 0 {
+                        return Err(format!("loop count 0 at +{:04x}", pos));
+                    }
+                    loop_depth += 1;
+                    if loop_depth > MAX_LOOP_NEST {
+                        return Err(format!("loops nested deeper than {} at +{:04x}", MAX_LOOP_NEST, pos));
+                    }
+                },
+                0xf9 if bytes[pos + 1] as usize >= VIBRATO_TBL.len() => {
+                    return Err(format!("vibrato {} at +{:04x} does not exist", bytes[pos + 1], pos));
+                },
+                0xf5 => {
+                    if loop_depth <= entry_depth {
+                        return Err(format!("loop end without loop start at +{:04x}", pos));
+                    }
+                    loop_depth -= 1;
+                },
+                0xf6 | 0xf8 => {
+                    let to = target(bytes, pos);
+                    if to >= len || !starts[to] {
+                        return Err(format!("{} at +{:04x} to +{:04x}, which is not a command", if r0 == 0xf6 { "call" } else { "jump" }, pos, to));
+                    }
+                    if r0 == 0xf8 {
+                        pos = to;
+                        continue;
+                    }
+                    if call_depth + 1 > MAX_CALL_NEST {
+                        return Err(format!("calls nested deeper than {} at +{:04x}", MAX_CALL_NEST, pos));
+                    }
+                    pending.push((to, loop_depth, call_depth + 1, loop_depth));
+                },
+                0xf7 => {
+                    if call_depth == 0 {
+                        return Err(format!("return outside a subroutine at +{:04x}", pos));
+                    }
+                    if loop_depth != entry_depth {
+                        return Err(format!("return at +{:04x} inside a loop", pos));
+                    }
+                    break;
+                },
+                _ => (),
+            }
+            pos += instruction_len(r0).unwrap();
+        }
+    }
+    Ok(())
+}
+
+// 演奏中のループ・呼び出しの状態
+#[derive(Default, Debug, Clone, Copy)]
+pub struct FlowState<'a> {
+    part: &'a [u8],
+    loops: [(&'a [u8], u8); MAX_LOOP_NEST],
+    loop_depth: usize,
+    calls: [&'a [u8]; MAX_CALL_NEST],
+    call_depth: usize,
+}
+
+#[allow(dead_code)]
+impl<'a> FlowState<'a> {
+    pub fn new(part: &'a [u8]) -> Self {
+        Self { part, ..Default::default() }
+    }
+
+    // read_adr の先頭の f4..f8 を実行する
+    // 実行できない (未知の命令、戻り先の無い復帰、範囲外の飛び先) ときは false を返し、終了として扱わせる
+    pub fn execute(&mut self, read_adr: &mut &'a [u8]) -> bool {
+        let adr = *read_adr;
+        match adr[0] {
+            0xf4 => {
+                if self.loop_depth < MAX_LOOP_NEST {
+                    self.loops[self.loop_depth] = (&adr[2..], adr[1]);
+                    self.loop_depth += 1;
+                }
+                *read_adr = &adr[2..];
+            },
+            0xf5 => {
+                *read_adr = &adr[1..];
+                if self.loop_depth > 0 {
+                    let (start, count) = &mut self.loops[self.loop_depth - 1];
+                    *count = count.saturating_sub(1);
+                    if *count > 0 {
+                        *read_adr = start;
+                    } else {
+                        self.loop_depth -= 1;
+                    }
+                }
+            },
+            0xf6 => {
+                let to = target(adr, 0);
+                if self.call_depth >= MAX_CALL_NEST || to >= self.part.len() {
+                    return false;
+                }
+                self.calls[self.call_depth] = &adr[3..];
+                self.call_depth += 1;
+                *read_adr = &self.part[to..];
+            },
+            0xf7 => {
+                if self.call_depth == 0 {
+                    return false;
+                }
+                self.call_depth -= 1;
+                *read_adr = self.calls[self.call_depth];
+            },
+            0xf8 => {
+                let to = target(adr, 0);
+                if to >= self.part.len() {
+                    return false;
+                }
+                *read_adr = &self.part[to..];
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    // f0..f2, f9..fc と制御命令を読み進めた先が終了か (状態は変えない)
+    pub fn reaches_end(&self, read_adr: &'a [u8]) -> bool {
+        let mut flow = *self;
+        let mut adr = read_adr;
+        for _ in 0..MAX_CONTROL_STEPS {
+            match adr.first() {
+                Some(0xf0..=0xf2 | 0xf9..=0xfc) => adr = &adr[2..],
+                Some(0xf3) | None => return true,
+                Some(0xf4..=0xff) => if !flow.execute(&mut adr) {
+                    return true;
+                },
+                Some(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+// offset が 0xf3 より前の命令の先頭か
+pub fn is_instruction_start(bytes: &[u8], offset: usize) -> bool {
+    let mut pos = 0;
+    while let Some(&r0) = bytes.get(pos) {
+        if pos == offset {
+            return r0 != 0xf3;
+        }
+        if pos > offset || r0 == 0xf3 {
+            return false;
+        }
+        match instruction_len(r0) {
+            Some(len) => pos += len,
+            None => return false,
+        }
+    }
+    false
+}
+
+// assemble で読める書式に逆アセンブルする (";" 以降にバイト位置と元のバイト列を付ける)
+pub fn disassemble(bytes: &[u8]) -> Result<String, String> {
+    let len = part_length(bytes)?;
+    let mut text = String::new();
+    let mut pos = 0;
+    while pos < len {
+        let r0 = bytes[pos];
+        let n = instruction_len(r0).unwrap_or(1).min(len - pos);
+        let code = &bytes[pos..pos + n];
+        let line = match *code {
+            // 下位４ビットが 0 でない波形と、-1..=1 以外の定位は書式で表せないので db にする
+            [0xf0, w] if w & 0x0f == 0 => format!("wave {}", w >> 4),
+            [0xf1, e] => format!("env {}", e),
+            [0xf2, u] => format!("unit {}", u),
+            [0xf3] => "end".to_string(),
+            [0xf4, count] => format!("loop {}", count),
+            [0xf5] => "next".to_string(),
+            [0xf6, hi, lo] => format!("call +{:04x}", (hi as usize) << 8 | lo as usize),
+            [0xf7] => "ret".to_string(),
+            [0xf8, hi, lo] => format!("jump +{:04x}", (hi as usize) << 8 | lo as usize),
+            [0xf9, v] => format!("vib {}", v),
+            [0xfa, n] => format!("porta {}", n),
+            [0xfb, d] => format!("sweep {}", d as i8),
+            [0xfc, p] if (-1..=1).contains(&(p as i8)) => format!("pan {}", ["L", "C", "R"][(p as i8 + 1) as usize]),
+            [note, length] if note >> 4 < 12 => format!("{}{} {}", KEY_NAMES[(note >> 4) as usize], note & 0x0f, length),
+            [note, length] if note >> 4 == 12 && note & 0x0f == 0 => format!("r {}", length),
+            _ => format!("db {}", code.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")),
+        };
+        let hex: Vec<String> = code.iter().map(|b| format!("{:02x}", b)).collect();
+        text += &format!("    {:<12} ; +{:04x} {}\n", line, pos, hex.join(" "));
+        pos += n;
+    }
+    Ok(text)
+}
+
+fn parse_operand(s: Option<&str>, line: &str) -> Result<usize, String> {
+    let s = s.ok_or(format!("missing operand: {}", line))?;
+    let value = match s.strip_prefix('+') {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    value.map_err(|_| format!("invalid operand {}: {}", s, line))
+}
+
+fn byte_operand(s: Option<&str>, line: &str) -> Result<u8, String> {
+    let value = parse_operand(s, line)?;
+    u8::try_from(value).map_err(|_| format!("operand {} is out of range: {}", value, line))
+}
+
+// disassemble の書式 (";" 以降は注釈) をバイトコードに戻す
+pub fn assemble(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for line in text.lines() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        let mut fields = line.split_whitespace();
+        let mnemonic = match fields.next() {
+            Some(mnemonic) => mnemonic,
+            None => continue,
+        };
+        match mnemonic {
+            "wave" => {
+                let w = byte_operand(fields.next(), line)?;
+                if w >= 0x10 {
+                    return Err(format!("wave must be 0..15: {}", line));
+                }
+                bytes.extend([0xf0, w << 4]);
+            },
+            "env" => bytes.extend([0xf1, byte_operand(fields.next(), line)?]),
+            "unit" => bytes.extend([0xf2, byte_operand(fields.next(), line)?]),
+            "end" => bytes.push(0xf3),
+            "loop" => bytes.extend([0xf4, byte_operand(fields.next(), line)?]),
+            "next" => bytes.push(0xf5),
+            "call" | "jump" => {
+                let to = parse_operand(fields.next(), line)?;
+                let to = u16::try_from(to).map_err(|_| format!("target is out of range: {}", line))?;
+                bytes.push(if mnemonic == "call" { 0xf6 } else { 0xf8 });
+                bytes.extend(to.to_be_bytes());
+            },
+            "ret" => bytes.push(0xf7),
+            "vib" => bytes.extend([0xf9, byte_operand(fields.next(), line)?]),
+            "porta" => bytes.extend([0xfa, byte_operand(fields.next(), line)?]),
+            "sweep" => {
+                let s = fields.next().ok_or(format!("missing operand: {}", line))?;
+                let d: i8 = s.parse().map_err(|_| format!("invalid operand {}: {}", s, line))?;
+                bytes.extend([0xfb, d as u8]);
+            },
+            "pan" => {
+                let p: i8 = match fields.next() {
+                    Some("L") => -1,
+                    Some("C") => 0,
+                    Some("R") => 1,
+                    _ => return Err(format!("pan must be L, C or R: {}", line)),
+                };
+                bytes.extend([0xfc, p as u8]);
+            },
+            "db" => for hex in fields.by_ref() {
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| format!("invalid byte {}: {}", hex, line))?);
+            },
+            "r" => bytes.extend([0xc0, byte_operand(fields.next(), line)?]),
+            _ => {
+                let split = mnemonic.find(|c: char| c.is_ascii_digit()).ok_or(format!("unknown command: {}", line))?;
+                let (name, oct) = mnemonic.split_at(split);
+                let key = KEY_NAMES.iter().take(12).position(|k| *k == name).ok_or(format!("unknown command: {}", line))?;
+                let oct: u8 = oct.parse().ok().filter(|o| *o < 0x10).ok_or(format!("invalid octave: {}", line))?;
+                bytes.extend([(key as u8) << 4 | oct, byte_operand(fields.next(), line)?]);
+            },
+        }
+        if let Some(extra) = fields.next() {
+            return Err(format!("unexpected {}: {}", extra, line));
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sound_score::*;
+
+    // 3 回のループの中でサブルーチンを呼ぶ
+    const LOOPED: &[u8] = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x04, 0xf4, 0x03, 0xf6, 0x00, 0x0d, 0xf5, 0xf3, 0x40, 0x02, 0x60, 0x02, 0xf7];
+
+    #[test]
+    fn part_length_follows_calls_and_jumps() {
+        assert_eq!(part_length(LOOPED).unwrap(), LOOPED.len());
+        assert_eq!(part_length(&[0xf8, 0x00, 0x05, 0xf3, 0xff, 0x40, 0x02, 0xf8, 0x00, 0x03, 0x99]).unwrap(), 10);
+        assert!(part_length(&[0x40, 0x02]).unwrap_err().starts_with("no end mark"));
+        assert!(part_length(&[0xfd, 0xf3]).unwrap_err().starts_with("unknown command fd"));
+        assert!(part_length(&[0x40, 0x02, 0xf6, 0x00]).unwrap_err().contains("is cut off"));
+    }
+
+    #[test]
+    fn validate_part_rejects_broken_flow() {
+        validate_part(LOOPED).unwrap();
+        let cases: [(&[u8], &str); 8] = [
+            (&[0xf4, 0x02, 0x40, 0x02, 0xf3], "end mark at +0004 inside a loop"),
+            (&[0xf5, 0xf3], "loop end without loop start at +0000"),
+            (&[0xf7, 0xf3], "return outside a subroutine at +0000"),
+            (&[0xf8, 0x00, 0x01, 0x40, 0x02, 0xf3], "jump at +0000 to +0001, which is not a command"),
+            (&[0xf4, 0x00, 0xf5, 0xf3], "loop count 0 at +0000"),
+            (&[0xf6, 0x00, 0x04, 0xf3, 0xf4, 0x02, 0xf7], "return at +0006 inside a loop"),
+            (&[0xf4, 1, 0xf4, 1, 0xf4, 1, 0xf4, 1, 0xf4, 1, 0xf5, 0xf5, 0xf5, 0xf5, 0xf5, 0xf3], "loops nested deeper than 4 at +0008"),
+            (&[0x40, 0x01, 0xf9, 0x08, 0xf3], "vibrato 8 at +0002 does not exist"),
+        ];
+        for (part, message) in cases {
+            assert_eq!(validate_part(part).unwrap_err(), message);
+        }
+    }
+
+    #[test]
+    fn flow_state_runs_loops_and_calls() {
+        let mut flow = FlowState::new(LOOPED);
+        let mut read_adr = &LOOPED[6..];
+        let mut notes = Vec::new();
+        for _ in 0..100 {
+            match read_adr[0] {
+                0xf3 => break,
+                0xf4..=0xf8 => assert!(flow.execute(&mut read_adr)),
+                note => {
+                    notes.push(note);
+                    read_adr = &read_adr[2..];
+                },
+            }
+        }
+        assert_eq!(notes, [0x40, 0x60, 0x40, 0x60, 0x40, 0x60]);
+        assert!(!FlowState::new(LOOPED).execute(&mut &[0xf7, 0xf3][..]));
+        assert!(FlowState::new(LOOPED).reaches_end(&LOOPED[11..]));
+        assert!(!FlowState::new(LOOPED).reaches_end(&LOOPED[6..]));
+    }
+
+    #[test]
+    fn disassemble_round_trips() {
+        let text = disassemble(LOOPED).unwrap();
+        assert!(text.contains("loop 3 ") && text.contains("call +000d ") && text.contains("ret "));
+        assert_eq!(assemble(&text).unwrap(), LOOPED);
+        assert_eq!(assemble("loop 2\n c+4 3\n r 1\nnext\njump +0000").unwrap(), [0xf4, 0x02, 0x44, 0x03, 0xc0, 0x01, 0xf5, 0xf8, 0x00, 0x00]);
+        let effects = [0xf9, 0x03, 0xfa, 0x08, 0xfb, 0xf8, 0xfc, 0xff, 0xfc, 0x00, 0xfc, 0x01, 0xf3];
+        let text = disassemble(&effects).unwrap();
+        assert!(text.contains("vib 3 ") && text.contains("porta 8 ") && text.contains("sweep -8 "));
+        assert!(text.contains("pan L ") && text.contains("pan C ") && text.contains("pan R "));
+        assert_eq!(assemble(&text).unwrap(), effects);
+        // 書式で表せない波形と定位は db で残す
+        let raw = [0xf0, 0x50, 0xf0, 0x35, 0xfc, 0x05, 0xfc, 0x80, 0xf3];
+        let text = disassemble(&raw).unwrap();
+        assert!(text.contains("wave 5 ") && text.contains("db f0 35 "));
+        assert!(text.contains("db fc 05 ") && text.contains("db fc 80 "));
+        assert_eq!(assemble(&text).unwrap(), raw);
+        assert!(assemble("h4 1").unwrap_err().starts_with("unknown command"));
+        assert!(assemble("a4 256").unwrap_err().starts_with("operand 256 is out of range"));
+        assert!(assemble("pan X").unwrap_err().starts_with("pan must be L, C or R"));
+        assert!(assemble("wave 16").unwrap_err().starts_with("wave must be 0..15"));
+        assert!(assemble("end 1").unwrap_err().starts_with("unexpected 1"));
+    }
+
+    #[test]
+    fn builtin_parts_round_trip() {
+        for (sound_no, parts) in MUSIC_SCORES.iter().enumerate() {
+            for (part_no, part) in parts.iter().enumerate() {
+                let len = part_length(part.0).unwrap();
+                assert_eq!(assemble(&disassemble(part.0).unwrap()).unwrap(), &part.0[..len], "{:02x}.{}", sound_no, part_no);
+            }
+        }
+    }
+}
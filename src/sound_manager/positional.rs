@@ -0,0 +1,60 @@
+// 音源の位置から定位と音量の下げ幅を決めるための聞き手と画面 (プレイフィールド) の大きさ
+// 座標の単位は呼び出し側で揃える (キャラクタ単位でもドット単位でもよい)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Listener {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+// 画面の端 (聞き手から幅・高さの半分) まで離れたときに下げる音量の段数
+pub const POSITION_EDGE_STEPS: f32 = 6.0;
+// 下げる段数の上限 (音量の段数)
+pub const MAX_POSITION_STEPS: i32 = 15;
+
+#[allow(dead_code)]
+impl Listener {
+    // 聞き手を画面の中央に置く
+    pub fn centered(width: i32, height: i32) -> Self {
+        Self { x: width / 2, y: height / 2, width, height }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width <= 0 || self.height <= 0 {
+            return Err(format!("play field size {}x{} is empty", self.width, self.height));
+        }
+        Ok(())
+    }
+
+    // (x, y) にある音源の定位 (-1: 左、0: 中央、1: 右) と音量の下げ幅 (段数)
+    // 聞き手から左右に幅の 1/6 以内なら中央とし、離れるほど音量を下げる
+    pub fn pan_and_attenuation(&self, x: i32, y: i32) -> (i32, i32) {
+        let dx = x - self.x;
+        let dy = y - self.y;
+        let pan = if dx.abs() * 6 < self.width { 0 } else { dx.signum() };
+        let nx = dx as f32 * 2.0 / self.width.max(1) as f32;
+        let ny = dy as f32 * 2.0 / self.height.max(1) as f32;
+        let steps = ((nx * nx + ny * ny).sqrt() * POSITION_EDGE_STEPS).round() as i32;
+        (pan, steps.clamp(0, MAX_POSITION_STEPS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pan_and_attenuation_follow_the_distance() {
+        let listener = Listener::centered(224, 288);
+        assert_eq!(listener.pan_and_attenuation(112, 144), (0, 0));
+        // 幅の 1/6 以内は中央
+        assert_eq!(listener.pan_and_attenuation(112 + 37, 144).0, 0);
+        assert_eq!(listener.pan_and_attenuation(112 + 38, 144).0, 1);
+        assert_eq!(listener.pan_and_attenuation(0, 144), (-1, 6));
+        assert_eq!(listener.pan_and_attenuation(224, 0), (1, 8));
+        assert_eq!(listener.pan_and_attenuation(10000, 144), (1, MAX_POSITION_STEPS));
+        assert!(Listener { x: 0, y: 0, width: 0, height: 10 }.validate().is_err());
+        listener.validate().unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use std::fs;
+
+use super::bytecode::*;
+use super::scale_set::*;
+use super::sound_score::*;
+use super::PartScore;
+
+// sound_score.rs の定数名(アドレス)から起こしたサウンド CPU ROM の対応表
+// 書式: "base" 行で ROM 先頭アドレス、各行は サウンド番号 と パート先頭アドレス/スケールセット番号 の並び
+// "crc32" 行があれば、読み込んだイメージの CRC32 と一致しない場合は読み込みを拒否する
+// パートに "@ポインタ位置" を付けると、パートの先頭アドレスは ROM 内のポインタ(ビッグエンディアン)から読む
+// "free 開始 終了" は書き換え用の空き領域(終了アドレスは含まない)、"checksum アドレス" は
+// ROM 全体の８ビット加算値を保つために書き換える調整用バイトの位置
+// 既定の対応表には元の ROM の CRC32 がまだ無い (確かめたダンプの値が分かれば crc32 行を足す)
+pub const DEFAULT_ROM_MAP: &str = "\
+base e000
+00 eba3/0 ebe4/0 ec17/0 ec56/0 ec87/0 ecc2/0 ecf1/0 ecf1/1
+01 f6b6/0 f6e3/0 f70c/0 f739/0 f6e3/1 f766/1 f78d/1 f6e3/2
+02 f7cd/1 f809/1 f845/1 f881/0 f881/1 f8b3/2 f7cd/2 f8c0/2
+03 f8e6/0 f917/1 f948/2 f979/0 f979/1 f9bc/0 f9c5/1 f9ce/2
+04 e70d/0 e7e0/0 e80f/1 e81c/1
+05 e832/0 eae4/0 e94a/0 ea2a/0
+06 f9ea/0 faa8/0 fa49/0 fad5/0 fae0/1 faeb/1
+07 fc7e/0 fcc4/0 fcf6/0 fd32/0 fd6e/1 fd8b/1 fda8/1
+08 fb8c/0 fb8c/1 fb8c/2 fb8c/0
+09 ed23/0 ed23/1
+0a f1f4/0 f249/1
+0b fc33/0 fc33/2
+0c ed51/0 ed6c/0 ed87/2 eda2/1
+0d edf4/0 ee29/0 ee5e/2
+0e ee96/0 eeb9/0
+0f eee6/0 eeff/1 eeff/2
+10 ef22/0 ef3b/1 ef22/2
+11 ef5e/0 ef7f/1 ef5e/2
+12 f40c/0 f42d/0 f44e/0 f42d/2
+13 f2c0/0
+14 f259/0 f259/2
+15 f28a/0 f28a/1 f2b3/0 f2b3/1
+16 edca/0 edd9/0 edca/1 edd9/1
+17 fb55/0 fb68/0 fb55/1 fb68/1
+18 efb0/0 efc9/1 efd4/2 efdf/0 efea/1
+19 fc1d/0
+1a fbf5/0 fbfe/0 fc07/0 fc10/0
+1b f3aa/0 f3aa/1 f3aa/2 f3aa/1 f3aa/0 f3aa/1 f3aa/2 f3aa/0
+1c f2ff/1 f320/1 f341/1 f362/0 f362/1 f362/2 f376/1
+1d f00b/0 f09f/0 f10f/0 f169/0 f1e4/0 f00b/1 f1e4/1
+1e fb03/0 fb1a/0 fb31/0 fb03/0
+1f fbac/0 fbbb/0 fbca/0 fbd9/0
+";
+
+#[derive(Debug, Clone, Copy)]
+pub struct RomPart {
+    pub address: usize,
+    pub scale: usize,
+    pub pointer: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct RomMap {
+    pub base: usize,
+    pub crc32: Option<u32>,
+    pub sounds: Vec<Vec<RomPart>>,
+    pub free: Vec<(usize, usize)>,
+    pub checksum: Option<usize>,
+}
+
+fn parse_hex(s: &str, line_no: usize) -> Result<usize, String> {
+    usize::from_str_radix(s, 16).map_err(|_| format!("line {}: invalid number {}", line_no, s))
+}
+
+#[allow(dead_code)]
+impl RomMap {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line_no = n + 1;
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let head = match fields.next() {
+                Some(head) => head,
+                None => continue,
+            };
+            match head {
+                "base" => map.base = parse_hex(fields.next().unwrap_or(""), line_no)?,
+                "crc32" => map.crc32 = Some(parse_hex(fields.next().unwrap_or(""), line_no)? as u32),
+                "checksum" => map.checksum = Some(parse_hex(fields.next().unwrap_or(""), line_no)?),
+                "free" => {
+                    let start = parse_hex(fields.next().unwrap_or(""), line_no)?;
+                    let end = parse_hex(fields.next().unwrap_or(""), line_no)?;
+                    if end <= start {
+                        return Err(format!("line {}: empty free area {:04x}-{:04x}", line_no, start, end));
+                    }
+                    map.free.push((start, end));
+                },
+                _ => {
+                    let sound_no = parse_hex(head, line_no)?;
+                    if sound_no != map.sounds.len() {
+                        return Err(format!("line {}: sound {:02x} is out of order (expected {:02x})", line_no, sound_no, map.sounds.len()));
+                    }
+                    let mut parts = Vec::new();
+                    for field in fields {
+                        let (field, pointer) = match field.split_once('@') {
+                            Some((field, pointer)) => (field, Some(parse_hex(pointer, line_no)?)),
+                            None => (field, None),
+                        };
+                        let (address, scale) = field.split_once('/').ok_or(format!("line {}: part must be ADDRESS/SCALE[@POINTER]: {}", line_no, field))?;
+                        let scale = parse_hex(scale, line_no)?;
+                        if scale >= SCALE_SETS.len() {
+                            return Err(format!("line {}: scale set {} does not exist", line_no, scale));
+                        }
+                        parts.push(RomPart { address: parse_hex(address, line_no)?, scale, pointer });
+                    }
+                    map.sounds.push(parts);
+                },
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn offset(&self, address: usize, len: usize, image: &[u8]) -> Option<usize> {
+        address.checked_sub(self.base).filter(|offset| offset + len <= image.len())
+    }
+
+    // ポインタがあればそれが指すアドレス、なければ対応表に書かれたアドレス
+    pub fn part_address(&self, part: &RomPart, image: &[u8]) -> Result<usize, String> {
+        match part.pointer {
+            Some(pointer) => {
+                let offset = self.offset(pointer, 2, image).ok_or(format!("pointer {:04x} is outside the ROM image", pointer))?;
+                Ok(u16::from_be_bytes([image[offset], image[offset + 1]]) as usize)
+            },
+            None => Ok(part.address),
+        }
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in data.iter() {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[derive(Debug)]
+pub struct RomScores {
+    pub crc32: u32,
+    pub sounds: Vec<Vec<(Vec<u8>, usize)>>,
+}
+
+#[allow(dead_code)]
+impl RomScores {
+    pub fn extract(image: &[u8], map: &RomMap) -> Result<Self, String> {
+        let crc = crc32(image);
+        if let Some(expected) = map.crc32 {
+            if crc != expected {
+                return Err(format!("ROM image CRC32 is {:08x}, expected {:08x}", crc, expected));
+            }
+        }
+        let mut sounds = Vec::new();
+        for (sound_no, parts) in map.sounds.iter().enumerate() {
+            let mut sound = Vec::new();
+            for (part_no, part) in parts.iter().enumerate() {
+                let address = map.part_address(part, image).map_err(|e| format!("sound {:02x} part {}: {}", sound_no, part_no, e))?;
+                let offset = map.offset(address, 1, image)
+                    .ok_or(format!("sound {:02x} part {}: address {:04x} is outside the ROM image", sound_no, part_no, address))?;
+                let len = part_length(&image[offset..])
+                    .map_err(|e| format!("sound {:02x} part {} at {:04x}: {}", sound_no, part_no, address, e))?;
+                sound.push((image[offset..offset + len].to_vec(), part.scale));
+            }
+            sounds.push(sound);
+        }
+        Ok(Self { crc32: crc, sounds })
+    }
+
+    pub fn load(path: &str, map: &RomMap) -> Result<Self, String> {
+        let image = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::extract(&image, map).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn score_table(&self) -> Vec<Vec<PartScore<'_>>> {
+        self.sounds.iter().map(|parts| {
+            parts.iter().map(|(bytes, scale)| (&bytes[..], SCALE_SETS[*scale])).collect()
+        }).collect()
+    }
+
+    // 手で書き起こした MUSIC_SCORES と異なるパートを列挙する
+    pub fn compare_builtin(&self) -> Vec<String> {
+        let mut differences = Vec::new();
+        for (sound_no, parts) in self.sounds.iter().enumerate() {
+            let builtin = match MUSIC_SCORES.get(sound_no) {
+                Some(builtin) => builtin,
+                None => {
+                    differences.push(format!("sound {:02x}: not in the built-in scores", sound_no));
+                    continue;
+                },
+            };
+            if builtin.len() != parts.len() {
+                differences.push(format!("sound {:02x}: {} parts, built-in has {}", sound_no, parts.len(), builtin.len()));
+            }
+            for (part_no, ((bytes, scale), (builtin_bytes, builtin_scale))) in parts.iter().zip(builtin.iter()).enumerate() {
+                if &bytes[..] != *builtin_bytes {
+                    differences.push(format!("sound {:02x} part {}: data differs from the built-in score", sound_no, part_no));
+                }
+                if SCALE_SETS[*scale] != *builtin_scale {
+                    differences.push(format!("sound {:02x} part {}: scale set differs from the built-in score", sound_no, part_no));
+                }
+            }
+        }
+        differences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8010 と 8020 に１パートずつ置いた 256 バイトのイメージ
+    const PART: &[u8] = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x04, 0xf3];
+    const MAP: &str = "base 8000\n00 8010/0\n01 8020/1@8000\n";
+
+    fn image() -> Vec<u8> {
+        let mut image = vec![0xff; 0x100];
+        image[..2].copy_from_slice(&[0x80, 0x20]);
+        image[0x10..0x10 + PART.len()].copy_from_slice(PART);
+        image[0x20..0x20 + PART.len()].copy_from_slice(PART);
+        image
+    }
+
+    #[test]
+    fn crc32_of_the_check_string() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn extract_checks_the_crc32() {
+        let image = image();
+        let map = RomMap::parse(&format!("{}crc32 {:08x}\n", MAP, crc32(&image))).unwrap();
+        let scores = RomScores::extract(&image, &map).unwrap();
+        assert_eq!(scores.crc32, crc32(&image));
+        assert_eq!(scores.sounds, [vec![(PART.to_vec(), 0)], vec![(PART.to_vec(), 1)]]);
+
+        // 譜面の外の１バイトが違っても読み込まない
+        let mut corrupted = image.clone();
+        corrupted[0xf0] ^= 0x01;
+        let error = RomScores::extract(&corrupted, &map).unwrap_err();
+        assert_eq!(error, format!("ROM image CRC32 is {:08x}, expected {:08x}", crc32(&corrupted), crc32(&image)));
+        // crc32 行が無ければ確かめない
+        RomScores::extract(&corrupted, &RomMap::parse(MAP).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn parse_rejects_broken_maps() {
+        let cases = [
+            ("base 8000\n01 8010/0\n", "line 2: sound 01 is out of order (expected 00)"),
+            ("base 8000\n00 8010\n", "line 2: part must be ADDRESS/SCALE[@POINTER]: 8010"),
+            ("base 8000\n00 8010/9\n", "line 2: scale set 9 does not exist"),
+            ("free 8100 8100\n", "line 1: empty free area 8100-8100"),
+            ("crc32 xyz\n", "line 1: invalid number xyz"),
+        ];
+        for (text, message) in cases {
+            assert_eq!(RomMap::parse(text).unwrap_err(), message);
+        }
+        let map = RomMap::parse(DEFAULT_ROM_MAP).unwrap();
+        assert_eq!(map.sounds.len(), MUSIC_SCORES.len());
+        assert!(map.sounds.iter().zip(MUSIC_SCORES.iter()).all(|(parts, score)| parts.len() == score.len()));
+    }
+}
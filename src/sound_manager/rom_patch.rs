@@ -0,0 +1,217 @@
+use super::bytecode::*;
+use super::rom_image::*;
+
+#[derive(Debug)]
+pub struct EditedPart {
+    pub sound_no: usize,
+    pub part_no: usize,
+    pub bytes: Vec<u8>,
+}
+
+fn parse_part_header(line: &str, line_no: usize) -> Result<Option<EditedPart>, String> {
+    let header = match line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let (sound_no, part_no) = header.split_once('.').ok_or(format!("line {}: header must be [SOUND.PART]", line_no))?;
+    Ok(Some(EditedPart {
+        sound_no: usize::from_str_radix(sound_no, 16).map_err(|_| format!("line {}: invalid sound number {}", line_no, sound_no))?,
+        part_no: part_no.parse().map_err(|_| format!("line {}: invalid part number {}", line_no, part_no))?,
+        bytes: Vec::new(),
+    }))
+}
+
+// "[サウンド番号.パート番号]" の行に続けて 16 進のバイト列を並べた書式 (";" 以降は注釈)
+pub fn parse_hex_parts(text: &str) -> Result<Vec<EditedPart>, String> {
+    let mut parts: Vec<EditedPart> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line_no = n + 1;
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(part) = parse_part_header(line, line_no)? {
+            parts.push(part);
+            continue;
+        }
+        let part = parts.last_mut().ok_or(format!("line {}: data before the first [SOUND.PART] header", line_no))?;
+        for hex in line.split_whitespace() {
+            let hex = hex.trim_start_matches("0x").trim_end_matches(',');
+            part.bytes.push(u8::from_str_radix(hex, 16).map_err(|_| format!("line {}: invalid byte {}", line_no, hex))?);
+        }
+    }
+    Ok(parts)
+}
+
+// parse_hex_parts と同じ見出しに続けて、disassemble の書式でパートを書いたもの
+pub fn parse_asm_parts(text: &str) -> Result<Vec<EditedPart>, String> {
+    let mut parts: Vec<(EditedPart, String)> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line_no = n + 1;
+        if let Some(part) = parse_part_header(line.split(';').next().unwrap_or("").trim(), line_no)? {
+            parts.push((part, String::new()));
+            continue;
+        }
+        if let Some((_, source)) = parts.last_mut() {
+            source.push_str(line);
+            source.push('\n');
+        } else if !line.split(';').next().unwrap_or("").trim().is_empty() {
+            return Err(format!("line {}: code before the first [SOUND.PART] header", line_no));
+        }
+    }
+    parts.into_iter().map(|(mut part, source)| {
+        let name = format!("[{:02x}.{}]", part.sound_no, part.part_no);
+        part.bytes = assemble(&source).map_err(|e| format!("{}: {}", name, e))?;
+        validate_part(&part.bytes).map_err(|e| format!("{}: {}", name, e))?;
+        Ok(part)
+    }).collect()
+}
+
+pub fn hex_parts_text(parts: &[EditedPart]) -> String {
+    let mut s = String::new();
+    for part in parts.iter() {
+        s.push_str(&format!("[{:02x}.{}]\n", part.sound_no, part.part_no));
+        let hex: Vec<String> = part.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        for line in hex.chunks(16) {
+            s.push_str(&line.join(" "));
+            s.push('\n');
+        }
+    }
+    s
+}
+
+fn byte_sum(image: &[u8]) -> u8 {
+    image.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+pub struct PatchedRom {
+    pub image: Vec<u8>,
+    pub log: Vec<String>,
+}
+
+pub fn patch_rom(image: &[u8], map: &RomMap, edited_parts: &[EditedPart]) -> Result<PatchedRom, String> {
+    if map.free.is_empty() {
+        return Err("the ROM map has no free areas to place edited parts in".to_string());
+    }
+    let mut free = Vec::new();
+    for &(start, end) in map.free.iter() {
+        map.offset(start, end - start, image).ok_or(format!("free area {:04x}-{:04x} is outside the ROM image", start, end))?;
+        if let Some(&(other_start, other_end)) = free.iter().find(|(other_start, other_end)| start < *other_end && *other_start < end) {
+            return Err(format!("free area {:04x}-{:04x} overlaps free area {:04x}-{:04x}", start, end, other_start, other_end));
+        }
+        if let Some(adjust) = map.checksum.filter(|adjust| (start..end).contains(adjust)) {
+            return Err(format!("free area {:04x}-{:04x} covers the checksum byte {:04x}", start, end, adjust));
+        }
+        free.push((start, end));
+    }
+    // 空き領域が現在使われているパートと重なっていないことを確かめる
+    for (sound_no, parts) in map.sounds.iter().enumerate() {
+        for (part_no, part) in parts.iter().enumerate() {
+            let address = map.part_address(part, image)?;
+            let offset = map.offset(address, 1, image).ok_or(format!("sound {:02x} part {}: address {:04x} is outside the ROM image", sound_no, part_no, address))?;
+            let len = part_length(&image[offset..]).map_err(|e| format!("sound {:02x} part {}: {}", sound_no, part_no, e))?;
+            if let Some(&(start, end)) = free.iter().find(|(start, end)| address < *end && *start < address + len) {
+                return Err(format!("free area {:04x}-{:04x} overlaps sound {:02x} part {} at {:04x}", start, end, sound_no, part_no, address));
+            }
+        }
+    }
+
+    let sum_before = byte_sum(image);
+    let mut patched = image.to_vec();
+    let mut log = Vec::new();
+    for edited in edited_parts.iter() {
+        let name = format!("sound {:02x} part {}", edited.sound_no, edited.part_no);
+        let len = part_length(&edited.bytes).map_err(|e| format!("{}: {}", name, e))?;
+        if len != edited.bytes.len() {
+            return Err(format!("{}: {} bytes follow the end mark (0xf3)", name, edited.bytes.len() - len));
+        }
+        let part = map.sounds.get(edited.sound_no)
+            .and_then(|parts| parts.get(edited.part_no))
+            .ok_or(format!("{}: not in the ROM map", name))?;
+        let pointer = part.pointer.ok_or(format!("{}: the ROM map has no pointer location for it", name))?;
+        let pointer_offset = map.offset(pointer, 2, image).ok_or(format!("{}: pointer {:04x} is outside the ROM image", name, pointer))?;
+        let block = match free.iter_mut().find(|(start, end)| end - start >= len) {
+            Some(block) => block,
+            None => {
+                let largest = free.iter().map(|(start, end)| end - start).max().unwrap_or(0);
+                return Err(format!("{}: {} bytes do not fit in the free areas of the ROM (largest free area: {} bytes)", name, len, largest));
+            },
+        };
+        let address = block.0;
+        block.0 += len;
+        let offset = map.offset(address, len, image).unwrap();
+        patched[offset..offset + len].copy_from_slice(&edited.bytes);
+        patched[pointer_offset..pointer_offset + 2].copy_from_slice(&(address as u16).to_be_bytes());
+        log.push(format!("{}: {} bytes at {:04x} (pointer at {:04x})", name, len, address, pointer));
+    }
+
+    match map.checksum {
+        Some(adjust) => {
+            let offset = map.offset(adjust, 1, image).ok_or(format!("checksum byte {:04x} is outside the ROM image", adjust))?;
+            let diff = sum_before.wrapping_sub(byte_sum(&patched));
+            patched[offset] = patched[offset].wrapping_add(diff);
+            log.push(format!("checksum byte at {:04x} set to {:02x} (sum {:02x})", adjust, patched[offset], sum_before));
+        },
+        None => log.push("the ROM map has no checksum byte; checksum not adjusted".to_string()),
+    }
+    Ok(PatchedRom { image: patched, log })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::rom_image::*;
+
+    // 8010 と 8020 のパートを 8000 と 8002 のポインタから読む 256 バイトのイメージ
+    const PART: &[u8] = &[0xf0, 0x20, 0xf1, 0x00, 0xf2, 0x01, 0x40, 0x04, 0xf3];
+    const MAP: &str = "base 8000\n00 8010/0@8000 8020/0@8002\n";
+
+    fn image() -> Vec<u8> {
+        let mut image = vec![0xff; 0x100];
+        image[..4].copy_from_slice(&[0x80, 0x10, 0x80, 0x20]);
+        image[0x10..0x10 + PART.len()].copy_from_slice(PART);
+        image[0x20..0x20 + PART.len()].copy_from_slice(PART);
+        image
+    }
+
+    fn edited(bytes: &[u8]) -> Vec<EditedPart> {
+        vec![EditedPart { sound_no: 0, part_no: 1, bytes: bytes.to_vec() }]
+    }
+
+    #[test]
+    fn patch_moves_the_part_and_keeps_the_sum() {
+        let image = image();
+        let map = RomMap::parse(&format!("{}free 8080 80c0\nchecksum 80ff\n", MAP)).unwrap();
+        let bytes = [0xf0, 0x30, 0xf1, 0x01, 0xf2, 0x02, 0x40, 0x04, 0x60, 0x04, 0xf3];
+        let patched = patch_rom(&image, &map, &edited(&bytes)).unwrap();
+        assert_eq!(patched.image[2..4], [0x80, 0x80]);
+        assert_eq!(patched.image[0x80..0x80 + bytes.len()], bytes);
+        assert_eq!(byte_sum(&patched.image), byte_sum(&image));
+        assert_eq!(patched.log[0], "sound 00 part 1: 11 bytes at 8080 (pointer at 8002)");
+        let scores = RomScores::extract(&patched.image, &map).unwrap();
+        assert_eq!(scores.sounds[0][0].0, PART);
+        assert_eq!(scores.sounds[0][1].0, bytes);
+    }
+
+    #[test]
+    fn patch_rejects_broken_maps_and_parts() {
+        let image = image();
+        let cases = [
+            ("", "the ROM map has no free areas"),
+            ("free 8080 80c0\nfree 80b0 80d0\n", "free area 80b0-80d0 overlaps free area 8080-80c0"),
+            ("free 8080 80c0\nchecksum 8090\n", "free area 8080-80c0 covers the checksum byte 8090"),
+            ("free 8018 8040\n", "free area 8018-8040 overlaps sound 00 part 0 at 8010"),
+            ("free 80f0 8110\n", "free area 80f0-8110 is outside the ROM image"),
+            ("free 8080 8082\n", "sound 00 part 1: 3 bytes do not fit in the free areas of the ROM (largest free area: 2 bytes)"),
+        ];
+        for (lines, message) in cases {
+            let map = RomMap::parse(&format!("{}{}", MAP, lines)).unwrap();
+            let error = patch_rom(&image, &map, &edited(&[0x40, 0x04, 0xf3])).err().unwrap();
+            assert!(error.starts_with(message), "{}", error);
+        }
+        let map = RomMap::parse(&format!("{}free 8080 80c0\n", MAP)).unwrap();
+        assert!(patch_rom(&image, &map, &edited(&[0x40, 0x04, 0xf3, 0x00])).err().unwrap().ends_with("1 bytes follow the end mark (0xf3)"));
+        let map = RomMap::parse("base 8000\n00 8010/0 8020/0\nfree 8080 80c0\n").unwrap();
+        assert!(patch_rom(&image, &map, &edited(&[0x40, 0x04, 0xf3])).err().unwrap().ends_with("the ROM map has no pointer location for it"));
+    }
+}
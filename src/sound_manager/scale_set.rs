@@ -17,3 +17,5 @@ pub const SCALE_2: ScaleSet = [
     0x03_5763, 0x03_8A3F, 0x03_C02E, 0x03_F92E, 0x04_35C5, 0x04_75C5,
     0x00_0000,
 ];
+
+pub const SCALE_SETS: [&ScaleSet; 3] = [&SCALE_0, &SCALE_1, &SCALE_2];
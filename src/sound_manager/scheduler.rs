@@ -0,0 +1,114 @@
+// 後のフレームで実行するサウンドの操作
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoundCommand {
+    Play { sound_no: usize },
+    PlayTimes { sound_no: usize, times: usize },
+    PlayLoop { sound_no: usize },
+    Stop { sound_no: usize },
+    FadeOut { sound_no: usize, frames: usize },
+    FadeIn { sound_no: usize, frames: usize },
+}
+
+// 操作を実行するタイミング
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleAt {
+    // frames 回 run を飛ばした次の run の始め (0 なら次の run)
+    Frames(usize),
+    // 演奏中のサウンドのパートが次の音符を鳴らし始める run の始め
+    // (そのサウンドを演奏していなければ次の run)
+    NextNote { sound_no: usize, part_no: usize },
+}
+
+// 予約を取り消すための番号
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleHandle(u64);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScheduledCommand {
+    pub handle: ScheduleHandle,
+    pub at: ScheduleAt,
+    pub command: SoundCommand,
+}
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    commands: Vec<ScheduledCommand>,
+    next_handle: u64,
+}
+
+#[allow(dead_code)]
+impl Scheduler {
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+
+    pub fn push(&mut self, at: ScheduleAt, command: SoundCommand) -> ScheduleHandle {
+        let handle = ScheduleHandle(self.next_handle);
+        self.next_handle += 1;
+        self.commands.push(ScheduledCommand { handle, at, command });
+        handle
+    }
+
+    // 取り消せたら true (実行済みか取り消し済みなら false)
+    pub fn cancel(&mut self, handle: ScheduleHandle) -> bool {
+        let len = self.commands.len();
+        self.commands.retain(|scheduled| scheduled.handle != handle);
+        self.commands.len() != len
+    }
+
+    pub fn contains(&self, handle: ScheduleHandle) -> bool {
+        self.commands.iter().any(|scheduled| scheduled.handle == handle)
+    }
+
+    // この run で実行する操作を予約順に取り出す (Frames は残りを１減らす)
+    // at_note_boundary はパートが次の run で音符を鳴らし始めるか
+    pub fn take_due(&mut self, at_note_boundary: impl Fn(usize, usize) -> bool) -> Vec<SoundCommand> {
+        let mut due = Vec::new();
+        self.commands.retain_mut(|scheduled| {
+            let ready = match &mut scheduled.at {
+                ScheduleAt::Frames(0) => true,
+                ScheduleAt::Frames(frames) => {
+                    *frames -= 1;
+                    false
+                },
+                ScheduleAt::NextNote { sound_no, part_no } => at_note_boundary(*sound_no, *part_no),
+            };
+            if ready {
+                due.push(scheduled.command);
+            }
+            !ready
+        });
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_count_down_in_schedule_order() {
+        let mut scheduler = Scheduler::default();
+        let later = scheduler.push(ScheduleAt::Frames(2), SoundCommand::Stop { sound_no: 1 });
+        let next = scheduler.push(ScheduleAt::Frames(0), SoundCommand::Play { sound_no: 1 });
+        let cancelled = scheduler.push(ScheduleAt::Frames(0), SoundCommand::Play { sound_no: 2 });
+        assert!(scheduler.cancel(cancelled));
+        assert!(!scheduler.cancel(cancelled));
+        assert_eq!(scheduler.take_due(|_, _| true), [SoundCommand::Play { sound_no: 1 }]);
+        assert!(!scheduler.contains(next) && scheduler.contains(later));
+        assert!(scheduler.take_due(|_, _| true).is_empty());
+        assert_eq!(scheduler.take_due(|_, _| true), [SoundCommand::Stop { sound_no: 1 }]);
+        assert!(!scheduler.contains(later));
+    }
+
+    #[test]
+    fn next_note_waits_for_the_part() {
+        let mut scheduler = Scheduler::default();
+        scheduler.push(ScheduleAt::NextNote { sound_no: 5, part_no: 1 }, SoundCommand::Stop { sound_no: 5 });
+        scheduler.push(ScheduleAt::NextNote { sound_no: 5, part_no: 0 }, SoundCommand::FadeOut { sound_no: 5, frames: 8 });
+        assert!(scheduler.take_due(|_, _| false).is_empty());
+        assert_eq!(scheduler.take_due(|sound_no, part_no| (sound_no, part_no) == (5, 0)), [SoundCommand::FadeOut { sound_no: 5, frames: 8 }]);
+        assert_eq!(scheduler.take_due(|_, _| true), [SoundCommand::Stop { sound_no: 5 }]);
+    }
+}
@@ -0,0 +1,553 @@
+use super::envelope_tbl::*;
+
+pub const MAX_PARTS: usize = 8;
+pub const MAX_OCTAVE: i32 = 7;
+const REST: u8 = 0xc0;
+const FRAMES_PER_SECOND: f64 = 60.0;
+// ScaleSet のキー 0・オクターブ 0 は A7 (MIDI ノート 105)
+const MIDI_NOTE_OF_KEY0_OCT0: i32 = 105;
+
+// GM の楽器ファミリー(8 program 単位)から波形番号への既定の対応
+const FAMILY_WAVE: [u8; 16] = [4, 6, 5, 3, 2, 6, 6, 4, 5, 6, 0, 6, 1, 3, 7, 1];
+const DRUM_CHANNEL: u8 = 9;
+const DRUM_WAVE: u8 = 1;
+
+pub struct SmfImportOptions {
+    pub unit_frames: u8,
+    pub sources: Option<Vec<usize>>,
+    pub wave_map: [u8; 128],
+    pub fixed_envelope: Option<u8>,
+}
+
+impl Default for SmfImportOptions {
+    fn default() -> Self {
+        let mut wave_map = [0; 128];
+        for (program, wave) in wave_map.iter_mut().enumerate() {
+            *wave = FAMILY_WAVE[program / 8];
+        }
+        Self {
+            unit_frames: 2,
+            sources: None,
+            wave_map,
+            fixed_envelope: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SmfSource {
+    pub track: usize,
+    pub channel: u8,
+    pub name: String,
+    pub num_notes: usize,
+}
+
+#[derive(Debug)]
+pub struct ImportedPart {
+    pub source: usize,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportedScore {
+    pub sources: Vec<SmfSource>,
+    pub parts: Vec<ImportedPart>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MidiEvent {
+    NoteOn { key: u8, velocity: u8 },
+    NoteOff { key: u8 },
+    Program(u8),
+    Control { number: u8, value: u8 },
+}
+
+struct TrackData {
+    name: String,
+    events: Vec<(u32, u8, MidiEvent)>, // (tick, チャンネル, イベント)
+}
+
+struct Smf {
+    format: u16,
+    division: u16,
+    tracks: Vec<TrackData>,
+    tempo_map: Vec<(u32, u32)>, // (tick, ４分音符あたりのマイクロ秒)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn eof(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.data.len() {
+            return Err(format!("unexpected end of data at offset {}", self.pos));
+        }
+        let b = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn var_len(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = (value << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(format!("variable length quantity too long at offset {}", self.pos))
+    }
+}
+
+fn parse_smf(data: &[u8]) -> Result<Smf, String> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(4)? != b"MThd" {
+        return Err("not a Standard MIDI File (MThd missing)".to_string());
+    }
+    let header_len = reader.u32()? as usize;
+    let header = reader.bytes(header_len)?;
+    if header_len < 6 {
+        return Err("MThd chunk too short".to_string());
+    }
+    let format = u16::from_be_bytes([header[0], header[1]]);
+    let num_tracks = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if format > 1 {
+        return Err(format!("SMF format {} is not supported (only 0 and 1)", format));
+    }
+    if division == 0 {
+        return Err("SMF division is 0".to_string());
+    }
+    let mut tracks = Vec::new();
+    let mut tempo_map = Vec::new();
+    while tracks.len() < num_tracks && !reader.eof() {
+        let id = reader.bytes(4)?;
+        let len = reader.u32()? as usize;
+        let chunk = reader.bytes(len)?;
+        if id != b"MTrk" {
+            continue;
+        }
+        tracks.push(parse_track(chunk, &mut tempo_map)?);
+    }
+    tempo_map.sort_by_key(|t| t.0);
+    Ok(Smf { format, division, tracks, tempo_map })
+}
+
+fn parse_track(chunk: &[u8], tempo_map: &mut Vec<(u32, u32)>) -> Result<TrackData, String> {
+    let mut reader = Reader::new(chunk);
+    let mut track = TrackData { name: String::new(), events: Vec::new() };
+    let mut tick = 0u32;
+    let mut running_status = 0u8;
+    while !reader.eof() {
+        let delta = reader.var_len()?;
+        tick = tick.checked_add(delta).ok_or(format!("delta time overflows the tick count at offset {}", reader.pos))?;
+        let mut status = reader.u8()?;
+        match status {
+            0xff => {
+                let meta_type = reader.u8()?;
+                let len = reader.var_len()? as usize;
+                let body = reader.bytes(len)?;
+                match meta_type {
+                    0x03 if track.name.is_empty() => track.name = String::from_utf8_lossy(body).to_string(),
+                    0x51 if len == 3 => tempo_map.push((tick, u32::from_be_bytes([0, body[0], body[1], body[2]]))),
+                    0x2f => break,
+                    _ => (),
+                }
+                continue;
+            },
+            0xf0 | 0xf7 => {
+                let len = reader.var_len()? as usize;
+                reader.bytes(len)?;
+                continue;
+            },
+            _ => (),
+        }
+        let first = if status < 0x80 {
+            if running_status == 0 {
+                return Err("data byte without running status".to_string());
+            }
+            let d = status;
+            status = running_status;
+            d
+        } else {
+            running_status = status;
+            reader.u8()?
+        };
+        let channel = status & 0x0f;
+        let event = match status & 0xf0 {
+            0x80 => {
+                reader.u8()?;
+                Some(MidiEvent::NoteOff { key: first })
+            },
+            0x90 => {
+                let velocity = reader.u8()?;
+                if velocity == 0 {
+                    Some(MidiEvent::NoteOff { key: first })
+                } else {
+                    Some(MidiEvent::NoteOn { key: first, velocity })
+                }
+            },
+            0xa0 | 0xe0 => {
+                reader.u8()?;
+                None
+            },
+            0xb0 => {
+                let value = reader.u8()?;
+                Some(MidiEvent::Control { number: first, value })
+            },
+            0xc0 => Some(MidiEvent::Program(first)),
+            _ => None, // 0xd0 (チャンネルプレッシャー) はデータが１バイト
+        };
+        if let Some(event) = event {
+            track.events.push((tick, channel, event));
+        }
+    }
+    Ok(track)
+}
+
+impl Smf {
+    fn tick_to_frame(&self, tick: u32) -> f64 {
+        if self.division & 0x8000 != 0 {
+            let fps = -((self.division >> 8) as i8) as f64;
+            let ticks_per_frame = (self.division & 0xff) as f64;
+            return tick as f64 / (fps * ticks_per_frame) * FRAMES_PER_SECOND;
+        }
+        let ticks_per_quarter = self.division as f64;
+        let mut usec = 0.0;
+        let mut last_tick = 0u32;
+        let mut tempo = 500_000u32;
+        for &(t, new_tempo) in self.tempo_map.iter() {
+            if t >= tick {
+                break;
+            }
+            usec += (t - last_tick) as f64 * tempo as f64 / ticks_per_quarter;
+            last_tick = t;
+            tempo = new_tempo;
+        }
+        usec += (tick - last_tick) as f64 * tempo as f64 / ticks_per_quarter;
+        usec / 1_000_000.0 * FRAMES_PER_SECOND
+    }
+}
+
+struct Note {
+    start: u32,
+    end: u32,
+    midi_key: u8,
+    wave: u8,
+    level: i32,
+}
+
+fn collect_notes(events: &[(u32, MidiEvent)], is_drum: bool, options: &SmfImportOptions, warnings: &mut Vec<String>, source_name: &str) -> Vec<Note> {
+    let mut notes: Vec<Note> = Vec::new();
+    let mut sounding: Option<Note> = None;
+    let mut wave = if is_drum { DRUM_WAVE } else { options.wave_map[0] };
+    let mut volume = 127;
+    let mut expression = 127;
+    let mut overlaps = 0;
+    for &(tick, event) in events.iter() {
+        match event {
+            MidiEvent::NoteOn { key, velocity } => {
+                if let Some(mut note) = sounding.take() {
+                    overlaps += 1;
+                    note.end = tick;
+                    notes.push(note);
+                }
+                let level = 15 * velocity as i32 * volume * expression / (127 * 127 * 127);
+                sounding = Some(Note { start: tick, end: tick, midi_key: key, wave, level });
+            },
+            MidiEvent::NoteOff { key } => {
+                if let Some(note) = &sounding {
+                    if note.midi_key == key {
+                        let mut note = sounding.take().unwrap();
+                        note.end = tick;
+                        notes.push(note);
+                    }
+                }
+            },
+            MidiEvent::Program(program) if !is_drum => wave = options.wave_map[program as usize],
+            MidiEvent::Control { number: 7, value } => volume = value as i32,
+            MidiEvent::Control { number: 11, value } => expression = value as i32,
+            _ => (),
+        }
+    }
+    if let Some(mut note) = sounding {
+        note.end = events.last().map(|e| e.0).unwrap_or(note.start);
+        notes.push(note);
+    }
+    if overlaps > 0 {
+        warnings.push(format!("{}: {} overlapping notes (polyphony) were cut to play one at a time", source_name, overlaps));
+    }
+    notes
+}
+
+fn steady_envelopes() -> Vec<(i32, u8)> {
+    let mut list = Vec::new();
+    for (idx, env) in ENVELOPE_TBL.iter().enumerate() {
+        if env.len() == 2 && env[1] == 0x10 && env[0] > 0 {
+            list.push((env[0], idx as u8));
+        }
+    }
+    list
+}
+
+fn envelope_for_level(level: i32, steady: &[(i32, u8)]) -> u8 {
+    let mut best = steady[0];
+    for &s in steady.iter() {
+        if (s.0 - level).abs() < (best.0 - level).abs() {
+            best = s;
+        }
+    }
+    best.1
+}
+
+fn key_and_octave(midi_key: u8) -> (u8, i32) {
+    let diff = MIDI_NOTE_OF_KEY0_OCT0 - midi_key as i32;
+    let oct = (diff + 11).div_euclid(12);
+    let key = midi_key as i32 - MIDI_NOTE_OF_KEY0_OCT0 + 12 * oct;
+    (key as u8, oct)
+}
+
+fn emit_length(bytes: &mut Vec<u8>, code: u8, mut len: u32) -> bool {
+    let split = len > 0xff;
+    while len > 0 {
+        let l = len.min(0xff);
+        bytes.push(code);
+        bytes.push(l as u8);
+        len -= l;
+    }
+    split
+}
+
+pub fn import_smf(data: &[u8], options: &SmfImportOptions) -> Result<ImportedScore, String> {
+    if options.unit_frames == 0 {
+        return Err("unit frames must be 1 or more".to_string());
+    }
+    let smf = parse_smf(data)?;
+    let mut result = ImportedScore::default();
+    let mut source_events: Vec<Vec<(u32, MidiEvent)>> = Vec::new();
+    for (track_no, track) in smf.tracks.iter().enumerate() {
+        for channel in 0..16u8 {
+            let events: Vec<(u32, MidiEvent)> = track.events.iter()
+                .filter(|e| e.1 == channel)
+                .map(|e| (e.0, e.2))
+                .collect();
+            let num_notes = events.iter().filter(|e| matches!(e.1, MidiEvent::NoteOn { .. })).count();
+            if num_notes == 0 {
+                continue;
+            }
+            result.sources.push(SmfSource { track: track_no, channel, name: track.name.clone(), num_notes });
+            source_events.push(events);
+        }
+    }
+    let selected = match &options.sources {
+        Some(list) => list.clone(),
+        None => (0..result.sources.len().min(MAX_PARTS)).collect(),
+    };
+    if selected.len() > MAX_PARTS {
+        return Err(format!("{} sources selected, but a sound has at most {} parts", selected.len(), MAX_PARTS));
+    }
+    if options.sources.is_none() && result.sources.len() > MAX_PARTS {
+        result.warnings.push(format!("{} sources found; only the first {} are imported", result.sources.len(), MAX_PARTS));
+    }
+    if smf.format == 0 && smf.tracks.len() > 1 {
+        result.warnings.push("format 0 file with more than one track".to_string());
+    }
+    let steady = steady_envelopes();
+    let unit = options.unit_frames as f64;
+    for &source_no in selected.iter() {
+        let source = result.sources.get(source_no).ok_or(format!("source {} does not exist", source_no))?;
+        let source_name = format!("track {} ch {}", source.track, source.channel + 1);
+        let notes = collect_notes(&source_events[source_no], source.channel == DRUM_CHANNEL, options, &mut result.warnings, &source_name);
+
+        let mut bytes = Vec::new();
+        let mut cursor = 0u32;
+        let mut wave = None;
+        let mut envelope = None;
+        let mut out_of_range = 0;
+        let mut dropped = 0;
+        let mut split = 0;
+        // 既存の譜面と同じく 0xf0, 0xf1, 0xf2 の順で先頭に置く
+        if let Some(first) = notes.first() {
+            let env = options.fixed_envelope.unwrap_or_else(|| envelope_for_level(first.level, &steady));
+            bytes.extend_from_slice(&[0xf0, first.wave << 4, 0xf1, env]);
+            wave = Some(first.wave);
+            envelope = Some(env);
+        }
+        bytes.extend_from_slice(&[0xf2, options.unit_frames]);
+        let quantized: Vec<(u32, u32)> = notes.iter().map(|n| (
+            (smf.tick_to_frame(n.start) / unit).round() as u32,
+            (smf.tick_to_frame(n.end) / unit).round() as u32,
+        )).collect();
+        for (i, note) in notes.iter().enumerate() {
+            let start = quantized[i].0.max(cursor);
+            let mut end = quantized[i].1;
+            if let Some(next) = quantized.get(i + 1) {
+                end = end.min(next.0);
+            }
+            if end <= start {
+                dropped += 1;
+                continue;
+            }
+            if start > cursor {
+                emit_length(&mut bytes, REST, start - cursor);
+            }
+            if wave != Some(note.wave) {
+                bytes.extend_from_slice(&[0xf0, note.wave << 4]);
+                wave = Some(note.wave);
+            }
+            let env = options.fixed_envelope.unwrap_or_else(|| envelope_for_level(note.level, &steady));
+            if envelope != Some(env) {
+                bytes.extend_from_slice(&[0xf1, env]);
+                envelope = Some(env);
+            }
+            let (key, mut oct) = key_and_octave(note.midi_key);
+            if !(0..=MAX_OCTAVE).contains(&oct) {
+                out_of_range += 1;
+                oct = oct.clamp(0, MAX_OCTAVE);
+            }
+            if emit_length(&mut bytes, (key << 4) | oct as u8, end - start) {
+                split += 1;
+            }
+            cursor = end;
+        }
+        bytes.push(0xf3);
+        if out_of_range > 0 {
+            result.warnings.push(format!("{}: {} notes outside the ScaleSet range (octave 0..{}) were folded into it", source_name, out_of_range, MAX_OCTAVE));
+        }
+        if dropped > 0 {
+            result.warnings.push(format!("{}: {} notes shorter than the unit of {} frames were dropped", source_name, dropped, options.unit_frames));
+        }
+        if split > 0 {
+            result.warnings.push(format!("{}: {} notes longer than 255 units were split (the envelope restarts)", source_name, split));
+        }
+        result.parts.push(ImportedPart { source: source_no, bytes });
+    }
+    Ok(result)
+}
+
+#[allow(dead_code)]
+impl ImportedScore {
+    pub fn to_rust_source(&self, name: &str) -> String {
+        let mut s = String::new();
+        for (part_no, part) in self.parts.iter().enumerate() {
+            s.push_str(&format!("const {}_{:02}: &[u8] = &[\n", name, part_no));
+            for line in part.bytes.chunks(16) {
+                let items: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+                s.push_str(&format!("    {},\n", items.join(", ")));
+            }
+            s.push_str("];\n");
+        }
+        s.push_str(&format!("const {}: &[(&[u8], &ScaleSet)] = &[\n", name));
+        for part_no in 0..self.parts.len() {
+            s.push_str(&format!("    ({}_{:02}, &SCALE_0),\n", name, part_no));
+        }
+        s.push_str("];\n");
+        s
+    }
+
+    pub fn to_hex(&self, sound_no: usize) -> String {
+        let mut s = String::new();
+        for (part_no, part) in self.parts.iter().enumerate() {
+            let source = &self.sources[part.source];
+            s.push_str(&format!("; track {} ch {} {}\n", source.track, source.channel + 1, source.name));
+            s.push_str(&format!("[{:02x}.{}]\n", sound_no, part_no));
+            let hex: Vec<String> = part.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            for line in hex.chunks(16) {
+                s.push_str(&line.join(" "));
+                s.push('\n');
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MThd (division 96) と MTrk １つだけの SMF
+    fn smf(format: u16, track: &[u8]) -> Vec<u8> {
+        let mut data = b"MThd".to_vec();
+        data.extend_from_slice(&6u32.to_be_bytes());
+        data.extend_from_slice(&format.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&96u16.to_be_bytes());
+        data.extend_from_slice(b"MTrk");
+        data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        data.extend_from_slice(track);
+        data
+    }
+
+    #[test]
+    fn key_and_octave_of_midi_notes() {
+        assert_eq!(key_and_octave(105), (0, 0));
+        assert_eq!(key_and_octave(116), (11, 0));
+        assert_eq!(key_and_octave(104), (11, 1));
+        assert_eq!(key_and_octave(93), (0, 1));
+        assert_eq!(key_and_octave(69), (0, 3));
+        // ScaleSet の範囲外は呼び出し側で丸める
+        assert_eq!(key_and_octave(117), (0, -1));
+    }
+
+    #[test]
+    fn emit_length_splits_long_notes() {
+        let mut bytes = Vec::new();
+        assert!(!emit_length(&mut bytes, 0x03, 0xff));
+        assert_eq!(bytes, [0x03, 0xff]);
+        let mut bytes = Vec::new();
+        assert!(emit_length(&mut bytes, REST, 300));
+        assert_eq!(bytes, [REST, 0xff, REST, 0x2d]);
+        let mut bytes = Vec::new();
+        assert!(emit_length(&mut bytes, 0x03, 0x1fe));
+        assert_eq!(bytes, [0x03, 0xff, 0x03, 0xff]);
+    }
+
+    #[test]
+    fn import_format0_file() {
+        let track = [
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // テンポ 120
+            0x00, 0xc0, 0x00,                         // プログラム 0
+            0x00, 0x90, 0x45, 0x64,                   // A4 (69) を４分音符 (30 フレーム)
+            0x60, 0x80, 0x45, 0x40,
+            0x30, 0x90, 0x47, 0x64,                   // ８分休符のあと B4 (71) を８分音符 (running status)
+            0x30, 0x47, 0x00,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let options = SmfImportOptions { fixed_envelope: Some(3), ..Default::default() };
+        let score = import_smf(&smf(0, &track), &options).unwrap();
+        assert_eq!(score.sources.len(), 1);
+        assert_eq!((score.sources[0].track, score.sources[0].channel, score.sources[0].num_notes), (0, 0, 2));
+        assert_eq!(score.parts.len(), 1);
+        assert_eq!(score.parts[0].bytes, [0xf0, 0x40, 0xf1, 0x03, 0xf2, 0x02, 0x03, 0x0f, REST, 0x08, 0x23, 0x07, 0xf3]);
+        assert!(score.warnings.is_empty(), "{:?}", score.warnings);
+    }
+
+    #[test]
+    fn reject_tick_overflow() {
+        // 最大のデルタタイムを持つ空のテキストイベントを並べる
+        let track: Vec<u8> = [0xff, 0xff, 0xff, 0x7f, 0xff, 0x01, 0x00].repeat(17);
+        let error = import_smf(&smf(0, &track), &SmfImportOptions::default()).unwrap_err();
+        assert!(error.contains("overflows"), "{}", error);
+    }
+}
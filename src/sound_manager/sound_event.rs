@@ -0,0 +1,62 @@
+use std::fmt;
+use super::voice_allocator::PartId;
+
+// run で起きたことの通知 (呼び出し側が毎フレーム drain_events で取り出す)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoundEvent {
+    // 演奏を始めた (繰り返し演奏では回ごと)
+    Started { sound_no: usize },
+    // 音符を鳴らし始めた (ch は出力したチャンネル、鳴らなかったときは None)
+    NoteOn { part: PartId, ch: Option<usize>, key: u8, oct: u8, frames: usize },
+    // 波形 (0xf0) とエンベロープ (0xf1) の変更
+    WaveForm { part: PartId, wave_form: usize },
+    Envelope { part: PartId, envelope: usize },
+    // その他の設定命令 (0xf2, 0xf9..=0xfc) と値
+    Control { part: PartId, code: u8, value: i32 },
+    // 最後の回を演奏し終えた
+    Finished { sound_no: usize },
+    // 途中で止められた (stop やフェードアウトの終わり)
+    Stopped { sound_no: usize },
+    // 鳴っていたパートがチャンネルを他のパートに取られた
+    Stolen { part: PartId, ch: usize, by: Option<PartId> },
+}
+
+// 取り出されずに溜まる通知の上限 (超えたら古いものから捨てる)
+pub const MAX_QUEUED_EVENTS: usize = 4096;
+
+impl fmt::Display for SoundEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SoundEvent::Started { sound_no } => write!(f, "{:02x} start", sound_no),
+            SoundEvent::NoteOn { part, ch, key, oct, frames } => {
+                write!(f, "{:02x}.{}.key:{} oct:{} len:{}", part.sound_no, part.part_no, key, oct, frames)?;
+                match ch {
+                    Some(ch) => write!(f, " ch:{}", ch),
+                    None => write!(f, " masked"),
+                }
+            },
+            SoundEvent::WaveForm { part, wave_form } => write!(f, "{:02x}.{}.wave form:{}", part.sound_no, part.part_no, wave_form),
+            SoundEvent::Envelope { part, envelope } => write!(f, "{:02x}.{}.envelope:{}", part.sound_no, part.part_no, envelope),
+            SoundEvent::Control { part, code, value } => {
+                let name = match code {
+                    0xf2 => "unit",
+                    0xf9 => "vibrato",
+                    0xfa => "portamento",
+                    0xfb => "sweep",
+                    0xfc => "pan",
+                    _ => "control",
+                };
+                write!(f, "{:02x}.{}.{}:{}", part.sound_no, part.part_no, name, value)
+            },
+            SoundEvent::Finished { sound_no } => write!(f, "{:02x} end mark", sound_no),
+            SoundEvent::Stopped { sound_no } => write!(f, "{:02x} stopped", sound_no),
+            SoundEvent::Stolen { part, ch, by } => {
+                write!(f, "{:02x}.{} lost ch {}", part.sound_no, part.part_no, ch)?;
+                match by {
+                    Some(by) => write!(f, " to {:02x}.{}", by.sound_no, by.part_no),
+                    None => Ok(()),
+                }
+            },
+        }
+    }
+}
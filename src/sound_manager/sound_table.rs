@@ -0,0 +1,631 @@
+use std::fs;
+
+use super::bytecode::*;
+use super::sound_index::*;
+use super::sound_score::*;
+use super::PartScore;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoundType {
+    OneShot,
+    Retriggerable,
+    // 止めるまで繰り返す (要求の回数によらない)
+    Looping,
+    // 演奏を始めるとき、チャンネルが重なる他のサウンドを止める
+    Exclusive,
+    // チャンネルが重なる他のサウンドが演奏中なら、終わるまで演奏を始めずに待つ
+    Queued,
+}
+
+impl SoundType {
+    fn name(&self) -> &'static str {
+        match self {
+            SoundType::OneShot => "oneshot",
+            SoundType::Retriggerable => "retriggerable",
+            SoundType::Looping => "looping",
+            SoundType::Exclusive => "exclusive",
+            SoundType::Queued => "queued",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "oneshot" => Some(SoundType::OneShot),
+            "retriggerable" => Some(SoundType::Retriggerable),
+            "looping" => Some(SoundType::Looping),
+            "exclusive" => Some(SoundType::Exclusive),
+            "queued" => Some(SoundType::Queued),
+            _ => None,
+        }
+    }
+}
+
+// パートの演奏状態を置く作業領域 (元のドライバの RAM 上の領域に相当し、名前はそのアドレス)
+// 同じ作業領域を使うサウンドは演奏状態を共有する
+#[derive(Debug, Clone)]
+pub struct WorkArea {
+    pub name: String,
+    pub len: usize,
+}
+
+// 演奏を終えたら delay フレーム後に next を times 回演奏する
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FollowUp {
+    pub next: usize,
+    pub times: usize,
+    pub delay: usize,
+}
+
+// 効果音の間、音楽の音量を下げる (ダッキング) ための分類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoundCategory {
+    Music,
+    Effect,
+}
+
+impl SoundCategory {
+    fn name(&self) -> &'static str {
+        match self {
+            SoundCategory::Music => "music",
+            SoundCategory::Effect => "effect",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "music" => Some(SoundCategory::Music),
+            "effect" => Some(SoundCategory::Effect),
+            _ => None,
+        }
+    }
+}
+
+// 効果音を演奏している間、音楽の音量を steps 段下げる
+// attack フレームかけて下げ、効果音が終わったら release フレームかけて戻す
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ducking {
+    pub steps: i32,
+    pub attack: usize,
+    pub release: usize,
+}
+
+// 音量の段数 (エンベロープの最大値)
+pub const MAX_DUCKING_STEPS: i32 = 15;
+
+// Retriggerable を演奏中に要求し直したときの扱い
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetriggerMode {
+    // 先頭から演奏し直す
+    Restart,
+    // 演奏中の要求は捨てる
+    Ignore,
+    // パートが演奏を始めてから読み進めたバイト数が offset に達していれば演奏し直し、達していなければ捨てる
+    // (ループやジャンプ、呼び出しで戻った分は引かない)
+    After { part_no: usize, offset: usize },
+    // 演奏中の要求を１つだけ覚えておき、演奏を終えたら続けて演奏する
+    Queue,
+}
+
+// min_interval は演奏し直しを受け付けるまでに最低限演奏するフレーム数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetriggerPolicy {
+    pub min_interval: usize,
+    pub mode: RetriggerMode,
+}
+
+impl RetriggerMode {
+    fn to_text(self) -> String {
+        match self {
+            RetriggerMode::Restart => "restart".to_string(),
+            RetriggerMode::Ignore => "ignore".to_string(),
+            RetriggerMode::After { part_no, offset } => format!("after {} {:x}", part_no, offset),
+            RetriggerMode::Queue => "queue".to_string(),
+        }
+    }
+
+    fn parse(fields: &[&str], line_no: usize) -> Result<Self, String> {
+        match fields {
+            ["restart"] => Ok(RetriggerMode::Restart),
+            ["ignore"] => Ok(RetriggerMode::Ignore),
+            ["after", part_no, offset] => Ok(RetriggerMode::After { part_no: parse_dec(part_no, line_no)?, offset: parse_hex(offset, line_no)? }),
+            ["queue"] => Ok(RetriggerMode::Queue),
+            _ => Err(format!("line {}: invalid retrigger mode {} (restart, ignore, after PART OFFSET or queue)", line_no, fields.join(" "))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SoundInfo<'a> {
+    pub name: String,
+    pub sound_type: SoundType,
+    pub work_area: usize,
+    pub start_ch: usize,
+    pub num_ch: usize,
+    pub priority: u8,
+    pub score: Vec<PartScore<'a>>,
+    pub follow_up: Option<FollowUp>,
+    // ループ演奏で戻るパートごとの位置 (バイト単位、無いパートは先頭)
+    pub loop_points: Vec<usize>,
+    // パートごとの定位 (-1: 左、0: 中央、1: 右、None は指定なし)。譜面の 0xfc が優先する
+    pub pans: Vec<Option<i32>>,
+    // １小節のフレーム数 (小節単位で頭出しするときに使う)
+    pub bar_frames: Option<usize>,
+    // 演奏し直しの制限 (Retriggerable のみ、None は毎回演奏し直す)
+    pub retrigger: Option<RetriggerPolicy>,
+    pub category: Option<SoundCategory>,
+}
+
+#[allow(dead_code)]
+impl SoundInfo<'_> {
+    pub fn default_pan(&self, part_no: usize) -> Option<i32> {
+        self.pans.get(part_no).copied().flatten()
+    }
+
+    // 開始チャンネルからのチャンネルの範囲が重なるか (Exclusive と Queued が使う)
+    pub fn shares_channels(&self, other: &SoundInfo) -> bool {
+        self.start_ch < other.start_ch + other.num_ch && other.start_ch < self.start_ch + self.num_ch
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SoundTable<'a> {
+    pub work_areas: Vec<WorkArea>,
+    pub sounds: Vec<SoundInfo<'a>>,
+    pub ducking: Option<Ducking>,
+}
+
+const ORIGINAL_WORK_AREAS: [(&str, usize); 12] = [
+    ("0100", 7), ("0169", 2), ("0187", 4), ("01c3", 4), ("01ff", 4), ("023b", 4),
+    ("0277", 2), ("0295", 2), ("02b3", 1), ("02c2", 8), ("033a", 4), ("0376", 3),
+];
+
+// (種類, 作業領域, 開始チャンネル)
+const ORIGINAL_SOUNDS: [(SoundType, usize, usize); NUM_SOUND_IDX] = [
+    (SoundType::OneShot      ,  9, 0), // FloorStart
+    (SoundType::OneShot      ,  9, 0), // FloorFinish
+    (SoundType::OneShot      ,  9, 0), // FinalFloorFinish
+    (SoundType::OneShot      ,  9, 0), // Zapped
+    (SoundType::OneShot      ,  0, 0), // IshtarFloor
+    (SoundType::OneShot      ,  0, 0), // NormalFloor
+    (SoundType::OneShot      ,  0, 0), // DragonFloor
+    (SoundType::OneShot      ,  0, 0), // DruagaFloor
+    (SoundType::OneShot      ,  4, 0), // Chime
+    (SoundType::Retriggerable,  1, 4), // SlimeMove
+    (SoundType::OneShot      ,  7, 4), // Spell
+    (SoundType::Retriggerable,  6, 4), // Fire
+    (SoundType::Retriggerable,  2, 3), // BreakWall
+    (SoundType::OneShot      , 11, 3), // DragonFlame
+    (SoundType::Retriggerable,  3, 5), // Sword1
+    (SoundType::Retriggerable,  3, 5), // Sword2
+    (SoundType::OneShot      ,  3, 5), // Sword3
+    (SoundType::Retriggerable,  3, 5), // Sword4
+    (SoundType::Retriggerable,  3, 4), // CutMonster
+    (SoundType::Retriggerable,  3, 5), // NoUse1
+    (SoundType::OneShot      ,  3, 5), // BlockSpell
+    (SoundType::OneShot      ,  4, 0), // OpenDoor
+    (SoundType::OneShot      ,  4, 0), // GetKey
+    (SoundType::OneShot      ,  4, 0), // GetItem
+    (SoundType::OneShot      ,  0, 0), // NoUse2
+    (SoundType::OneShot      ,  8, 7), // GilWalk
+    (SoundType::OneShot      , 10, 4), // CreditUpPost
+    (SoundType::OneShot      ,  9, 0), // Miss
+    (SoundType::OneShot      ,  9, 0), // GameOver
+    (SoundType::OneShot      ,  9, 0), // NameEntry
+    (SoundType::OneShot      , 10, 4), // Extend
+    (SoundType::OneShot      ,  5, 4), // CreditUpPre
+];
+
+// 元のサウンドのうち音楽に分類するもの (BGM とジングル)
+const ORIGINAL_MUSIC: [usize; 11] = [
+    SoundIdx::FloorStart as usize,
+    SoundIdx::FloorFinish as usize,
+    SoundIdx::FinalFloorFinish as usize,
+    SoundIdx::Zapped as usize,
+    SoundIdx::IshtarFloor as usize,
+    SoundIdx::NormalFloor as usize,
+    SoundIdx::DragonFloor as usize,
+    SoundIdx::DruagaFloor as usize,
+    SoundIdx::Miss as usize,
+    SoundIdx::GameOver as usize,
+    SoundIdx::NameEntry as usize,
+];
+
+fn parse_hex(s: &str, line_no: usize) -> Result<usize, String> {
+    usize::from_str_radix(s, 16).map_err(|_| format!("line {}: invalid number {}", line_no, s))
+}
+
+fn pan_name(pan: Option<i32>) -> &'static str {
+    match pan {
+        Some(pan) if pan < 0 => "L",
+        Some(0) => "C",
+        Some(_) => "R",
+        None => "-",
+    }
+}
+
+fn parse_pan(s: &str, line_no: usize) -> Result<Option<i32>, String> {
+    match s {
+        "L" => Ok(Some(-1)),
+        "C" => Ok(Some(0)),
+        "R" => Ok(Some(1)),
+        "-" => Ok(None),
+        _ => Err(format!("line {}: invalid pan {} (L, C, R or -)", line_no, s)),
+    }
+}
+
+fn parse_dec(s: &str, line_no: usize) -> Result<usize, String> {
+    s.parse().map_err(|_| format!("line {}: invalid number {}", line_no, s))
+}
+
+pub fn validate_loop_points(score: &[PartScore], loop_points: &[usize]) -> Result<(), String> {
+    if loop_points.len() > score.len() {
+        return Err(format!("{} loop points for {} parts", loop_points.len(), score.len()));
+    }
+    for (part_no, (&point, part)) in loop_points.iter().zip(score.iter()).enumerate() {
+        if !is_instruction_start(part.0, point) {
+            return Err(format!("part {}: loop point +{:x} is not a command before the end mark", part_no, point));
+        }
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+impl<'a> SoundTable<'a> {
+    // 元のドライバと同じ配置
+    // 元のドライバではサウンド番号の大きいものが後で処理されて上書きするので、番号をそのまま優先度とする
+    pub fn original() -> Self {
+        let work_areas = ORIGINAL_WORK_AREAS.iter()
+            .map(|&(name, len)| WorkArea { name: name.to_string(), len })
+            .collect();
+        let mut sounds: Vec<SoundInfo> = ORIGINAL_SOUNDS.iter().enumerate().map(|(idx, &(sound_type, work_area, start_ch))| {
+            SoundInfo {
+                name: format!("{:?}", SoundIdx::from(idx as i32)),
+                sound_type,
+                work_area,
+                start_ch,
+                num_ch: MUSIC_SCORES[idx].len(),
+                priority: idx as u8,
+                score: MUSIC_SCORES[idx].to_vec(),
+                follow_up: None,
+                loop_points: Vec::new(),
+                pans: Vec::new(),
+                bar_frames: None,
+                retrigger: None,
+                category: Some(if ORIGINAL_MUSIC.contains(&idx) { SoundCategory::Music } else { SoundCategory::Effect }),
+            }
+        }).collect();
+        // クレジット音は要求回数だけ鳴らし終えてから後半を鳴らす
+        sounds[SoundIdx::CreditUpPre as usize].follow_up = Some(FollowUp {
+            next: SoundIdx::CreditUpPost as usize,
+            times: 1,
+            delay: 0,
+        });
+        Self { work_areas, sounds, ducking: None }
+    }
+
+    // 書式: "area 名前 パート数" の行で作業領域を、
+    // "番号 名前 種類 作業領域名 開始ch チャンネル数 優先度 譜面番号" の行でサウンドを並べる
+    // 番号・譜面番号は 16 進で、譜面番号は scores (MUSIC_SCORES や ROM から読んだ譜面) の添字
+    // "follow 番号 次の番号 回数 遅延フレーム数" の行で演奏終了後に続けて鳴らすサウンドを指定する
+    // "loop 番号 位置 位置 .." の行でループ演奏時にパートが戻る位置 (16 進のバイト位置) を指定する
+    // "pan 番号 L C R - .." の行でパートごとの定位を指定する (- は指定なし)
+    // "bar 番号 フレーム数" の行で１小節の長さを指定する
+    // "retrigger 番号 最短間隔フレーム数 restart|ignore|after パート バイト数|queue" の行で演奏し直しの制限を指定する
+    // (after のバイト数は 16 進で、パートが演奏を始めてから読み進めたバイト数。譜面の位置ではないので命令の境目でなくてよい)
+    // "category 番号 music|effect" の行でダッキングの分類を、"duck 段数 attack release" の行でダッキングを指定する
+    pub fn parse(text: &str, scores: &[Vec<PartScore<'a>>]) -> Result<Self, String> {
+        let mut table = Self { work_areas: Vec::new(), sounds: Vec::new(), ducking: None };
+        for (n, line) in text.lines().enumerate() {
+            let line_no = n + 1;
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if fields[0] == "area" {
+                if fields.len() != 3 {
+                    return Err(format!("line {}: expected \"area NAME PARTS\"", line_no));
+                }
+                table.work_areas.push(WorkArea { name: fields[1].to_string(), len: parse_dec(fields[2], line_no)? });
+                continue;
+            }
+            if fields[0] == "follow" {
+                if fields.len() != 5 {
+                    return Err(format!("line {}: expected \"follow NO NEXT TIMES DELAY\"", line_no));
+                }
+                let sound_no = parse_hex(fields[1], line_no)?;
+                let follow_up = FollowUp {
+                    next: parse_hex(fields[2], line_no)?,
+                    times: parse_dec(fields[3], line_no)?,
+                    delay: parse_dec(fields[4], line_no)?,
+                };
+                table.sounds.get_mut(sound_no).ok_or(format!("line {}: sound {:02x} is not defined yet", line_no, sound_no))?
+                    .follow_up = Some(follow_up);
+                continue;
+            }
+            if fields[0] == "loop" {
+                if fields.len() < 3 {
+                    return Err(format!("line {}: expected \"loop NO OFFSET ..\"", line_no));
+                }
+                let sound_no = parse_hex(fields[1], line_no)?;
+                let mut loop_points = Vec::new();
+                for offset in fields[2..].iter() {
+                    loop_points.push(parse_hex(offset, line_no)?);
+                }
+                table.sounds.get_mut(sound_no).ok_or(format!("line {}: sound {:02x} is not defined yet", line_no, sound_no))?
+                    .loop_points = loop_points;
+                continue;
+            }
+            if fields[0] == "pan" {
+                if fields.len() < 3 {
+                    return Err(format!("line {}: expected \"pan NO L|C|R|- ..\"", line_no));
+                }
+                let sound_no = parse_hex(fields[1], line_no)?;
+                let mut pans = Vec::new();
+                for pan in fields[2..].iter() {
+                    pans.push(parse_pan(pan, line_no)?);
+                }
+                table.sounds.get_mut(sound_no).ok_or(format!("line {}: sound {:02x} is not defined yet", line_no, sound_no))?
+                    .pans = pans;
+                continue;
+            }
+            if fields[0] == "bar" {
+                if fields.len() != 3 {
+                    return Err(format!("line {}: expected \"bar NO FRAMES\"", line_no));
+                }
+                let sound_no = parse_hex(fields[1], line_no)?;
+                let bar_frames = parse_dec(fields[2], line_no)?;
+                table.sounds.get_mut(sound_no).ok_or(format!("line {}: sound {:02x} is not defined yet", line_no, sound_no))?
+                    .bar_frames = Some(bar_frames);
+                continue;
+            }
+            if fields[0] == "retrigger" {
+                if fields.len() < 4 {
+                    return Err(format!("line {}: expected \"retrigger NO INTERVAL MODE ..\"", line_no));
+                }
+                let sound_no = parse_hex(fields[1], line_no)?;
+                let policy = RetriggerPolicy {
+                    min_interval: parse_dec(fields[2], line_no)?,
+                    mode: RetriggerMode::parse(&fields[3..], line_no)?,
+                };
+                table.sounds.get_mut(sound_no).ok_or(format!("line {}: sound {:02x} is not defined yet", line_no, sound_no))?
+                    .retrigger = Some(policy);
+                continue;
+            }
+            if fields[0] == "category" {
+                if fields.len() != 3 {
+                    return Err(format!("line {}: expected \"category NO music|effect\"", line_no));
+                }
+                let sound_no = parse_hex(fields[1], line_no)?;
+                let category = SoundCategory::parse(fields[2]).ok_or(format!("line {}: unknown category {}", line_no, fields[2]))?;
+                table.sounds.get_mut(sound_no).ok_or(format!("line {}: sound {:02x} is not defined yet", line_no, sound_no))?
+                    .category = Some(category);
+                continue;
+            }
+            if fields[0] == "duck" {
+                if fields.len() != 4 {
+                    return Err(format!("line {}: expected \"duck STEPS ATTACK RELEASE\"", line_no));
+                }
+                table.ducking = Some(Ducking {
+                    steps: parse_dec(fields[1], line_no)? as i32,
+                    attack: parse_dec(fields[2], line_no)?,
+                    release: parse_dec(fields[3], line_no)?,
+                });
+                continue;
+            }
+            if fields.len() != 8 {
+                return Err(format!("line {}: expected \"NO NAME TYPE AREA START_CH NUM_CH PRIORITY SCORE\"", line_no));
+            }
+            if parse_hex(fields[0], line_no)? != table.sounds.len() {
+                return Err(format!("line {}: sound {} is out of order", line_no, fields[0]));
+            }
+            let sound_type = SoundType::parse(fields[2]).ok_or(format!("line {}: unknown sound type {}", line_no, fields[2]))?;
+            let work_area = table.work_areas.iter().position(|area| area.name == fields[3])
+                .ok_or(format!("line {}: unknown work area {}", line_no, fields[3]))?;
+            let score_no = parse_hex(fields[7], line_no)?;
+            let score = scores.get(score_no).ok_or(format!("line {}: score {:02x} does not exist", line_no, score_no))?;
+            table.sounds.push(SoundInfo {
+                name: fields[1].to_string(),
+                sound_type,
+                work_area,
+                start_ch: parse_dec(fields[4], line_no)?,
+                num_ch: parse_dec(fields[5], line_no)?,
+                priority: u8::try_from(parse_dec(fields[6], line_no)?)
+                    .map_err(|_| format!("line {}: priority {} is outside 0..=255", line_no, fields[6]))?,
+                score: score.clone(),
+                follow_up: None,
+                loop_points: Vec::new(),
+                pans: Vec::new(),
+                bar_frames: None,
+                retrigger: None,
+                category: None,
+            });
+        }
+        table.validate()?;
+        Ok(table)
+    }
+
+    pub fn load(path: &str, scores: &[Vec<PartScore<'a>>]) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&text, scores).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // parse で読める書式で書き出す (譜面番号はサウンド番号と同じものとする)
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for area in self.work_areas.iter() {
+            text += &format!("area {} {}\n", area.name, area.len);
+        }
+        for (idx, sound) in self.sounds.iter().enumerate() {
+            text += &format!("{:02x} {:<16} {:<13} {} {} {} {:3} {:02x}\n",
+                idx, sound.name, sound.sound_type.name(), self.work_areas[sound.work_area].name,
+                sound.start_ch, sound.num_ch, sound.priority, idx);
+        }
+        for (idx, sound) in self.sounds.iter().enumerate() {
+            if let Some(follow_up) = sound.follow_up {
+                text += &format!("follow {:02x} {:02x} {} {}\n", idx, follow_up.next, follow_up.times, follow_up.delay);
+            }
+            if !sound.loop_points.is_empty() {
+                let points: Vec<String> = sound.loop_points.iter().map(|point| format!("{:x}", point)).collect();
+                text += &format!("loop {:02x} {}\n", idx, points.join(" "));
+            }
+            if !sound.pans.is_empty() {
+                let pans: Vec<&str> = sound.pans.iter().map(|&pan| pan_name(pan)).collect();
+                text += &format!("pan {:02x} {}\n", idx, pans.join(" "));
+            }
+            if let Some(bar_frames) = sound.bar_frames {
+                text += &format!("bar {:02x} {}\n", idx, bar_frames);
+            }
+            if let Some(category) = sound.category {
+                text += &format!("category {:02x} {}\n", idx, category.name());
+            }
+            if let Some(policy) = sound.retrigger {
+                text += &format!("retrigger {:02x} {} {}\n", idx, policy.min_interval, policy.mode.to_text());
+            }
+        }
+        if let Some(ducking) = self.ducking {
+            text += &format!("duck {} {} {}\n", ducking.steps, ducking.attack, ducking.release);
+        }
+        text
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sounds.is_empty() {
+            return Err("the sound table has no sounds".to_string());
+        }
+        if let Some(ducking) = self.ducking {
+            if !(0..=MAX_DUCKING_STEPS).contains(&ducking.steps) {
+                return Err(format!("ducking by {} steps is outside 0..={}", ducking.steps, MAX_DUCKING_STEPS));
+            }
+        }
+        for (idx, sound) in self.sounds.iter().enumerate() {
+            let name = format!("sound {:02x} ({})", idx, sound.name);
+            let area = self.work_areas.get(sound.work_area).ok_or(format!("{}: work area {} does not exist", name, sound.work_area))?;
+            if sound.num_ch == 0 || sound.start_ch + sound.num_ch > 8 {
+                return Err(format!("{}: channels {}..{} are outside 0..8", name, sound.start_ch, sound.start_ch + sound.num_ch));
+            }
+            if sound.score.len() > sound.num_ch {
+                return Err(format!("{}: {} parts do not fit in {} channels", name, sound.score.len(), sound.num_ch));
+            }
+            if sound.score.len() > area.len {
+                return Err(format!("{}: {} parts do not fit in work area {} ({} parts)", name, sound.score.len(), area.name, area.len));
+            }
+            if let Some(follow_up) = sound.follow_up {
+                if follow_up.next >= self.sounds.len() || follow_up.times == 0 {
+                    return Err(format!("{}: follow-up plays sound {:02x} {} times", name, follow_up.next, follow_up.times));
+                }
+            }
+            for (part_no, part) in sound.score.iter().enumerate() {
+                validate_part(part.0).map_err(|e| format!("{}: part {}: {}", name, part_no, e))?;
+            }
+            validate_loop_points(&sound.score, &sound.loop_points).map_err(|e| format!("{}: {}", name, e))?;
+            if sound.bar_frames == Some(0) {
+                return Err(format!("{}: bar length is 0", name));
+            }
+            if sound.pans.len() > sound.score.len() {
+                return Err(format!("{}: {} pans for {} parts", name, sound.pans.len(), sound.score.len()));
+            }
+            if let Some(policy) = sound.retrigger {
+                if sound.sound_type != SoundType::Retriggerable {
+                    return Err(format!("{}: retrigger policy on a {} sound", name, sound.sound_type.name()));
+                }
+                if let RetriggerMode::After { part_no, .. } = policy.mode {
+                    if part_no >= sound.score.len() {
+                        return Err(format!("{}: retrigger after part {} of {} parts", name, part_no, sound.score.len()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scores() -> Vec<Vec<PartScore<'static>>> {
+        MUSIC_SCORES.iter().map(|parts| parts.to_vec()).collect()
+    }
+
+    #[test]
+    fn original_table_round_trips() {
+        let table = SoundTable::original();
+        table.validate().unwrap();
+        let parsed = SoundTable::parse(&table.to_text(), &scores()).unwrap();
+        assert_eq!(parsed.work_areas.len(), table.work_areas.len());
+        assert_eq!(parsed.sounds.len(), NUM_SOUND_IDX);
+        for (sound, parsed) in table.sounds.iter().zip(parsed.sounds.iter()) {
+            assert_eq!(
+                (&sound.name, sound.sound_type, sound.work_area, sound.start_ch, sound.num_ch, sound.priority, sound.score.len(), sound.follow_up, sound.category),
+                (&parsed.name, parsed.sound_type, parsed.work_area, parsed.start_ch, parsed.num_ch, parsed.priority, parsed.score.len(), parsed.follow_up, parsed.category),
+            );
+        }
+    }
+
+    #[test]
+    fn parse_reports_the_line() {
+        let scores = scores();
+        let header = "area 0100 7\narea 0187 4\n";
+        let cases = [
+            ("00 a oneshot 0100 0 7 0 05\n01 b oneshot 0100 0 7 0\n", "line 4: expected"),
+            ("01 a oneshot 0100 0 7 0 05\n", "line 3: sound 01 is out of order"),
+            ("00 a repeating 0100 0 7 0 05\n", "line 3: unknown sound type repeating"),
+            ("00 a oneshot 0200 0 7 0 05\n", "line 3: unknown work area 0200"),
+            ("00 a oneshot 0100 0 7 0 ff\n", "line 3: score ff does not exist"),
+            ("# コメント\n\n00 a oneshot 0100 0 7 0 0x\n", "line 5: invalid number 0x"),
+            ("00 a oneshot 0100 0 7 0 05\nfollow 00 00 1\n", "line 4: expected \"follow"),
+            ("follow 00 00 1 0\n", "line 3: sound 00 is not defined yet"),
+            ("00 a oneshot 0100 0 7 0 05\npan 00 L X\n", "line 4: invalid pan X"),
+            ("00 a oneshot 0100 0 7 0 05\nbar 00\n", "line 4: expected \"bar"),
+            ("00 a retriggerable 0100 0 7 0 05\nretrigger 00 0\n", "line 4: expected \"retrigger"),
+            ("00 a retriggerable 0100 0 7 0 05\nretrigger 00 0 after 1\n", "line 4: invalid retrigger mode after 1"),
+            ("00 a oneshot 0100 0 7 0 05\ncategory 00 voice\n", "line 4: unknown category voice"),
+            ("00 a oneshot 0100 0 7 0 05\nduck 4 2\n", "line 4: expected \"duck"),
+        ];
+        for (sounds, message) in cases {
+            let error = SoundTable::parse(&format!("{}{}", header, sounds), &scores).unwrap_err();
+            assert!(error.starts_with(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn validate_checks_channels_and_work_areas() {
+        let scores = scores();
+        let header = "area 0100 7\narea 0169 2\n";
+        let cases = [
+            // NormalFloor (05) は４パート
+            ("00 a oneshot 0100 6 4 0 05\n", "channels 6..10 are outside 0..8"),
+            ("00 a oneshot 0100 0 2 0 05\n", "4 parts do not fit in 2 channels"),
+            ("00 a oneshot 0169 0 4 0 05\n", "4 parts do not fit in work area 0169"),
+            ("00 a oneshot 0100 4 4 0 05\nfollow 00 01 1 0\n", "follow-up plays sound 01 1 times"),
+            ("00 a oneshot 0100 4 4 0 05\nloop 00 6 3\n", "part 1: loop point +3 is not a command"),
+            ("00 a oneshot 0100 4 4 0 05\nloop 00 6 6 6 6 6\n", "5 loop points for 4 parts"),
+            ("00 a oneshot 0100 4 4 0 05\npan 00 L R - C C\n", "5 pans for 4 parts"),
+            ("00 a oneshot 0100 4 4 0 05\nbar 00 0\n", "bar length is 0"),
+            ("00 a oneshot 0100 4 4 0 05\nretrigger 00 0 ignore\n", "retrigger policy on a oneshot sound"),
+            ("00 a retriggerable 0100 4 4 0 05\nretrigger 00 0 after 4 0\n", "retrigger after part 4 of 4 parts"),
+            ("duck 16 2 4\n00 a oneshot 0100 4 4 0 05\n", "ducking by 16 steps is outside 0..=15"),
+        ];
+        for (sounds, message) in cases {
+            let error = SoundTable::parse(&format!("{}{}", header, sounds), &scores).unwrap_err();
+            assert!(error.contains(message), "{}", error);
+        }
+        let table = SoundTable::parse(&format!("{}00 a oneshot 0100 4 4 0 05\nloop 00 6 6\npan 00 L R - C\nbar 00 48\n", header), &scores).unwrap();
+        assert_eq!(table.sounds[0].loop_points, [6, 6]);
+        assert_eq!(table.sounds[0].pans, [Some(-1), Some(1), None, Some(0)]);
+        assert_eq!(table.sounds[0].bar_frames, Some(48));
+        assert!(table.to_text().ends_with("loop 00 6 6\npan 00 L R - C\nbar 00 48\n"));
+        let table = SoundTable::parse(&format!("{}00 a oneshot 0100 4 4 0 05\ncategory 00 music\nduck 4 2 8\n", header), &scores).unwrap();
+        assert_eq!(table.sounds[0].category, Some(SoundCategory::Music));
+        assert_eq!(table.ducking, Some(Ducking { steps: 4, attack: 2, release: 8 }));
+        assert!(table.to_text().ends_with("category 00 music\nduck 4 2 8\n"));
+        // after はバイト数なので、命令の途中やパートの長さを越える値でもよい
+        for mode in ["restart", "ignore", "after 1 6", "after 1 3", "after 0 1000", "queue"] {
+            let text = format!("{}00 a retriggerable 0100 4 4 0 05\nretrigger 00 3 {}\n", header, mode);
+            let table = SoundTable::parse(&text, &scores).unwrap();
+            assert_eq!(table.sounds[0].retrigger.unwrap().min_interval, 3);
+            assert!(table.to_text().ends_with(&format!("retrigger 00 3 {}\n", mode)));
+        }
+    }
+}
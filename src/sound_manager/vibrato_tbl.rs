@@ -0,0 +1,23 @@
+// ビブラート (f9 vv で選ぶ)
+// (発音から揺れ始めるまでのフレーム数, １フレームに進める位相 (64 で１周期), 深さ (周波数の 1/1024 単位))
+// 0 番はビブラート無し
+pub const VIBRATO_TBL: &[(usize, usize, i32)] = &[
+    (0, 0, 0),
+    (0, 4, 6),
+    (0, 8, 6),
+    (8, 4, 6),
+    (8, 4, 12),
+    (16, 3, 8),
+    (0, 16, 24),
+    (4, 8, 48),
+];
+
+// 位相 (0..64) に対する揺れ (-16..16 の三角波)
+pub fn vibrato_offset(phase: usize) -> i32 {
+    let phase = (phase & 0x3f) as i32;
+    match phase {
+        0..=15 => phase,
+        16..=47 => 32 - phase,
+        _ => phase - 64,
+    }
+}
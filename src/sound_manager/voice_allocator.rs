@@ -0,0 +1,168 @@
+use super::sound_table::*;
+
+// Original: 元のドライバと同じく作業領域を共有し、固定のチャンネルに後のサウンドが上書きする
+// Dynamic: サウンドごとに演奏状態を持ち、フレームごとに優先度順でチャンネルを割り当てる
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum VoiceLayout {
+    #[default]
+    Original,
+    Dynamic,
+}
+
+// 演奏中のパートの１フレーム分の出力
+#[derive(Debug, Clone, Copy)]
+pub struct PartOutput {
+    pub sound_no: usize,
+    pub part_no: usize,
+    pub registers: (usize, i32, i32),
+    pub pan: Option<i32>,
+}
+
+#[derive(Debug, Default)]
+pub struct VoiceAllocation {
+    // チャンネルごとに割り当てた出力 (outputs の添字)
+    pub channels: [Option<usize>; 8],
+    // チャンネルを得られず、進行だけして鳴らなかった出力
+    pub masked: Vec<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct VoiceAllocator {
+    // 前のフレームで各パートが使ったチャンネル (続けて同じチャンネルを使うため)
+    part_channels: Vec<Vec<Option<usize>>>,
+}
+
+#[allow(dead_code)]
+impl VoiceAllocator {
+    pub fn new(table: &SoundTable) -> Self {
+        Self {
+            part_channels: table.sounds.iter().map(|sound| vec![None; sound.score.len()]).collect(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for channel in self.part_channels.iter_mut().flatten() {
+            *channel = None;
+        }
+    }
+
+    // 優先度の高い順 (同じなら後のサウンド) に、前回のチャンネル、パート番号どおりのチャンネル、
+    // 範囲内の空きチャンネルの順で割り当てる
+    // 割り当てられなかったパートはマスクされるが演奏は進むので、上位のサウンドが終われば再び鳴る
+    pub fn allocate(&mut self, table: &SoundTable, outputs: &[PartOutput]) -> VoiceAllocation {
+        let mut order: Vec<usize> = (0..outputs.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse((table.sounds[outputs[i].sound_no].priority, outputs[i].sound_no)));
+
+        let mut allocation = VoiceAllocation::default();
+        let mut assigned = vec![None; outputs.len()];
+        for &i in order.iter() {
+            let output = &outputs[i];
+            let sound = &table.sounds[output.sound_no];
+            let range = sound.start_ch..sound.start_ch + sound.num_ch;
+            let previous = self.part_channels[output.sound_no][output.part_no];
+            let ch = previous.into_iter()
+                .chain(std::iter::once(sound.start_ch + output.part_no))
+                .chain(range.clone())
+                .find(|ch| range.contains(ch) && allocation.channels[*ch].is_none());
+            match ch {
+                Some(ch) => {
+                    allocation.channels[ch] = Some(i);
+                    assigned[i] = Some(ch);
+                },
+                None => allocation.masked.push(i),
+            }
+        }
+
+        self.clear();
+        for (output, ch) in outputs.iter().zip(assigned) {
+            self.part_channels[output.sound_no][output.part_no] = ch;
+        }
+        allocation
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PartId {
+    pub sound_no: usize,
+    pub part_no: usize,
+}
+
+// 直前のフレームでチャンネルに出力したパートと、そのチャンネルで鳴らなかったパート
+#[derive(Debug, Clone, Default)]
+pub struct ChannelReport {
+    pub owner: Option<PartId>,
+    pub masked: Vec<PartId>,
+}
+
+impl ChannelReport {
+    pub fn clear(&mut self) {
+        self.owner = None;
+        self.masked.clear();
+    }
+
+    // Original では後から書いたパートが前のパートを上書きする
+    pub fn overwrite(&mut self, part: PartId) {
+        if let Some(owner) = self.owner.replace(part) {
+            self.masked.push(owner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: usize = 8;
+    const B: usize = 21;
+
+    // A と B を開始チャンネル 0 から num_ch チャンネルに置いた表
+    fn table(priorities: (u8, u8), num_ch: (usize, usize)) -> SoundTable<'static> {
+        let mut table = SoundTable::original();
+        for (idx, priority, num_ch) in [(A, priorities.0, num_ch.0), (B, priorities.1, num_ch.1)] {
+            table.sounds[idx].start_ch = 0;
+            table.sounds[idx].num_ch = num_ch;
+            table.sounds[idx].priority = priority;
+        }
+        table
+    }
+
+    fn output(sound_no: usize, part_no: usize) -> PartOutput {
+        PartOutput { sound_no, part_no, registers: (0, 0x1000, 15), pan: None }
+    }
+
+    #[test]
+    fn higher_priority_takes_the_channel() {
+        for (priorities, winner, loser) in [((10, 5), 0, 1), ((5, 10), 1, 0)] {
+            let table = table(priorities, (1, 1));
+            let mut allocator = VoiceAllocator::new(&table);
+            let allocation = allocator.allocate(&table, &[output(A, 0), output(B, 0)]);
+            assert_eq!(allocation.channels[0], Some(winner));
+            assert_eq!(allocation.masked, [loser]);
+        }
+    }
+
+    #[test]
+    fn same_priority_prefers_the_later_sound() {
+        let table = table((5, 5), (1, 1));
+        let mut allocator = VoiceAllocator::new(&table);
+        let allocation = allocator.allocate(&table, &[output(A, 0), output(B, 0)]);
+        assert_eq!(allocation.channels[0], Some(1));
+    }
+
+    #[test]
+    fn part_keeps_its_previous_channel() {
+        let table = table((5, 10), (2, 1));
+        let mut allocator = VoiceAllocator::new(&table);
+        // B がチャンネル 0 を使うので、A のパート 0 は範囲内の空きのチャンネル 1 に回る
+        let allocation = allocator.allocate(&table, &[output(A, 0), output(B, 0)]);
+        assert_eq!(allocation.channels[..2], [Some(1), Some(0)]);
+        assert!(allocation.masked.is_empty());
+        // B が終わってもチャンネル 1 のまま
+        let allocation = allocator.allocate(&table, &[output(A, 0)]);
+        assert_eq!(allocation.channels[..2], [None, Some(0)]);
+        // clear で前回のチャンネルを忘れる
+        allocator.clear();
+        let allocation = allocator.allocate(&table, &[output(A, 0)]);
+        assert_eq!(allocation.channels[..2], [Some(0), None]);
+    }
+}
@@ -0,0 +1,309 @@
+use std::fs;
+
+use crate::fur_export::*;
+use crate::reference_driver::*;
+use crate::sound_manager::*;
+
+const USAGE: &str = "\
+usage:
+  wsg_test2 [--rom <image> [--rom-map <map>] [--reference [--request-base ADR]]] [--sound-table <table>] [--dynamic-voices]
+      start the player (optionally with scores from a sound ROM, or driven by its original sound program)
+  wsg_test2 sound-table                          print the original sound table in the --sound-table format
+  wsg_test2 durations [--rom <image> [--rom-map <map>]] [--sound-table <table>]
+                                                 print the length of every sound and its parts
+  wsg_test2 smf-import <file.mid> [--unit N] [--sources N,N,..] [--envelope N] [--name NAME] [--hex SOUND_NO]
+  wsg_test2 fur-export <SOUND_NO> <out.fur>
+  wsg_test2 rom-check <image> [--rom-map <map>]
+  wsg_test2 rom-patch <image> <parts.hex> <out> --rom-map <map>
+                                                 the map must give @POINTER locations, free areas and the checksum byte
+  wsg_test2 disasm <SOUND_NO> [--rom <image> [--rom-map <map>]]
+                                                 print the parts of a sound in the asm format
+  wsg_test2 asm <parts.asm>                      assemble parts into the rom-patch format
+  wsg_test2 driver-diff <image> [--rom-map <map>] [--request-base ADR] [--sound SOUND_NO] [--frames N] [--suppress-last]";
+
+pub fn run(args: &[String]) -> Option<Result<(), String>> {
+    let command = args.first()?;
+    let params = &args[1..];
+    let result = match command.as_str() {
+        "smf-import" => smf_import(params),
+        "fur-export" => fur_export(params),
+        "rom-check" => rom_check(params),
+        "rom-patch" => rom_patch(params),
+        "driver-diff" => driver_diff(params),
+        "durations" => durations(params),
+        "disasm" => disasm(params),
+        "asm" => asm(params),
+        "sound-table" => {
+            print!("{}", SoundTable::original().to_text());
+            Ok(())
+        },
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => return None,
+    };
+    Some(result)
+}
+
+pub fn option_value<'a>(params: &'a [String], name: &str) -> Option<&'a str> {
+    let pos = params.iter().position(|p| p == name)?;
+    params.get(pos + 1).map(|s| s.as_str())
+}
+
+// 値を取らないオプション
+const FLAGS: [&str; 3] = ["--reference", "--suppress-last", "--dynamic-voices"];
+
+fn positional(params: &[String], n: usize) -> Result<&str, String> {
+    let mut skip_next = false;
+    let mut count = 0;
+    for p in params.iter() {
+        if skip_next {
+            skip_next = false;
+            continue;
+        }
+        if p.starts_with("--") {
+            skip_next = !FLAGS.contains(&p.as_str());
+            continue;
+        }
+        if count == n {
+            return Ok(p);
+        }
+        count += 1;
+    }
+    Err(format!("missing argument\n{}", USAGE))
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str, what: &str) -> Result<T, String> {
+    s.parse::<T>().map_err(|_| format!("invalid {}: {}", what, s))
+}
+
+fn parse_hex_number(s: &str, what: &str) -> Result<usize, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches('$');
+    usize::from_str_radix(digits, 16).map_err(|_| format!("invalid {}: {}", what, s))
+}
+
+fn smf_import(params: &[String]) -> Result<(), String> {
+    let path = positional(params, 0)?;
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut options = SmfImportOptions::default();
+    if let Some(unit) = option_value(params, "--unit") {
+        options.unit_frames = parse_number(unit, "unit")?;
+    }
+    if let Some(list) = option_value(params, "--sources") {
+        let mut sources = Vec::new();
+        for n in list.split(',') {
+            sources.push(parse_number(n.trim(), "source number")?);
+        }
+        options.sources = Some(sources);
+    }
+    if let Some(envelope) = option_value(params, "--envelope") {
+        options.fixed_envelope = Some(parse_number(envelope, "envelope")?);
+    }
+    let score = import_smf(&data, &options)?;
+    for (no, source) in score.sources.iter().enumerate() {
+        eprintln!("source {}: track {} ch {} \"{}\" {} notes", no, source.track, source.channel + 1, source.name, source.num_notes);
+    }
+    for warning in score.warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+    match option_value(params, "--hex") {
+        Some(sound_no) => print!("{}", score.to_hex(parse_hex_number(sound_no, "sound number")?)),
+        None => print!("{}", score.to_rust_source(option_value(params, "--name").unwrap_or("IMPORTED"))),
+    }
+    Ok(())
+}
+
+fn fur_export(params: &[String]) -> Result<(), String> {
+    let sound_no = parse_hex_number(positional(params, 0)?, "sound number")?;
+    let path = positional(params, 1)?;
+    let data = export_fur(sound_no)?;
+    fs::write(path, data).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn rom_map(params: &[String]) -> Result<RomMap, String> {
+    let map = match option_value(params, "--rom-map") {
+        Some(path) => RomMap::load(path)?,
+        None => RomMap::parse(DEFAULT_ROM_MAP)?,
+    };
+    // CRC32 が無いとイメージが目的の ROM か確かめられない (rom-check で表示した値を crc32 行に書く)
+    if map.crc32.is_none() {
+        eprintln!("warning: the ROM map has no crc32 line; the image is not verified against the known dump");
+    }
+    Ok(map)
+}
+
+// プレイヤー起動時の --rom 指定
+pub fn rom_scores(args: &[String]) -> Result<Option<RomScores>, String> {
+    let path = match option_value(args, "--rom") {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let rom_scores = RomScores::load(path, &rom_map(args)?)?;
+    for difference in rom_scores.compare_builtin().iter() {
+        eprintln!("{}", difference);
+    }
+    Ok(Some(rom_scores))
+}
+
+// プレイヤー起動時の --sound-table 指定 (譜面番号は ROM の譜面、または組み込みの MUSIC_SCORES を指す)
+// --dynamic-voices でチャンネルを優先度順に割り当てる
+pub fn sound_manager<'a>(args: &[String], rom_scores: Option<&'a RomScores>) -> Result<SoundManager<'a>, String> {
+    let scores = match rom_scores {
+        Some(rom_scores) => rom_scores.score_table(),
+        None => MUSIC_SCORES.iter().map(|parts| parts.to_vec()).collect(),
+    };
+    let mut sound_manager = match option_value(args, "--sound-table") {
+        Some(path) => SoundManager::new(SoundTable::load(path, &scores)?)?,
+        None => {
+            let mut sound_manager = SoundManager::default();
+            sound_manager.set_scores(scores)?;
+            sound_manager
+        },
+    };
+    if args.iter().any(|a| a == "--dynamic-voices") {
+        sound_manager.set_voice_layout(VoiceLayout::Dynamic);
+    }
+    Ok(sound_manager)
+}
+
+fn rom_check(params: &[String]) -> Result<(), String> {
+    let path = positional(params, 0)?;
+    let rom_scores = RomScores::load(path, &rom_map(params)?)?;
+    println!("CRC32: {:08x}", rom_scores.crc32);
+    let differences = rom_scores.compare_builtin();
+    for difference in differences.iter() {
+        println!("{}", difference);
+    }
+    if differences.is_empty() {
+        println!("all parts match the built-in scores");
+    }
+    Ok(())
+}
+
+fn rom_patch(params: &[String]) -> Result<(), String> {
+    let image_path = positional(params, 0)?;
+    let parts_path = positional(params, 1)?;
+    let out_path = positional(params, 2)?;
+    // 既定の対応表にはポインタ位置・空き領域・チェックサムの位置が無いので、対応表の指定を必須とする
+    if option_value(params, "--rom-map").is_none() {
+        return Err("rom-patch needs --rom-map <map> with @POINTER locations, free areas and a checksum byte".to_string());
+    }
+    let map = rom_map(params)?;
+    let image = fs::read(image_path).map_err(|e| format!("{}: {}", image_path, e))?;
+    let text = fs::read_to_string(parts_path).map_err(|e| format!("{}: {}", parts_path, e))?;
+    let parts = parse_hex_parts(&text).map_err(|e| format!("{}: {}", parts_path, e))?;
+    let patched = patch_rom(&image, &map, &parts)?;
+    for line in patched.log.iter() {
+        println!("{}", line);
+    }
+    // 書き換えたイメージが読み込めることを確かめてから書き出す
+    let mut check_map = map;
+    check_map.crc32 = None;
+    RomScores::extract(&patched.image, &check_map).map_err(|e| format!("patched image does not load: {}", e))?;
+    fs::write(out_path, &patched.image).map_err(|e| format!("{}: {}", out_path, e))
+}
+
+// 60 フレームを１秒とした mm:ss.ff
+pub fn format_time(frames: usize) -> String {
+    format!("{:02}:{:02}.{:02}", frames / 3600, frames / 60 % 60, frames % 60)
+}
+
+fn durations(params: &[String]) -> Result<(), String> {
+    let rom_scores = rom_scores(params)?;
+    let sound_manager = sound_manager(params, rom_scores.as_ref())?;
+    let frames_text = |frames: Option<usize>| frames.map_or("-".to_string(), |frames| frames.to_string());
+    for sound_no in 0..sound_manager.num_sounds() {
+        let duration = sound_manager.duration(sound_no);
+        let time = duration.frames.map_or("--:--.--".to_string(), format_time);
+        let parts: Vec<String> = duration.part_frames.iter().map(|&frames| frames_text(frames)).collect();
+        println!("{:02x} {:<16} {:>6} frames {}  parts {}{}", sound_no, sound_manager.sound_name(sound_no),
+            frames_text(duration.frames), time, parts.join(" "), if duration.parts_match() { "" } else { " (lengths differ)" });
+    }
+    Ok(())
+}
+
+fn disasm(params: &[String]) -> Result<(), String> {
+    let sound_no = parse_hex_number(positional(params, 0)?, "sound number")?;
+    let rom_scores = match option_value(params, "--rom") {
+        Some(path) => Some(RomScores::load(path, &rom_map(params)?)?),
+        None => None,
+    };
+    let scores = match rom_scores.as_ref() {
+        Some(rom_scores) => rom_scores.score_table(),
+        None => MUSIC_SCORES.iter().map(|parts| parts.to_vec()).collect(),
+    };
+    let parts = scores.get(sound_no).ok_or(format!("sound {:02x} does not exist", sound_no))?;
+    for (part_no, part) in parts.iter().enumerate() {
+        println!("[{:02x}.{}]", sound_no, part_no);
+        print!("{}", disassemble(part.0).map_err(|e| format!("part {}: {}", part_no, e))?);
+    }
+    Ok(())
+}
+
+fn asm(params: &[String]) -> Result<(), String> {
+    let path = positional(params, 0)?;
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let parts = parse_asm_parts(&text).map_err(|e| format!("{}: {}", path, e))?;
+    print!("{}", hex_parts_text(&parts));
+    Ok(())
+}
+
+fn request_base(params: &[String]) -> Result<usize, String> {
+    match option_value(params, "--request-base") {
+        Some(adr) => parse_hex_number(adr, "request base"),
+        None => Ok(DEFAULT_REQUEST_BASE),
+    }
+}
+
+// プレイヤー起動時の --reference 指定 (--rom の ROM イメージの元プログラムで鳴らす)
+pub fn reference_driver(args: &[String]) -> Result<Option<ReferenceDriver>, String> {
+    if !args.iter().any(|a| a == "--reference") {
+        return Ok(None);
+    }
+    let path = option_value(args, "--rom").ok_or("--reference needs --rom <image>".to_string())?;
+    let image = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    ReferenceDriver::new(&image, rom_map(args)?.base, request_base(args)?).map(Some)
+}
+
+fn driver_diff(params: &[String]) -> Result<(), String> {
+    let path = positional(params, 0)?;
+    let map = rom_map(params)?;
+    let image = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let rom_scores = RomScores::extract(&image, &map)?;
+    let max_frames = match option_value(params, "--frames") {
+        Some(frames) => parse_number(frames, "frames")?,
+        None => 3600,
+    };
+    let sounds = match option_value(params, "--sound") {
+        Some(sound_no) => {
+            let sound_no = parse_hex_number(sound_no, "sound number")?;
+            if sound_no >= NUM_SOUND_IDX {
+                return Err(format!("invalid sound number: {:02x}", sound_no));
+            }
+            sound_no..sound_no + 1
+        },
+        None => 0..NUM_SOUND_IDX,
+    };
+    let mut total_mismatches = 0;
+    for sound_no in sounds {
+        let mut sound_manager = SoundManager::default();
+        sound_manager.set_scores(rom_scores.score_table())?;
+        sound_manager.suppress_last_silence = params.iter().any(|p| p == "--suppress-last");
+        let mut reference = ReferenceDriver::new(&image, map.base, request_base(params)?)?;
+        let diff = diff_sound(&mut sound_manager, &mut reference, sound_no, max_frames);
+        print!("sound {:02x} {:<16} {:5} frames, lag {}: ", sound_no, format!("{:?}", SoundIdx::from(sound_no as i32)), diff.frames, diff.lag);
+        match diff.first_mismatch {
+            Some(m) => {
+                println!("{} frames differ, first at frame {} ch {}: manager {:?} reference {:?}",
+                    diff.mismatched_frames, m.frame, m.ch, m.manager, m.reference);
+                total_mismatches += 1;
+            },
+            None => println!("match"),
+        }
+    }
+    if total_mismatches > 0 {
+        println!("{} sounds differ", total_mismatches);
+    }
+    Ok(())
+}